@@ -1,11 +1,11 @@
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
-use crate::consts::{packet_type, AckStatus, GGStatus, GGMessageClass};
+use crate::consts::{packet_type, AckStatus, GGStatus, GGMessageClass, GG_MAX_PACKET_LENGTH};
 use crate::error::GGError;
 use crate::packets::{GGLogin60, GGPacket, GGRecvMessage, GGSendMessage, GGSendMessageAck, NewStatus};
 use crate::codec_helpers::{
-  decode_cp1250, encode_cp1250,
-  decode_contact_entries, decode_contact_statuses,
+  decode_cp1250, encode_cp1250, decode_description, encode_description,
+  decode_contact_entries, decode_contact_statuses, decode_contact_status_no_size,
   encode_contact_status_with_size, encode_contact_status_no_size,
   encode_richtext_formatting, decode_richtext_formatting,
 };
@@ -66,7 +66,7 @@ impl Encoder<GGPacket> for GGCodec {
 
         // Optional fields (description + time)
         if let Some(ref desc) = login.description {
-          let desc_bytes = encode_cp1250(desc);
+          let desc_bytes = encode_description(desc);
           payload.put_slice(&desc_bytes);
           payload.put_u8(0); // null terminator
           if let Some(time) = login.time {
@@ -153,7 +153,7 @@ impl Encoder<GGPacket> for GGCodec {
 
         // Optional description (null-terminated) + optional time
         if let Some(ref desc) = new_status.description {
-          let desc_bytes = encode_cp1250(desc);
+          let desc_bytes = encode_description(desc);
           payload.put_slice(&desc_bytes);
           payload.put_u8(0); // null terminator
           if let Some(time) = new_status.time {
@@ -176,11 +176,10 @@ impl Encoder<GGPacket> for GGCodec {
         payload.put_u8(0); // null terminator
 
         // Append rich text formatting if present
-        if let Some(ref formats) = msg.formatting {
-          if !formats.is_empty() {
-            let richtext_data = encode_richtext_formatting(formats);
-            payload.put_slice(&richtext_data);
-          }
+        if let Some(ref formats) = msg.formatting
+          && !formats.is_empty() {
+          let richtext_data = encode_richtext_formatting(formats);
+          payload.put_slice(&richtext_data);
         }
 
         dst.put_u32_le(packet_type::GG_SEND_MSG);
@@ -199,11 +198,10 @@ impl Encoder<GGPacket> for GGCodec {
         payload.put_u8(0); // null terminator
 
         // Append rich text formatting if present
-        if let Some(ref formats) = msg.formatting {
-          if !formats.is_empty() {
-            let richtext_data = encode_richtext_formatting(formats);
-            payload.put_slice(&richtext_data);
-          }
+        if let Some(ref formats) = msg.formatting
+          && !formats.is_empty() {
+          let richtext_data = encode_richtext_formatting(formats);
+          payload.put_slice(&richtext_data);
         }
 
         dst.put_u32_le(packet_type::GG_RECV_MSG);
@@ -235,6 +233,9 @@ impl Decoder for GGCodec {
     // Peek at the packet type and length without consuming
     let packet_type = u32::from_le_bytes([src[0], src[1], src[2], src[3]]);
     let length = u32::from_le_bytes([src[4], src[5], src[6], src[7]]) as usize;
+    if length > GG_MAX_PACKET_LENGTH {
+      return Err(GGError::PacketTooLarge(length))
+    }
 
     // Check if we have enough data for the full packet
     if src.len() < 8 + length {
@@ -247,7 +248,7 @@ impl Decoder for GGCodec {
     match packet_type {
       packet_type::GG_WELCOME => {
         let seed = src.get_u32_le();
-        return Ok(Some(GGPacket::Welcome { seed }))
+        Ok(Some(GGPacket::Welcome { seed }))
       }
 
       packet_type::GG_LOGIN60 => {
@@ -275,7 +276,7 @@ impl Decoder for GGCodec {
         let (description, time) = if remaining > 0 {
           // Find null terminator
           let desc_end = src.iter().take(remaining).position(|&b| b == 0).unwrap_or(remaining);
-          let desc = decode_cp1250(&src[..desc_end]);
+          let desc = decode_description(&src[..desc_end]);
           src.advance(desc_end);
 
           // Skip null terminator if present
@@ -296,7 +297,7 @@ impl Decoder for GGCodec {
           (None, None)
         };
 
-        return Ok(Some(GGPacket::Login60(GGLogin60 {
+        Ok(Some(GGPacket::Login60(GGLogin60 {
           uin,
           hash,
           status: status.try_into().unwrap_or(GGStatus::Avail),
@@ -317,21 +318,21 @@ impl Decoder for GGCodec {
         if length > 0 {
           src.advance(length); // Skip any unexpected content
         }
-        return Ok(Some(GGPacket::LoginOk))
+        Ok(Some(GGPacket::LoginOk))
       }
 
       packet_type::GG_PING => {
         if length > 0 {
           src.advance(length); // Skip any unexpected content
         }
-        return Ok(Some(GGPacket::Ping))
+        Ok(Some(GGPacket::Ping))
       }
 
       packet_type::GG_PONG => {
         if length > 0 {
           src.advance(length); // Skip any unexpected content
         }
-        return Ok(Some(GGPacket::Pong))
+        Ok(Some(GGPacket::Pong))
       },
 
       packet_type::GG_DISCONNECTING => {
@@ -372,7 +373,7 @@ impl Decoder for GGCodec {
               (String::new(), None)
             };
 
-            return Ok(Some(GGPacket::SendMessage(GGSendMessage {
+            Ok(Some(GGPacket::SendMessage(GGSendMessage {
               recipient,
               seq,
               class,
@@ -385,7 +386,7 @@ impl Decoder for GGCodec {
             if length > 0 {
               src.advance(length);
             }
-            return Ok(Some(GGPacket::Disconnect))
+            Ok(Some(GGPacket::Disconnect))
           }
         }
       }
@@ -394,29 +395,38 @@ impl Decoder for GGCodec {
         if length > 0 {
           src.advance(length); // Skip any unexpected content
         }
-        return Ok(Some(GGPacket::ListEmpty))
+        Ok(Some(GGPacket::ListEmpty))
       }
 
       packet_type::GG_NOTIFY_FIRST => {
-        let entries = decode_contact_entries(src, length);
-        return Ok(Some(GGPacket::NotifyFirst(entries)))
+        match self.mode {
+          CodecMode::Server => {
+            let entries = decode_contact_entries(src, length);
+            Ok(Some(GGPacket::NotifyFirst(entries)))
+          }
+          CodecMode::Client => {
+            // Server sends GG_STATUS60 with the same packet type
+            let status = decode_contact_status_no_size(src, length);
+            Ok(Some(GGPacket::Status60(status)))
+          }
+        }
       }
 
       packet_type::GG_NOTIFY_LAST => {
         let entries = decode_contact_entries(src, length);
-        return Ok(Some(GGPacket::NotifyLast(entries)))
+        Ok(Some(GGPacket::NotifyLast(entries)))
       }
 
       packet_type::GG_LOGIN_FAILED => {
         if length > 0 {
           src.advance(length); // Skip any content
         }
-        return Ok(Some(GGPacket::LoginFailed))
+        Ok(Some(GGPacket::LoginFailed))
       }
 
       packet_type::GG_NOTIFY_REPLY60 => {
         let statuses = decode_contact_statuses(src, length);
-        return Ok(Some(GGPacket::NotifyReply60(statuses)))
+        Ok(Some(GGPacket::NotifyReply60(statuses)))
       }
 
       packet_type::GG_NEW_STATUS => {
@@ -429,7 +439,7 @@ impl Decoder for GGCodec {
           // Find null terminator
           let desc_end = src.iter().take(remaining).position(|&b| b == 0).unwrap_or(remaining);
           let desc = if desc_end > 0 {
-            Some(decode_cp1250(&src[..desc_end]))
+            Some(decode_description(&src[..desc_end]))
           } else {
             None
           };
@@ -453,7 +463,7 @@ impl Decoder for GGCodec {
           (None, None)
         };
 
-        return Ok(Some(GGPacket::NewStatus(NewStatus {
+        Ok(Some(GGPacket::NewStatus(NewStatus {
           status,
           description,
           time,
@@ -494,7 +504,7 @@ impl Decoder for GGCodec {
           (String::new(), None)
         };
 
-        return Ok(Some(GGPacket::RecvMessage(GGRecvMessage {
+        Ok(Some(GGPacket::RecvMessage(GGRecvMessage {
           sender,
           seq,
           time,
@@ -510,14 +520,14 @@ impl Decoder for GGCodec {
         let recipient = src.get_u32_le();
         let seq = src.get_u32_le();
 
-        return Ok(Some(GGPacket::SendMessageAck(GGSendMessageAck {
+        Ok(Some(GGPacket::SendMessageAck(GGSendMessageAck {
           status,
           recipient,
           seq,
         })))
       }
 
-      _ => return Err(GGError::UnsupportedPacketType(packet_type))
+      _ => Err(GGError::UnsupportedPacketType(packet_type))
     }
   }
 }
@@ -528,6 +538,7 @@ mod tests {
   use claims::{assert_ok, assert_ok_eq};
   use super::*;
   use crate::packets::{ContactEntry, ContactStatus, ContactType};
+  use crate::consts::GG_MAX_DESCRIPTION_LENGTH;
 
   #[test]
   fn it_handles_welcome_packet() {
@@ -693,6 +704,30 @@ mod tests {
     assert_ok_eq!(decoded, Some(packet));
   }
 
+  #[test]
+  fn it_decodes_notify_reply60_long_description_without_time() {
+    let mut output = BytesMut::new();
+    let mut codec = GGCodec::default();
+
+    let statuses = vec![ContactStatus {
+      uin: 2000,
+      flags: 0,
+      status: 0x15, // Not available WITH description (GG_STATUS_NOT_AVAIL_DESCR)
+      remote_ip: Ipv4Addr::new(0, 0, 0, 0),
+      remote_port: 0,
+      version: 0x20,
+      image_size: 0,
+      description: Some("Wracam o 18".to_string()),
+      time: None,
+    }];
+    let packet = GGPacket::NotifyReply60(statuses);
+
+    assert_ok!(codec.encode(packet.clone(), &mut output));
+
+    let decoded = codec.decode(&mut output);
+    assert_ok_eq!(decoded, Some(packet));
+  }
+
   #[test]
   fn it_handles_status60_packet() {
     let mut output = BytesMut::new();
@@ -714,8 +749,34 @@ mod tests {
     assert_ok!(codec.encode(packet.clone(), &mut output));
     insta::assert_binary_snapshot!(".packet", output.to_vec());
 
-    // Status60 decodes as NotifyFirst due to shared packet type 0x000f
-    // This is expected - they're distinguished by direction in practice
+    // Status60 shares packet type 0x000f with NotifyFirst, only clients decode it as a status
+    let decoded = GGCodec::client().decode(&mut output.clone());
+    assert_ok_eq!(decoded, Some(packet));
+  }
+
+  #[test]
+  fn it_decodes_status60_without_description_in_client_mode() {
+    let mut output = BytesMut::new();
+    let mut codec = GGCodec::server();
+
+    let status = ContactStatus {
+      uin: 12345,
+      flags: 0,
+      status: 0x02, // Available (GG_STATUS_AVAIL)
+      remote_ip: Ipv4Addr::new(0, 0, 0, 0),
+      remote_port: 0,
+      version: 0x20,
+      image_size: 0,
+      description: None,
+      time: None,
+    };
+    assert_ok!(codec.encode(GGPacket::Status60(status.clone()), &mut output));
+    assert_ok!(codec.encode(GGPacket::Pong, &mut output));
+
+    // The next packet must start right after the status
+    let mut client = GGCodec::client();
+    assert_ok_eq!(client.decode(&mut output), Some(GGPacket::Status60(status)));
+    assert_ok_eq!(client.decode(&mut output), Some(GGPacket::Pong));
   }
 
   #[test]
@@ -970,4 +1031,107 @@ mod tests {
     let decoded = codec.decode(&mut output.clone());
     assert_ok_eq!(decoded, Some(packet));
   }
+
+  #[test]
+  fn it_truncates_at_cp1250_boundaries() {
+    use crate::codec_helpers::{cp1250_len, truncate_cp1250};
+
+    assert_eq!(truncate_cp1250("", 70), "");
+    assert_eq!(truncate_cp1250("abc", 0), "");
+    assert_eq!(truncate_cp1250("abc", 3), "abc");
+    assert_eq!(truncate_cp1250("abcd", 3), "abc");
+    // Polish letters take two bytes in UTF-8, but one in CP1250
+    assert_eq!(truncate_cp1250("zażółć", 5), "zażół");
+    assert_eq!(cp1250_len("zażółć"), 6);
+    // Characters missing from CP1250 are sent as `&#128512;` and never split
+    assert_eq!(cp1250_len("a😀"), 10);
+    assert_eq!(truncate_cp1250("a😀b", 9), "a");
+    assert_eq!(truncate_cp1250("a😀b", 10), "a😀");
+  }
+
+  #[test]
+  fn it_truncates_long_descriptions() {
+    let mut output = BytesMut::new();
+    let mut codec = GGCodec::default();
+
+    let exact = "ą".repeat(GG_MAX_DESCRIPTION_LENGTH);
+    let status = |description: String| ContactStatus {
+      uin: 2000,
+      flags: 0,
+      status: 0x04, // Available WITH description
+      remote_ip: Ipv4Addr::new(0, 0, 0, 0),
+      remote_port: 0,
+      version: 0x20,
+      image_size: 0,
+      description: Some(description),
+      time: Some(1234567890),
+    };
+    let packet = GGPacket::NotifyReply60(vec![
+      status(exact.clone()),
+      status(format!("{}ę", exact)),
+      status(format!("{}😀", "a".repeat(GG_MAX_DESCRIPTION_LENGTH - 1))),
+    ]);
+
+    assert_ok!(codec.encode(packet, &mut output));
+    let expected = GGPacket::NotifyReply60(vec![
+      status(exact.clone()),
+      status(exact.clone()),
+      status("a".repeat(GG_MAX_DESCRIPTION_LENGTH - 1)),
+    ]);
+    assert_ok_eq!(codec.decode(&mut output), Some(expected));
+  }
+
+  #[test]
+  fn it_decodes_long_descriptions_truncated() {
+    let description = [b'x'; 200];
+    let mut input = BytesMut::new();
+    input.put_u32_le(packet_type::GG_NEW_STATUS);
+    input.put_u32_le(4 + description.len() as u32 + 1 + 4);
+    input.put_u32_le(GGStatus::AvailDescr as u32);
+    input.put_slice(&description);
+    input.put_u8(0);
+    input.put_u32_le(1234567890);
+
+    let decoded = GGCodec::server().decode(&mut input);
+    assert_ok_eq!(decoded, Some(GGPacket::NewStatus(NewStatus {
+      status: GGStatus::AvailDescr,
+      description: Some("x".repeat(GG_MAX_DESCRIPTION_LENGTH)),
+      time: Some(1234567890),
+    })));
+    assert!(input.is_empty());
+  }
+
+  #[test]
+  fn it_rejects_oversized_packets() {
+    let mut input = BytesMut::new();
+    input.put_u32_le(packet_type::GG_SEND_MSG);
+    input.put_u32_le(GG_MAX_PACKET_LENGTH as u32 + 1);
+
+    let decoded = GGCodec::server().decode(&mut input);
+    assert!(matches!(decoded, Err(GGError::PacketTooLarge(length)) if length == GG_MAX_PACKET_LENGTH + 1));
+  }
+
+  #[test]
+  fn it_truncates_messages_with_formatting() {
+    use crate::packets::RichTextFormat;
+
+    let mut msg = GGSendMessage {
+      recipient: 12345,
+      seq: 1,
+      class: GGMessageClass::Chat,
+      message: "żółw😀żółw".to_string(),
+      formatting: Some(vec![RichTextFormat::bold(0), RichTextFormat::italic(4), RichTextFormat::new(13)]),
+    };
+    assert!(!msg.truncate(17));
+    assert_eq!(msg.formatting.as_ref().unwrap().len(), 3);
+
+    // Cutting into the reference of the emoji drops it whole
+    assert!(msg.truncate(10));
+    assert_eq!(msg.message, "żółw");
+    assert_eq!(msg.formatting, Some(vec![RichTextFormat::bold(0)]));
+
+    assert!(msg.truncate(0));
+    assert_eq!(msg.message, "");
+    assert_eq!(msg.formatting, None);
+  }
 }
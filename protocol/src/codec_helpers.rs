@@ -4,7 +4,7 @@ use std::borrow::Cow;
 use std::net::Ipv4Addr;
 use bytes::{Buf, BufMut, BytesMut};
 use encoding_rs::WINDOWS_1250;
-use crate::consts::{font, status_has_description};
+use crate::consts::{font, status_has_description, GG_MAX_DESCRIPTION_LENGTH};
 use crate::packets::{ContactEntry, ContactStatus, ContactType, RgbColor, RichTextFormat};
 
 /// Decode CP1250 (Windows-1250) bytes to a Rust String.
@@ -19,6 +19,36 @@ pub fn encode_cp1250(s: &str) -> Cow<'_, [u8]> {
   encoded
 }
 
+/// Length of a string in CP1250 bytes. Characters missing from CP1250 are
+/// sent as HTML numeric references, e.g. `&#128512;`, and count as such.
+pub fn cp1250_len(s: &str) -> usize {
+  encode_cp1250(s).len()
+}
+
+/// Longest prefix of a string that fits in `max` CP1250 bytes, without
+/// cutting a character or its numeric reference in half.
+pub fn truncate_cp1250(s: &str, max: usize) -> &str {
+  let mut used = 0;
+  let mut buf = [0u8; 4];
+  for (index, c) in s.char_indices() {
+    used += encode_cp1250(c.encode_utf8(&mut buf)).len();
+    if used > max {
+      return &s[..index];
+    }
+  }
+  s
+}
+
+/// Decode a description, anything past `GG_MAX_DESCRIPTION_LENGTH` is dropped.
+pub fn decode_description(bytes: &[u8]) -> String {
+  decode_cp1250(&bytes[..bytes.len().min(GG_MAX_DESCRIPTION_LENGTH)])
+}
+
+/// Encode a description, cut to `GG_MAX_DESCRIPTION_LENGTH`.
+pub fn encode_description(description: &str) -> Cow<'_, [u8]> {
+  encode_cp1250(truncate_cp1250(description, GG_MAX_DESCRIPTION_LENGTH))
+}
+
 pub fn decode_contact_entries(src: &mut BytesMut, length: usize) -> Vec<ContactEntry> {
   let entry_count = length / 5; // 4 bytes uin + 1 byte type
   let mut entries = Vec::with_capacity(entry_count);
@@ -58,13 +88,14 @@ pub fn decode_contact_statuses(src: &mut BytesMut, mut length: usize) -> Vec<Con
 
       if description_size > 0 && length >= description_size {
         // description_size includes: description + null terminator (1 byte) + time (4 bytes, optional)
-        // Check if there's time at the end (need at least 5 bytes: 1 null + 4 time)
-        let has_time = description_size >= 5;
+        // Time is present only when the null terminator sits 5 bytes before the end,
+        // otherwise a long description without time would lose its last 4 characters
+        let has_time = description_size >= 5 && src[description_size - 5] == 0;
         // desc_len = total - null terminator - time (if present)
         let desc_len = if has_time { description_size - 5 } else { description_size.saturating_sub(1) };
 
         let desc = if desc_len > 0 {
-          let d = decode_cp1250(&src[..desc_len]);
+          let d = decode_description(&src[..desc_len]);
           src.advance(desc_len);
           Some(d)
         } else {
@@ -107,6 +138,62 @@ pub fn decode_contact_statuses(src: &mut BytesMut, mut length: usize) -> Vec<Con
   statuses
 }
 
+/// Decode contact status for GG_STATUS60 (no description_size, null-terminated)
+pub fn decode_contact_status_no_size(src: &mut BytesMut, length: usize) -> ContactStatus {
+  let uin_with_flags = src.get_u32_le();
+  let uin = uin_with_flags & 0x00FFFFFF;
+  let flags = ((uin_with_flags >> 24) & 0xFF) as u8;
+
+  let status = src.get_u8();
+  let remote_ip = Ipv4Addr::new(src[0], src[1], src[2], src[3]);
+  src.advance(4);
+  let remote_port = src.get_u16_le();
+  let version = src.get_u8();
+  let image_size = src.get_u8();
+  let _unknown1 = src.get_u8();
+
+  let mut remaining = length.saturating_sub(14);
+
+  let (description, time) = if status_has_description(status) && remaining > 0 {
+    let desc_end = src.iter().take(remaining).position(|&b| b == 0).unwrap_or(remaining);
+    let desc = decode_description(&src[..desc_end]);
+    src.advance(desc_end);
+    remaining -= desc_end;
+
+    // Skip null terminator
+    if remaining > 0 {
+      src.advance(1);
+      remaining -= 1;
+    }
+
+    let time = if remaining >= 4 {
+      remaining -= 4;
+      Some(src.get_u32_le())
+    } else {
+      None
+    };
+
+    (Some(desc), time)
+  } else {
+    (None, None)
+  };
+
+  // Skip anything we don't understand, so the next packet starts in the right place
+  src.advance(remaining);
+
+  ContactStatus {
+    uin,
+    flags,
+    status,
+    remote_ip,
+    remote_port,
+    version,
+    image_size,
+    description,
+    time,
+  }
+}
+
 /// Encode contact status for GG_NOTIFY_REPLY60 (has description_size prefix)
 pub fn encode_contact_status_with_size(payload: &mut BytesMut, status: &ContactStatus) {
   // uin (24 bits) with flags in MSB (8 bits)
@@ -122,7 +209,7 @@ pub fn encode_contact_status_with_size(payload: &mut BytesMut, status: &ContactS
   // Only write description fields if status indicates description (GG_S_D equivalent)
   if status_has_description(status.status) {
     if let Some(ref desc) = status.description {
-      let desc_bytes = encode_cp1250(desc);
+      let desc_bytes = encode_description(desc);
       // description_size = description + null terminator + time (if present)
       let desc_size = desc_bytes.len() + 1 + if status.time.is_some() { 4 } else { 0 };
       payload.put_u8(desc_size as u8);
@@ -150,14 +237,13 @@ pub fn encode_contact_status_no_size(payload: &mut BytesMut, status: &ContactSta
   payload.put_u8(0); // unknown1
 
   // Only write description fields if status indicates description (GG_S_D equivalent)
-  if status_has_description(status.status) {
-    if let Some(ref desc) = status.description {
-      let desc_bytes = encode_cp1250(desc);
-      payload.put_slice(&desc_bytes);
-      payload.put_u8(0); // null terminator
-      if let Some(time) = status.time {
-        payload.put_u32_le(time);
-      }
+  if status_has_description(status.status)
+    && let Some(ref desc) = status.description {
+    let desc_bytes = encode_description(desc);
+    payload.put_slice(&desc_bytes);
+    payload.put_u8(0); // null terminator
+    if let Some(time) = status.time {
+      payload.put_u32_le(time);
     }
   }
 }
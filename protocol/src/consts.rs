@@ -40,14 +40,25 @@ pub type GGNumber = u32;
 /// Maximum UIN supported by GG 6.0 protocol (24 bits, upper 8 bits reserved for flags)
 pub const GG60_MAX_UIN: GGNumber = 0x00FFFFFF; // 16,777,215
 
+/// Longest message the server accepts, in characters. The original client
+/// stops at 1989. CP1250 uses a byte per character, so this is also bytes.
+pub const GG_MAX_MESSAGE_LENGTH: usize = 2000;
+/// Longest status description, in characters (CP1250 bytes).
+pub const GG_MAX_DESCRIPTION_LENGTH: usize = 70;
+/// Largest packet the codec accepts. Real packets are a few kilobytes at most,
+/// a bigger length header comes from a broken or hostile peer.
+pub const GG_MAX_PACKET_LENGTH: usize = 64 * 1024;
+
 /// Friends only mask - OR with status to enable "friends only" mode.
 pub const STATUS_FRIENDS_MASK: u32 = 0x8000;
 
 /// User status.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
+#[derive(Default)]
 pub enum GGStatus {
   /// Not available.
+  #[default]
   NotAvail = 0x0001,
   /// Not available with description.
   NotAvailDescr = 0x0015,
@@ -67,11 +78,6 @@ pub enum GGStatus {
   Blocked = 0x0006,
 }
 
-impl Default for GGStatus {
-  fn default() -> Self {
-    Self::NotAvail
-  }
-}
 
 impl GGStatus {
   /// Returns true if this status type includes a description field.
@@ -11,4 +11,6 @@ pub enum GGError {
   UnsupportedPacketType(u32),
   #[error("Unsupported packet: {0:?}")]
   UnsupportedPacket(GGPacket),
+  #[error("Packet too large: {0} bytes")]
+  PacketTooLarge(usize),
 }
@@ -9,6 +9,7 @@ mod codec_helpers;
 
 // Re-export commonly used types
 pub use codec::GGCodec;
+pub use codec_helpers::{cp1250_len, truncate_cp1250};
 pub use consts::{packet_type, GGStatus, version, GGNumber};
 pub use error::GGError;
 pub use hash::gg_login_hash;
@@ -5,7 +5,7 @@
 
 use bytes::BytesMut;
 use crate::consts::{font, AckStatus, GGNumber, GGMessageClass};
-use crate::codec_helpers::{encode_richtext_formatting, decode_richtext_formatting};
+use crate::codec_helpers::{cp1250_len, truncate_cp1250, encode_richtext_formatting, decode_richtext_formatting};
 
 /// RGB color for text formatting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -196,6 +196,28 @@ pub struct GGSendMessage {
   pub formatting: Option<Vec<RichTextFormat>>,
 }
 
+impl GGSendMessage {
+  /// Cut the message to at most `max` CP1250 bytes, without splitting a
+  /// character. Formatting past the new end is dropped. Returns true if the
+  /// message was cut.
+  pub fn truncate(&mut self, max: usize) -> bool {
+    let length = truncate_cp1250(&self.message, max).len();
+    if length == self.message.len() {
+      return false;
+    }
+    self.message.truncate(length);
+
+    let end = cp1250_len(&self.message);
+    if let Some(formats) = self.formatting.as_mut() {
+      formats.retain(|format| (format.position as usize) < end);
+      if formats.is_empty() {
+        self.formatting = None;
+      }
+    }
+    true
+  }
+}
+
 /// Receive message packet (S → C).
 #[derive(Debug, Clone, PartialEq)]
 pub struct GGRecvMessage {
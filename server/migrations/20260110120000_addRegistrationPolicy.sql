@@ -0,0 +1,12 @@
+-- Accounts created in approval mode stay locked until an admin approves them
+ALTER TABLE users ADD COLUMN approved_at TIMESTAMP;
+UPDATE users SET approved_at = created_at;
+
+-- Single-use invite codes for invite-only registration
+CREATE TABLE invites (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    used_at TIMESTAMP,
+    used_by_uin INTEGER
+);
@@ -0,0 +1,8 @@
+-- Last known presence of each user, shown to contacts while the user is offline
+CREATE TABLE presences (
+    uin INTEGER PRIMARY KEY,
+    status INTEGER NOT NULL,
+    description TEXT,
+    time INTEGER,
+    last_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
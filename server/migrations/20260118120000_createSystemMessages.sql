@@ -0,0 +1,9 @@
+-- Numbered system messages, shown by clients through appmsg and sent to online users from UIN 0
+CREATE TABLE system_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    broadcast_at TIMESTAMP
+);
+
+CREATE INDEX idx_system_messages_broadcast_at ON system_messages (broadcast_at);
@@ -0,0 +1,6 @@
+-- Newest message of the day revision each user has received
+CREATE TABLE motd_deliveries (
+    uin INTEGER PRIMARY KEY,
+    revision INTEGER NOT NULL,
+    delivered_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
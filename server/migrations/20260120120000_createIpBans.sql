@@ -0,0 +1,6 @@
+-- Addresses banned by admins at runtime
+CREATE TABLE ip_bans (
+    ip TEXT PRIMARY KEY,
+    reason TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,13 @@
+-- Bans, suspensions and mutes of accounts, at most one of each kind per user
+CREATE TABLE user_moderation (
+    uin INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    reason TEXT,
+    moderator TEXT NOT NULL,
+    until TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    enforced_at TIMESTAMP,
+    PRIMARY KEY (uin, kind)
+);
+
+CREATE INDEX idx_user_moderation_enforced_at ON user_moderation (enforced_at);
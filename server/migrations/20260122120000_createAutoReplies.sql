@@ -0,0 +1,8 @@
+-- Automatic replies sent back to people messaging a user who is away
+CREATE TABLE auto_replies (
+    uin INTEGER PRIMARY KEY,
+    message TEXT NOT NULL,
+    when_offline BOOLEAN NOT NULL DEFAULT TRUE,
+    when_busy BOOLEAN NOT NULL DEFAULT TRUE,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,201 @@
+//! IP allow and deny lists for the GG and HTTP listeners.
+//!
+//! ```toml
+//! [access]
+//! allow = ["192.168.0.0/16"]   # empty lets everyone in
+//! deny = ["192.168.1.13"]
+//! ```
+//!
+//! A deny entry wins over an allow entry. Admins can also ban single
+//! addresses at runtime with `gg-retro ban add`, those bans are stored in
+//! the database and picked up by a running server within a few seconds.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use crate::core::{poll_every, SharedAppState};
+use crate::models::{DatabasePool, IpBanRepository, RepositoryError};
+
+const BAN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+#[error("Invalid IP range: {0}")]
+pub struct InvalidCidr(String);
+
+/// Range of addresses, written as `"192.168.0.0/16"` or a single `"10.0.0.1"`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+  pub addr: IpAddr,
+  pub prefix: u8,
+}
+
+impl Cidr {
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    match (self.addr, ip.to_canonical()) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+        u32::from(net) & mask == u32::from(ip) & mask
+      },
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+        u128::from(net) & mask == u128::from(ip) & mask
+      },
+      _ => false,
+    }
+  }
+}
+
+impl FromStr for Cidr {
+  type Err = InvalidCidr;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || InvalidCidr(s.to_string());
+    let (addr, prefix) = match s.trim().split_once('/') {
+      Some((addr, prefix)) => (addr, Some(prefix)),
+      None => (s.trim(), None),
+    };
+
+    let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+      None => max_prefix,
+    };
+
+    if prefix > max_prefix {
+      return Err(invalid());
+    }
+    Ok(Self { addr, prefix })
+  }
+}
+
+impl Display for Cidr {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
+impl TryFrom<String> for Cidr {
+  type Error = InvalidCidr;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<Cidr> for String {
+  fn from(cidr: Cidr) -> Self {
+    cidr.to_string()
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AccessConfig {
+  pub allow: Vec<Cidr>,
+  pub deny: Vec<Cidr>,
+}
+
+impl AccessConfig {
+  pub fn is_allowed(&self, ip: IpAddr) -> bool {
+    let allowed = self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip));
+    allowed && !self.deny.iter().any(|cidr| cidr.contains(ip))
+  }
+}
+
+/// Addresses banned at runtime, mirrored from the database.
+#[derive(Debug, Default)]
+pub struct IpBans {
+  banned: RwLock<HashSet<IpAddr>>,
+}
+
+impl IpBans {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    self.banned.read().contains(&ip.to_canonical())
+  }
+
+  /// Replace the bans with the ones stored in the database. Returns how many there are.
+  pub async fn load(&self, db_pool: &DatabasePool) -> Result<usize, RepositoryError> {
+    let banned = IpBanRepository::new(db_pool)
+      .list()
+      .await?
+      .into_iter()
+      .filter_map(|ban| ban.ip.parse::<IpAddr>().ok())
+      .map(|ip| ip.to_canonical())
+      .collect::<HashSet<IpAddr>>();
+
+    let count = banned.len();
+    *self.banned.write() = banned;
+    Ok(count)
+  }
+}
+
+/// Keep the bans in sync with the database until `shutdown` is cancelled.
+pub async fn ban_refresher(shutdown: CancellationToken, app_state: SharedAppState) {
+  poll_every(BAN_REFRESH_INTERVAL, shutdown, || async {
+    if let Err(e) = app_state.ip_bans().load(app_state.db_pool()).await {
+      tracing::error!("Failed to refresh IP bans: {}", e);
+    }
+  }).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn test_cidr_parsing() {
+    assert_eq!("192.168.0.0/16".parse::<Cidr>().unwrap().to_string(), "192.168.0.0/16");
+    assert_eq!("10.0.0.1".parse::<Cidr>().unwrap().to_string(), "10.0.0.1/32");
+    assert_eq!("::ffff:10.0.0.1".parse::<Cidr>().unwrap().to_string(), "10.0.0.1/32");
+    assert_eq!("2001:db8::/32".parse::<Cidr>().unwrap().prefix, 32);
+
+    for invalid in ["", "192.168.0.0/33", "2001:db8::/129", "192.168.0.0/", "gg.pl/8", "10.0.0.1/-1"] {
+      assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+    }
+  }
+
+  #[test]
+  fn test_cidr_contains() {
+    let lan = "192.168.0.0/16".parse::<Cidr>().unwrap();
+    assert!(lan.contains(ip("192.168.1.20")));
+    assert!(lan.contains(ip("::ffff:192.168.255.255")));
+    assert!(!lan.contains(ip("192.169.0.1")));
+    assert!(!lan.contains(ip("2001:db8::1")));
+
+    let everyone = "0.0.0.0/0".parse::<Cidr>().unwrap();
+    assert!(everyone.contains(ip("8.8.8.8")));
+
+    let single = "2001:db8::1".parse::<Cidr>().unwrap();
+    assert!(single.contains(ip("2001:db8::1")));
+    assert!(!single.contains(ip("2001:db8::2")));
+  }
+
+  #[test]
+  fn test_deny_wins_over_allow() {
+    let config = AccessConfig {
+      allow: vec!["192.168.0.0/16".parse().unwrap()],
+      deny: vec!["192.168.1.13".parse().unwrap()],
+    };
+
+    assert!(config.is_allowed(ip("192.168.1.12")));
+    assert!(!config.is_allowed(ip("192.168.1.13")));
+    assert!(!config.is_allowed(ip("8.8.8.8")));
+    assert!(AccessConfig::default().is_allowed(ip("8.8.8.8")));
+  }
+}
@@ -1,42 +1,71 @@
 //! Server discovery endpoints.
 //!
 //! These endpoints return the GG server address for clients to connect to.
-//! - `/appsvc/appmsg4.asp` - Plain TCP connection (port 8074)
+//! - `/appsvc/appmsg4.asp` - Plain TCP connection (`advertised_gg_port`, 8074 by default)
 //! - `/appsvc/appmsg3.asp` - TLS connection (port 443)
 
+use std::net::SocketAddr;
 use axum::{Router, routing::get, extract::Query};
-use axum::extract::State;
+use axum::extract::{ConnectInfo, State};
 use axum::http::StatusCode;
 use serde::Deserialize;
 use crate::core::SharedAppState;
+use crate::models::{SystemMessage, SystemMessageId, SystemMessageRepository};
 
 /// Query parameters for server discovery.
+///
+/// Clients also send their UIN (`fmnumber`), version (`version`) and the
+/// response format (`fmt`), none of which change the answer.
 #[derive(Debug, Deserialize)]
 pub struct AppMsgParams {
-  /// User's GG number (UIN).
-  pub fmnumber: Option<u32>,
-  /// Client version in format "A, B, C, D".
-  pub version: Option<String>,
-  /// Response format: None for plain text, "2" for HTML.
-  pub fmt: Option<String>,
   /// Last received system message number.
   pub lastmsg: Option<u32>,
 }
 
 /// Server discovery for plain TCP connections.
 ///
-/// Response format: `MSG_NUM 0 IP:PORT IP`
+/// Response format: `MSG_NUM 0 IP:PORT IP`, then the system message if any.
 /// Example: `0 0 217.17.41.84:8074 217.17.41.84`
-#[tracing::instrument]
-pub async fn appmsg4(Query(params): Query<AppMsgParams>, State(app_state) : State<SharedAppState>) -> Result<String, StatusCode> {
+///
+/// LAN clients are sent to the address of this machine, clients from the
+/// internet to `public_address`.
+#[tracing::instrument(skip(app_state))]
+pub async fn appmsg4(
+  Query(params): Query<AppMsgParams>,
+  ConnectInfo(client): ConnectInfo<SocketAddr>,
+  State(app_state) : State<SharedAppState>
+) -> Result<String, StatusCode> {
   // request{method=GET uri=/appsvc/appmsg4.asp?fmnumber=5000&version=6%2C+1%2C+0%2C+158&fmt=2&lastmsg=26679 version=HTTP/1.0}: tower_http::trace::on_response: finished processing request latency=0 ms status=200
 
-  Ok(format!("0 0 {}:8074 {}", app_state.host_ip, app_state.host_ip).into())
+  let server = app_state.config().advertised_gg_addr(client.ip(), app_state.host_ip);
+  tracing::debug!(client = %client, server = %server, "Advertising GG server");
+
+  // Discovery has to work even if the system message can't be looked up
+  let last_seen = SystemMessageId::from(params.lastmsg.unwrap_or(0));
+  let message = SystemMessageRepository::new(app_state.db_pool())
+    .find_newest_after(last_seen)
+    .await
+    .unwrap_or_else(|e| {
+      tracing::error!("Failed to look up system message: {}", e);
+      None
+    });
+
+  Ok(discovery_response(server, message.as_ref()))
+}
+
+/// `MSG_NUM 0 IP:PORT IP`, followed by the system message body on the next
+/// lines if there is one the client hasn't seen.
+fn discovery_response(server: SocketAddr, message: Option<&SystemMessage>) -> String {
+  match message {
+    Some(message) => format!("{} 0 {} {}\r\n{}", message.id, server, server.ip(), message.body),
+    None => format!("0 0 {} {}", server, server.ip()),
+  }
 }
 
 /// Server discovery for TLS connections.
 ///
-/// Returns empty 200 response (TLS not implemented).
+/// Returns empty 200 response (TLS not implemented), so clients fall back
+/// to `appmsg4`, which also serves system messages.
 #[tracing::instrument]
 pub async fn appmsg3(Query(_params): Query<AppMsgParams>) -> StatusCode {
   StatusCode::OK
@@ -47,3 +76,66 @@ pub fn router() -> Router<SharedAppState> {
     .route("/appsvc/appmsg4.asp", get(appmsg4))
     .route("/appsvc/appmsg3.asp", get(appmsg3))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use figment::Figment;
+  use figment::providers::{Format, Serialized, Toml};
+  use crate::core::ServerConfig;
+
+  fn config(toml: &str) -> ServerConfig {
+    Figment::new()
+      .merge(Toml::string(toml))
+      .join(Serialized::defaults(ServerConfig::default()))
+      .extract()
+      .unwrap()
+  }
+
+  #[test]
+  fn test_advertised_address_depends_on_client_network() {
+    let config = config("public_address = \"203.0.113.7\"\nadvertised_gg_port = 18074");
+    let lan_ip = "192.168.1.10".parse().unwrap();
+
+    for client in ["192.168.1.20", "10.0.0.5", "127.0.0.1", "::ffff:172.16.0.3"] {
+      let addr = config.advertised_gg_addr(client.parse().unwrap(), lan_ip);
+      assert_eq!(addr.to_string(), "192.168.1.10:18074", "client {}", client);
+    }
+    for client in ["198.51.100.1", "8.8.8.8", "2001:db8::1"] {
+      let addr = config.advertised_gg_addr(client.parse().unwrap(), lan_ip);
+      assert_eq!(addr.to_string(), "203.0.113.7:18074", "client {}", client);
+    }
+  }
+
+  #[test]
+  fn test_without_public_address_everyone_gets_lan_address() {
+    let config = config("gg_port = 9000");
+    let addr = config.advertised_gg_addr("8.8.8.8".parse().unwrap(), "192.168.1.10".parse().unwrap());
+    assert_eq!(addr.to_string(), "192.168.1.10:9000");
+  }
+
+  #[test]
+  fn test_unreachable_public_address_is_rejected() {
+    assert!(config("public_address = \"203.0.113.7\"").validate().is_ok());
+    assert!(config("public_address = \"0.0.0.0\"").validate().is_err());
+    assert!(config("public_address = \"239.1.1.1\"").validate().is_err());
+    assert!(config("advertised_gg_port = 0").validate().is_err());
+    assert!(Figment::new()
+      .merge(Toml::string("public_address = \"gg.example.com\""))
+      .join(Serialized::defaults(ServerConfig::default()))
+      .extract::<ServerConfig>()
+      .is_err());
+  }
+
+  #[test]
+  fn test_discovery_response_carries_system_message() {
+    let server = "192.168.1.10:8074".parse().unwrap();
+    assert_eq!(discovery_response(server, None), "0 0 192.168.1.10:8074 192.168.1.10");
+
+    let message = SystemMessage { id: 7, body: "Przerwa techniczna".to_string(), created_at: None, broadcast_at: None };
+    assert_eq!(
+      discovery_response(server, Some(&message)),
+      "7 0 192.168.1.10:8074 192.168.1.10\r\nPrzerwa techniczna"
+    );
+  }
+}
@@ -9,10 +9,8 @@ use axum::{Router, routing::get, extract::{Query, State}, http::{StatusCode, hea
 use axum::response::{IntoResponse, Response};
 use axum::routing::any;
 use serde::Deserialize;
-use image::{ImageBuffer, Rgba, codecs::gif::GifEncoder};
-use imageproc::drawing::draw_text_mut;
-use ab_glyph::{FontRef, PxScale};
 
+use crate::captcha::{CODE_LENGTH, HEIGHT, WIDTH};
 use crate::core::SharedAppState;
 use crate::models::{RepositoryError, TokenRepository};
 
@@ -23,31 +21,6 @@ pub struct TokenPicParams {
   pub tokenid: String,
 }
 
-/// Embedded font for captcha rendering (DejaVu Sans Mono).
-const FONT_DATA: &[u8] = include_bytes!("../../assets/DejaVuSansMono.ttf");
-const WIDTH: u32 = 60;
-const HEIGHT: u32 = 20;
-
-/// Render captcha text to a GIF image.
-fn render_captcha_gif(text: &str) -> Vec<u8> {
-  let white = Rgba([255u8, 255, 255, 255]);
-  let black = Rgba([0u8, 0, 0, 255]);
-
-  let mut img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_pixel(WIDTH, HEIGHT, white);
-
-  let font = FontRef::try_from_slice(FONT_DATA).expect("failed to load font");
-  let scale = PxScale::from(14.0);
-
-  draw_text_mut(&mut img, black, 5, 2, scale, &font, text);
-
-  let mut buf = Vec::new();
-  {
-    let mut encoder = GifEncoder::new(&mut buf);
-    encoder.encode_frame(image::Frame::new(img)).expect("failed to encode gif");
-  }
-  buf
-}
-
 /// Generate a captcha token for account operations.
 ///
 /// Response format:
@@ -66,10 +39,10 @@ fn render_captcha_gif(text: &str) -> Vec<u8> {
 #[tracing::instrument(skip(app_state))]
 pub async fn regtoken(State(app_state): State<SharedAppState>) -> Result<String, RepositoryError> {
   let repo = TokenRepository::new(app_state.db_pool());
-  let token = repo.create().await?;
+  let token = repo.create(&app_state.captcha().generate_code()).await?;
 
   let endpoint = app_state.host_uri("/appsvc/tokenpic.asp").unwrap_or_default().to_string();
-  Ok(format!("{} {} 4\r\n{}\r\n{}", WIDTH, HEIGHT, token.token_id, endpoint))
+  Ok(format!("{} {} {}\r\n{}\r\n{}", WIDTH, HEIGHT, CODE_LENGTH, token.token_id, endpoint))
 }
 
 /// Get captcha image for the given token.
@@ -86,7 +59,7 @@ pub async fn tokenpic(State(app_state): State<SharedAppState>, Query(params): Qu
   };
 
   tracing::info!(token = ?token, "rendering captcha");
-  let gif_data = render_captcha_gif(&token.captcha_code);
+  let gif_data = app_state.captcha().render_gif(&token.captcha_code);
 
   (
     StatusCode::OK,
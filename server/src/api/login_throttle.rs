@@ -0,0 +1,120 @@
+//! Failed password attempts on the web pages.
+//!
+//! Attempts are counted per client address, and with a lower cap per
+//! address and UIN. Counting per UIN alone would let anyone lock the owner
+//! out of their own account by typing wrong passwords for it.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use gg_protocol::GGNumber;
+
+/// Failed attempts allowed from one address within `FAILED_LOGIN_WINDOW`.
+pub const MAX_FAILED_LOGINS_PER_IP: u32 = 20;
+/// Failed attempts allowed from one address against one UIN within `FAILED_LOGIN_WINDOW`.
+pub const MAX_FAILED_LOGINS_PER_UIN: u32 = 5;
+pub const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum LoginKey {
+  Ip(IpAddr),
+  Uin(IpAddr, GGNumber),
+}
+
+impl LoginKey {
+  fn max_failures(&self) -> u32 {
+    match self {
+      LoginKey::Ip(_) => MAX_FAILED_LOGINS_PER_IP,
+      LoginKey::Uin(..) => MAX_FAILED_LOGINS_PER_UIN,
+    }
+  }
+}
+
+/// The address attempts are counted for. IPv6 clients usually get a whole
+/// /64, so it counts as one address.
+fn client(ip: IpAddr) -> IpAddr {
+  match ip.to_canonical() {
+    IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !u128::from(u64::MAX)).into()),
+    ip => ip,
+  }
+}
+
+/// Once an address runs out of attempts, further ones are refused until the
+/// window that started with its first failure is over.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+  failures: Mutex<HashMap<LoginKey, (u32, Instant)>>,
+}
+
+impl LoginThrottle {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  fn keys(ip: IpAddr, uin: Option<GGNumber>) -> impl Iterator<Item = LoginKey> {
+    let ip = client(ip);
+    std::iter::once(LoginKey::Ip(ip)).chain(uin.map(|uin| LoginKey::Uin(ip, uin)))
+  }
+
+  /// Check if attempts from `ip` against `uin` are refused right now.
+  pub fn is_blocked(&self, ip: IpAddr, uin: Option<GGNumber>) -> bool {
+    let now = Instant::now();
+    let failures = self.failures.lock();
+    Self::keys(ip, uin).any(|key| {
+      failures.get(&key)
+        .is_some_and(|(count, since)| *count >= key.max_failures() && now.duration_since(*since) < FAILED_LOGIN_WINDOW)
+    })
+  }
+
+  /// Count a failed attempt from `ip` against `uin`.
+  pub fn fail(&self, ip: IpAddr, uin: Option<GGNumber>) {
+    let now = Instant::now();
+    let mut failures = self.failures.lock();
+    failures.retain(|_, (_, since)| now.duration_since(*since) < FAILED_LOGIN_WINDOW);
+    for key in Self::keys(ip, uin) {
+      failures.entry(key).or_insert((0, now)).0 += 1;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_failed_logins_are_throttled_per_address_and_uin() {
+    let throttle = LoginThrottle::new();
+
+    for _ in 0..MAX_FAILED_LOGINS_PER_UIN {
+      assert!(!throttle.is_blocked(ip("10.0.0.1"), Some(1000)));
+      throttle.fail(ip("10.0.0.1"), Some(1000));
+    }
+    assert!(throttle.is_blocked(ip("10.0.0.1"), Some(1000)));
+    // The owner logging in from elsewhere is not locked out
+    assert!(!throttle.is_blocked(ip("10.0.0.2"), Some(1000)));
+    assert!(!throttle.is_blocked(ip("10.0.0.1"), Some(2000)));
+
+    tokio::time::advance(FAILED_LOGIN_WINDOW).await;
+    assert!(!throttle.is_blocked(ip("10.0.0.1"), Some(1000)));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_failed_logins_are_throttled_per_address() {
+    let throttle = LoginThrottle::new();
+
+    for uin in 0..MAX_FAILED_LOGINS_PER_IP {
+      assert!(!throttle.is_blocked(ip("2001:db8::1"), Some(uin)));
+      throttle.fail(ip("2001:db8::1"), Some(uin));
+    }
+    assert!(throttle.is_blocked(ip("2001:db8::1"), Some(9999)));
+    assert!(throttle.is_blocked(ip("2001:db8::2"), None));
+    assert!(!throttle.is_blocked(ip("2001:db8:0:1::1"), None));
+    assert!(!throttle.is_blocked(ip("10.0.0.1"), None));
+  }
+}
@@ -5,16 +5,25 @@
 //! - Captcha token and image (`captcha`)
 //! - Account registration/management (`register`)
 //! - Password recovery (`sendpwd`)
+//! - Email verification (`verify`)
 //! - Web landing page (`web`)
 
 mod appmsg;
 mod captcha;
+mod login_throttle;
 mod register;
 mod sendpwd;
+mod verify;
 mod web;
 
+pub use login_throttle::LoginThrottle;
+
 use std::fmt::{Display, Formatter};
-use axum::response::IntoResponse;
+use std::net::SocketAddr;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::Router;
 use thiserror::Error;
 use tokio::net::TcpListener;
@@ -22,23 +31,29 @@ use tokio_util::sync::CancellationToken;
 use tower_http::trace::{TraceLayer, DefaultMakeSpan, DefaultOnResponse};
 use tracing::Level;
 use crate::core::SharedAppState;
+use crate::models::RepositoryError;
 
 #[derive(Debug, Error)]
 pub enum ApiRequestError {
   Render(#[from] askama::Error),
+  Repository(#[from] RepositoryError),
 }
 
 impl Display for ApiRequestError {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     match self {
       ApiRequestError::Render(e) => write!(f, "{}", e),
+      ApiRequestError::Repository(e) => write!(f, "{}", e),
     }
   }
 }
 
 impl IntoResponse for ApiRequestError {
   fn into_response(self) -> axum::response::Response {
-    (axum::http::StatusCode::INTERNAL_SERVER_ERROR, (), self.to_string()).into_response()
+    match self {
+      ApiRequestError::Repository(e) => e.into_response(),
+      e => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, (), e.to_string()).into_response(),
+    }
   }
 }
 
@@ -50,6 +65,8 @@ pub fn router(app_state: SharedAppState) -> Router {
     .merge(captcha::router())
     .merge(register::router())
     .merge(sendpwd::router())
+    .merge(verify::router())
+    .layer(middleware::from_fn_with_state(app_state.clone(), check_access))
     .layer(
       TraceLayer::new_for_http()
         .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
@@ -58,6 +75,20 @@ pub fn router(app_state: SharedAppState) -> Router {
     .with_state(app_state)
 }
 
+/// Turn away clients the access lists or IP bans don't let in.
+async fn check_access(
+  State(app_state): State<SharedAppState>,
+  ConnectInfo(client): ConnectInfo<SocketAddr>,
+  request: Request,
+  next: Next,
+) -> Response {
+  if !app_state.is_ip_allowed(client.ip()) {
+    tracing::warn!("Rejected HTTP request from {}: address not allowed", client);
+    return StatusCode::FORBIDDEN.into_response();
+  }
+  next.run(request).await
+}
+
 /// Start the HTTP server on the given listener with graceful shutdown.
 #[tracing::instrument(skip(listener, shutdown, app_state))]
 pub async fn http_server(
@@ -66,7 +97,7 @@ pub async fn http_server(
   app_state: SharedAppState,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
   tracing::info!(bind = ?listener.local_addr()?, "HTTP server listening");
-  axum::serve(listener, router(app_state))
+  axum::serve(listener, router(app_state).into_make_service_with_connect_info::<SocketAddr>())
     .with_graceful_shutdown(async move {
       shutdown.cancelled().await;
       tracing::info!("HTTP server shutting down...");
@@ -74,3 +105,32 @@ pub async fn http_server(
     .await?;
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpStream;
+  use crate::core::test_app_state;
+
+  async fn status_line(overrides: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(http_server(listener, CancellationToken::new(), test_app_state(overrides).await));
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"GET /appsvc/appmsg4.asp HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    server.abort();
+
+    response.lines().next().unwrap_or_default().to_string()
+  }
+
+  #[tokio::test]
+  async fn test_access_lists_guard_http() {
+    assert_eq!(status_line("").await, "HTTP/1.0 200 OK");
+    assert_eq!(status_line("[access]\ndeny = [\"127.0.0.0/8\"]").await, "HTTP/1.0 403 Forbidden");
+    assert_eq!(status_line("[access]\nallow = [\"192.168.0.0/16\"]").await, "HTTP/1.0 403 Forbidden");
+  }
+}
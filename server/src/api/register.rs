@@ -4,8 +4,12 @@
 
 use axum::{Router, routing::post, Form, extract::State};
 use serde::Deserialize;
-use crate::core::SharedAppState;
-use crate::models::{RepositoryError, TokenRepository, UserRepository};
+use crate::api::verify::send_verification_email;
+use crate::core::{RegistrationPolicy, SharedAppState};
+use crate::models::{InviteRepository, RepositoryError, TokenRepository, UserRepository};
+
+/// How many times registration retries when the allocated UIN gets taken concurrently.
+const MAX_UIN_ATTEMPTS: usize = 3;
 
 /// Form fields for account operations.
 #[derive(Debug, Deserialize)]
@@ -18,14 +22,8 @@ pub struct RegisterForm {
   pub tokenid: Option<String>,
   /// User-entered token value (captcha answer).
   pub tokenval: Option<String>,
-  /// Hash code for verification.
-  pub code: Option<String>,
   /// GG number (for existing account operations).
   pub fmnumber: Option<u32>,
-  /// Current password (for account changes).
-  pub fmpwd: Option<String>,
-  /// Set to "1" to delete account.
-  pub delete: Option<String>,
 }
 
 /// Handle account registration, deletion, or password change.
@@ -33,6 +31,11 @@ pub struct RegisterForm {
 /// **Registration:**
 /// - Required: `pwd`, `email`, `tokenid`, `tokenval`, `code`
 /// - Success: `reg_success:UIN`
+/// - Depends on `RegistrationPolicy`: in invite mode the email field must be
+///   `email#CODE`, in approval mode the account is locked until approved,
+///   in closed mode registration always fails.
+/// - A verification link is sent to the email address. Duplicate or malformed
+///   addresses return `reg_failed`.
 ///
 /// **Deletion:**
 /// - Required: `fmnumber`, `fmpwd`, `delete=1`, `tokenid`, `tokenval`, `code`
@@ -46,21 +49,97 @@ pub async fn register(
 ) -> Result<String, RepositoryError> {
   let users = UserRepository::new(app_state.db_pool());
   let tokens = TokenRepository::new(app_state.db_pool());
+  let invites = InviteRepository::new(app_state.db_pool());
+  let policy = app_state.config().registration();
 
   // Registration: no fmnumber means new account
-  if form.fmnumber.is_none() {
-    if let (Some(pwd), Some(email), Some(tokenid), Some(captcha_code)) = (form.pwd, form.email, form.tokenid, form.tokenval) {
-      if !tokens.validate(&tokenid, &captcha_code).await? {
-        tracing::error!("invalid captcha");
-        return Ok("reg_failed".to_string())
-      }
+  if form.fmnumber.is_none()
+    && let (Some(pwd), Some(email), Some(tokenid), Some(captcha_code)) = (form.pwd, form.email, form.tokenid, form.tokenval) {
+    if policy == RegistrationPolicy::Closed {
+      tracing::warn!("registration is closed");
+      return Ok("reg_failed".to_string())
+    }
+
+    let (email, invite_code) = split_invite_code(&email);
+    if policy == RegistrationPolicy::Invite && invite_code.is_none() {
+      tracing::warn!(email = %email, "registration without invite code");
+      return Ok("reg_failed".to_string())
+    }
+
+    if !is_valid_email(email) {
+      tracing::warn!(email = %email, "registration with invalid email");
+      return Ok("reg_failed".to_string())
+    }
+
+    let captcha_valid = if app_state.captcha().requires_answer() {
+      tokens.validate(&tokenid, &captcha_code).await?
+    } else {
+      tokens.consume(&tokenid).await?
+    };
+
+    if !captcha_valid {
+      tracing::error!("invalid captcha");
+      return Ok("reg_failed".to_string())
+    }
+
+    if users.find_by_email(email).await?.is_some() {
+      tracing::warn!(email = %email, "email already registered");
+      return Ok("reg_failed".to_string())
+    }
 
-      let name = email.split('@').next().unwrap_or("user");
-      let user = users.create(name, &email, &pwd).await?;
+    let invite_code = match invite_code {
+      Some(code) if policy == RegistrationPolicy::Invite => {
+        if !invites.claim(code).await? {
+          tracing::error!(code = code, "invalid or used invite code");
+          return Ok("reg_failed".to_string())
+        }
+        Some(code)
+      },
+      _ => None,
+    };
 
-      tracing::info!(uin = user.uin, email = %email, "User registered");
-      return Ok(format!("reg_success:{}", user.uin))
+    let name = email.split('@').next().unwrap_or("user");
+    let approved = policy != RegistrationPolicy::Approval;
+    let mut attempts = 0;
+    let user = loop {
+      attempts += 1;
+      let created = match app_state.uin_allocator().allocate(&users).await {
+        Ok(uin) => users.create(uin, name, email, &pwd, approved).await,
+        Err(e) => {
+          tracing::error!(error = %e, "failed to allocate UIN");
+          if let Some(code) = invite_code {
+            invites.release(code).await?;
+          }
+          return Ok("reg_failed".to_string())
+        }
+      };
+
+      match created {
+        Ok(user) => break user,
+        Err(e) => {
+          // Another registration may have taken the same UIN in the meantime
+          if e.is_unique_violation() && attempts < MAX_UIN_ATTEMPTS && users.find_by_email(email).await?.is_none() {
+            continue;
+          }
+          if let Some(code) = invite_code {
+            invites.release(code).await?;
+          }
+          if e.is_unique_violation() {
+            tracing::warn!(email = %email, "email already registered");
+            return Ok("reg_failed".to_string())
+          }
+          return Err(e)
+        }
+      }
+    };
+
+    if let Some(code) = invite_code {
+      invites.assign(code, user.uin).await?;
     }
+
+    tracing::info!(uin = user.uin, email = %email, approved, "User registered");
+    send_verification_email(app_state.clone(), user.uin, user.email.clone());
+    return Ok(format!("reg_success:{}", user.uin))
   }
 
   // TODO: Implement deletion (fmnumber + fmpwd + delete="1")
@@ -69,7 +148,122 @@ pub async fn register(
   Ok("reg_failed".to_string())
 }
 
+/// Basic sanity check of an email address: `local@domain.tld` without spaces.
+fn is_valid_email(email: &str) -> bool {
+  match email.split_once('@') {
+    Some((local, domain)) => {
+      !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+    },
+    None => false,
+  }
+}
+
+/// Split `email#CODE` into the email address and the invite code.
+fn split_invite_code(email: &str) -> (&str, Option<&str>) {
+  match email.split_once('#') {
+    Some((email, code)) if !code.trim().is_empty() => (email.trim(), Some(code.trim())),
+    Some((email, _)) => (email.trim(), None),
+    None => (email.trim(), None),
+  }
+}
+
 pub fn router() -> Router<SharedAppState> {
   Router::new()
     .route("/appsvc/fmregister3.asp", post(register))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gg_protocol::GGNumber;
+  use crate::core::test_app_state;
+
+  async fn registration(app_state: &SharedAppState, email: &str) -> RegisterForm {
+    let token = TokenRepository::new(app_state.db_pool()).create("KM7R").await.unwrap();
+    RegisterForm {
+      pwd: Some("tajne".to_string()),
+      email: Some(email.to_string()),
+      tokenid: Some(token.token_id),
+      tokenval: Some("KM7R".to_string()),
+      fmnumber: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_duplicate_email_fails_cleanly() {
+    let app_state = test_app_state("").await;
+
+    let first = register(State(app_state.clone()), Form(registration(&app_state, "ala@gg.pl").await)).await.unwrap();
+    assert!(first.starts_with("reg_success:"));
+
+    let second = register(State(app_state.clone()), Form(registration(&app_state, "ala@gg.pl").await)).await.unwrap();
+    assert_eq!(second, "reg_failed");
+    assert_eq!(UserRepository::new(app_state.db_pool()).list().await.unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_closed_registration_fails() {
+    let app_state = test_app_state("registration = \"closed\"").await;
+
+    let result = register(State(app_state.clone()), Form(registration(&app_state, "ala@gg.pl").await)).await.unwrap();
+    assert_eq!(result, "reg_failed");
+    assert!(UserRepository::new(app_state.db_pool()).list().await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_invite_registration_needs_unused_code() {
+    let app_state = test_app_state("registration = \"invite\"").await;
+    let invites = InviteRepository::new(app_state.db_pool());
+    let code = invites.create().await.unwrap().code;
+
+    let without_code = register(State(app_state.clone()), Form(registration(&app_state, "ala@gg.pl").await)).await.unwrap();
+    assert_eq!(without_code, "reg_failed");
+
+    let email = format!("ala@gg.pl#{}", code);
+    let with_code = register(State(app_state.clone()), Form(registration(&app_state, &email).await)).await.unwrap();
+    let uin: GGNumber = with_code.strip_prefix("reg_success:").unwrap().parse().unwrap();
+    assert_eq!(invites.list().await.unwrap()[0].used_by_uin, Some(uin));
+
+    let email = format!("ola@gg.pl#{}", code);
+    let used_code = register(State(app_state.clone()), Form(registration(&app_state, &email).await)).await.unwrap();
+    assert_eq!(used_code, "reg_failed");
+    assert_eq!(UserRepository::new(app_state.db_pool()).list().await.unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_approval_registration_creates_unapproved_account() {
+    let app_state = test_app_state("registration = \"approval\"").await;
+
+    let result = register(State(app_state.clone()), Form(registration(&app_state, "ala@gg.pl").await)).await.unwrap();
+    let uin: GGNumber = result.strip_prefix("reg_success:").unwrap().parse().unwrap();
+
+    let user = UserRepository::new(app_state.db_pool()).find_by_uin(uin).await.unwrap().unwrap();
+    assert!(!user.is_approved());
+  }
+
+  #[test]
+  fn test_split_invite_code() {
+    assert_eq!(split_invite_code("jan@gg.pl"), ("jan@gg.pl", None));
+    assert_eq!(split_invite_code("jan@gg.pl#ABC123"), ("jan@gg.pl", Some("ABC123")));
+    assert_eq!(split_invite_code(" jan@gg.pl # ABC123 "), ("jan@gg.pl", Some("ABC123")));
+    assert_eq!(split_invite_code("jan@gg.pl#"), ("jan@gg.pl", None));
+  }
+
+  #[test]
+  fn test_is_valid_email() {
+    assert!(is_valid_email("jan@gg.pl"));
+    assert!(is_valid_email("jan.kowalski+gg@poczta.example.com"));
+    assert!(!is_valid_email(""));
+    assert!(!is_valid_email("jan"));
+    assert!(!is_valid_email("@gg.pl"));
+    assert!(!is_valid_email("jan@localhost"));
+    assert!(!is_valid_email("jan@gg.pl."));
+    assert!(!is_valid_email("jan@@gg.pl"));
+    assert!(!is_valid_email("jan kowalski@gg.pl"));
+  }
+}
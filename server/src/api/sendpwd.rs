@@ -2,9 +2,10 @@
 //!
 //! `/appsvc/fmsendpwd3.asp` - Sends password reminder to registered email.
 
-use axum::{Router, routing::post, Form};
+use axum::{Router, routing::post, Form, extract::State};
 use serde::Deserialize;
 use crate::core::SharedAppState;
+use crate::models::{RepositoryError, TokenRepository, UserRepository};
 
 /// Form fields for password recovery.
 #[derive(Debug, Deserialize)]
@@ -15,19 +16,71 @@ pub struct SendPwdForm {
   pub tokenid: String,
   /// User-entered token value (captcha answer).
   pub tokenval: String,
-  /// Hash code for verification.
-  pub code: String,
 }
 
 /// Send password reminder to registered email.
 ///
+/// Only accounts with a verified email address can recover their password,
+/// everyone else gets `pwdsend_failed`.
+///
 /// Success response: `pwdsend_success`
-#[tracing::instrument]
-pub async fn sendpwd(Form(form): Form<SendPwdForm>) -> String {
-  todo!("Implement password recovery")
+#[tracing::instrument(skip(app_state))]
+pub async fn sendpwd(
+  State(app_state): State<SharedAppState>,
+  Form(form): Form<SendPwdForm>
+) -> Result<String, RepositoryError> {
+  let users = UserRepository::new(app_state.db_pool());
+  let tokens = TokenRepository::new(app_state.db_pool());
+
+  let captcha_valid = if app_state.captcha().requires_answer() {
+    tokens.validate(&form.tokenid, &form.tokenval).await?
+  } else {
+    tokens.consume(&form.tokenid).await?
+  };
+
+  if !captcha_valid {
+    tracing::error!("invalid captcha");
+    return Ok("pwdsend_failed".to_string())
+  }
+
+  let Some(user) = users.find_by_uin(form.userid).await? else {
+    tracing::warn!(uin = form.userid, "password recovery for unknown user");
+    return Ok("pwdsend_failed".to_string())
+  };
+
+  if !user.is_email_verified() {
+    tracing::warn!(uin = user.uin, "password recovery for unverified email");
+    return Ok("pwdsend_failed".to_string())
+  }
+
+  // TODO: Send the password reminder, only verified addresses get this far
+  tracing::warn!(uin = user.uin, "password recovery is not implemented");
+  Ok("pwdsend_failed".to_string())
 }
 
 pub fn router() -> Router<SharedAppState> {
   Router::new()
     .route("/appsvc/fmsendpwd3.asp", post(sendpwd))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::core::test_app_state;
+
+  async fn form(app_state: &SharedAppState, userid: u32) -> SendPwdForm {
+    let token = TokenRepository::new(app_state.db_pool()).create("KM7R").await.unwrap();
+    SendPwdForm { userid, tokenid: token.token_id, tokenval: "KM7R".to_string() }
+  }
+
+  #[tokio::test]
+  async fn test_unverified_accounts_cannot_recover_password() {
+    let app_state = test_app_state("").await;
+    UserRepository::new(app_state.db_pool()).create(1000, "Ala", "ala@gg.pl", "tajne", true).await.unwrap();
+
+    let unverified = sendpwd(State(app_state.clone()), Form(form(&app_state, 1000).await)).await.unwrap();
+    assert_eq!(unverified, "pwdsend_failed");
+    let unknown = sendpwd(State(app_state.clone()), Form(form(&app_state, 2000).await)).await.unwrap();
+    assert_eq!(unknown, "pwdsend_failed");
+  }
+}
@@ -0,0 +1,113 @@
+//! Email verification endpoint.
+//!
+//! `/verify?token=...` - Confirms the email address from the link sent after registration.
+
+use askama::Template;
+use axum::{Router, routing::get, extract::{Query, State}, response::Html};
+use serde::Deserialize;
+use gg_protocol::GGNumber;
+use crate::api::ApiRequestError;
+use crate::core::SharedAppState;
+use crate::mail::Mail;
+use crate::models::EmailVerificationRepository;
+
+/// Query parameters for email verification.
+#[derive(Debug, Deserialize)]
+pub struct VerifyParams {
+  /// Token from the verification link.
+  pub token: String,
+}
+
+#[derive(Template)]
+#[template(path = "verify.html")]
+struct VerifyTemplate {
+  uin: Option<GGNumber>,
+}
+
+/// Create a verification token and email the link in the background,
+/// so a slow mail server doesn't delay the registration response.
+pub fn send_verification_email(app_state: SharedAppState, uin: GGNumber, email: String) {
+  tokio::spawn(async move {
+    let verifications = EmailVerificationRepository::new(app_state.db_pool());
+
+    let verification = match verifications.create(uin).await {
+      Ok(verification) => verification,
+      Err(e) => {
+        tracing::error!(uin = uin, error = %e, "failed to create email verification");
+        return
+      }
+    };
+
+    let link = app_state.host_uri(&format!("/verify?token={}", verification.token)).unwrap_or_default();
+    let mail = Mail {
+      to: email,
+      subject: "GG-Retro: potwierdz adres email".to_string(),
+      body: format!(
+        "Witaj!\n\nTwoj numer GG to {uin}.\nAby potwierdzic adres email, otworz link:\n\n{link}\n\nLink jest wazny przez 48 godzin.\n"
+      ),
+    };
+
+    if let Err(e) = app_state.mailer().send(mail).await {
+      tracing::error!(uin = uin, error = %e, "failed to send verification email");
+    }
+  });
+}
+
+/// Confirm the email address for the token in the link.
+#[tracing::instrument(skip(app_state))]
+pub async fn verify(
+  State(app_state): State<SharedAppState>,
+  Query(params): Query<VerifyParams>,
+) -> Result<Html<String>, ApiRequestError> {
+  let verifications = EmailVerificationRepository::new(app_state.db_pool());
+  let uin = verifications.verify(&params.token).await?;
+
+  Ok(Html(VerifyTemplate { uin }.render()?))
+}
+
+pub fn router() -> Router<SharedAppState> {
+  Router::new()
+    .route("/verify", get(verify))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::core::test_app_state;
+  use crate::models::UserRepository;
+
+  async fn open(app_state: &SharedAppState, token: &str) -> String {
+    let params = VerifyParams { token: token.to_string() };
+    verify(State(app_state.clone()), Query(params)).await.unwrap().0
+  }
+
+  #[tokio::test]
+  async fn test_verify_link() {
+    let app_state = test_app_state("").await;
+    let users = UserRepository::new(app_state.db_pool());
+    users.create(1000, "Ala", "ala@gg.pl", "tajne", true).await.unwrap();
+    let verification = EmailVerificationRepository::new(app_state.db_pool()).create(1000).await.unwrap();
+
+    assert!(open(&app_state, &verification.token).await.contains("<strong>1000</strong>"));
+    assert!(users.find_by_uin(1000).await.unwrap().unwrap().is_email_verified());
+
+    // A link works only once
+    assert!(open(&app_state, &verification.token).await.contains("nieprawidłowy"));
+    assert!(open(&app_state, "nieznany").await.contains("nieprawidłowy"));
+  }
+
+  #[tokio::test]
+  async fn test_expired_link_is_rejected() {
+    let app_state = test_app_state("").await;
+    let users = UserRepository::new(app_state.db_pool());
+    users.create(1000, "Ala", "ala@gg.pl", "tajne", true).await.unwrap();
+    let verification = EmailVerificationRepository::new(app_state.db_pool()).create(1000).await.unwrap();
+    sqlx::query("UPDATE email_verifications SET created_at = datetime('now', '-49 hours')")
+      .execute(app_state.db_pool())
+      .await
+      .unwrap();
+
+    assert!(open(&app_state, &verification.token).await.contains("wygasł"));
+    assert!(!users.find_by_uin(1000).await.unwrap().unwrap().is_email_verified());
+  }
+}
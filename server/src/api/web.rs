@@ -1,19 +1,25 @@
 //! Static web assets embedded in the binary.
 //!
-//! Serves the landing page and static assets like logo.
+//! Serves the landing page, the auto-reply settings form and static assets
+//! like logo.
 
 use askama::Template;
 use axum::{
+  Form,
   Router,
   response::{Html, IntoResponse},
   routing::get,
-  extract::{State, Query},
+  extract::{ConnectInfo, State, Query},
 };
+use std::net::{IpAddr, SocketAddr};
 use serde::Deserialize;
 use axum_embed::ServeEmbed;
 use rust_embed::RustEmbed;
+use gg_protocol::GGNumber;
+use gg_protocol::consts::GG_MAX_MESSAGE_LENGTH;
 use crate::api::ApiRequestError;
-use crate::core::SharedAppState;
+use crate::core::{AppState, SharedAppState};
+use crate::models::{AutoReply, AutoReplyRepository, RepositoryError, UserRepository};
 
 /// Embedded static assets from the static directory.
 #[derive(RustEmbed, Clone)]
@@ -62,11 +68,229 @@ async fn index(
   Ok(Html(template.render()?))
 }
 
+/// What the auto-reply form asks for.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum AutoReplyAction {
+  /// Show the stored auto-reply.
+  Load,
+  /// Store the auto-reply, or turn it off if the message is empty.
+  #[default]
+  Save,
+  /// Turn the auto-reply off.
+  Clear,
+}
+
+/// Form fields of the auto-reply settings page.
+#[derive(Deserialize)]
+struct AutoReplyForm {
+  uin: String,
+  password: String,
+  #[serde(default)]
+  message: String,
+  offline: Option<String>,
+  busy: Option<String>,
+  #[serde(default)]
+  action: AutoReplyAction,
+}
+
+impl std::fmt::Debug for AutoReplyForm {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("AutoReplyForm")
+      .field("uin", &self.uin)
+      .field("action", &self.action)
+      .finish()
+  }
+}
+
+#[derive(Template)]
+#[template(path = "autoreply.html")]
+struct AutoReplyTemplate {
+  uin: Option<GGNumber>,
+  message: String,
+  offline: bool,
+  busy: bool,
+  max_length: usize,
+  notice: Option<&'static str>,
+  error: bool,
+}
+
+impl AutoReplyTemplate {
+  fn new(uin: Option<GGNumber>) -> Self {
+    Self {
+      uin,
+      message: String::new(),
+      offline: true,
+      busy: true,
+      max_length: GG_MAX_MESSAGE_LENGTH,
+      notice: None,
+      error: false,
+    }
+  }
+
+  fn notice(mut self, notice: &'static str, error: bool) -> Self {
+    self.notice = Some(notice);
+    self.error = error;
+    self
+  }
+}
+
+/// Show an empty auto-reply form.
+async fn auto_reply_form() -> Result<Html<String>, ApiRequestError> {
+  Ok(Html(AutoReplyTemplate::new(None).render()?))
+}
+
+/// Load, store or turn off the auto-reply of the account in the form.
+#[tracing::instrument(skip(app_state))]
+async fn update_auto_reply(
+  State(app_state): State<SharedAppState>,
+  ConnectInfo(client): ConnectInfo<SocketAddr>,
+  Form(form): Form<AutoReplyForm>,
+) -> Result<Html<String>, ApiRequestError> {
+  let template = auto_reply_page(&app_state, client.ip(), form).await?;
+  Ok(Html(template.render()?))
+}
+
+/// Handle the auto-reply form. Wrong passwords are counted by the
+/// `LoginThrottle`, so the form can't be used to guess them.
+async fn auto_reply_page(app_state: &AppState, ip: IpAddr, form: AutoReplyForm) -> Result<AutoReplyTemplate, RepositoryError> {
+  let db_pool = app_state.db_pool();
+  let throttle = app_state.login_throttle();
+  let uin = form.uin.trim().parse::<GGNumber>().ok();
+  let mut template = AutoReplyTemplate::new(uin);
+  template.message = form.message.clone();
+  template.offline = form.offline.is_some();
+  template.busy = form.busy.is_some();
+
+  if throttle.is_blocked(ip, uin) {
+    tracing::warn!(uin = %form.uin, ip = %ip, "auto-reply change refused, too many failed attempts");
+    return Ok(template.notice("Zbyt wiele nieudanych prób. Spróbuj ponownie za kwadrans.", true))
+  }
+
+  let user = match uin {
+    Some(uin) => UserRepository::new(db_pool).find_by_uin(uin).await?,
+    None => None,
+  };
+  let Some(user) = user.filter(|user| user.password == form.password) else {
+    tracing::warn!(uin = %form.uin, ip = %ip, "auto-reply change with wrong number or password");
+    throttle.fail(ip, uin);
+    return Ok(template.notice("Nieprawidłowy numer GG lub hasło.", true))
+  };
+
+  let auto_replies = AutoReplyRepository::new(db_pool);
+  let action = match form.action {
+    AutoReplyAction::Save if form.message.trim().is_empty() => AutoReplyAction::Clear,
+    action => action,
+  };
+  match action {
+    AutoReplyAction::Load => {
+      let Some(auto_reply) = auto_replies.find(user.uin).await? else {
+        return Ok(AutoReplyTemplate::new(uin).notice("Autoodpowiedź jest wyłączona.", false))
+      };
+      template.message = auto_reply.message;
+      template.offline = auto_reply.when_offline;
+      template.busy = auto_reply.when_busy;
+      Ok(template)
+    },
+    AutoReplyAction::Save => {
+      if !AutoReply::is_valid_message(&form.message) {
+        return Ok(template.notice("Autoodpowiedź jest za długa.", true))
+      }
+      if !template.offline && !template.busy {
+        return Ok(template.notice("Zaznacz, kiedy wysyłać autoodpowiedź.", true))
+      }
+      auto_replies.set(user.uin, &form.message, template.offline, template.busy).await?;
+      Ok(template.notice("Autoodpowiedź została zapisana.", false))
+    },
+    AutoReplyAction::Clear => {
+      auto_replies.delete(user.uin).await?;
+      Ok(AutoReplyTemplate::new(uin).notice("Autoodpowiedź jest wyłączona.", false))
+    },
+  }
+}
+
 /// Create router for static web assets.
 pub fn router() -> Router<SharedAppState> {
   let serve_assets = ServeEmbed::<Assets>::new();
 
   Router::new()
     .route("/", get(index))
+    .route("/autoreply", get(auto_reply_form).post(update_auto_reply))
     .nest_service("/static", serve_assets)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::login_throttle::MAX_FAILED_LOGINS_PER_UIN;
+  use crate::core::test_app_state;
+
+  fn ip() -> IpAddr {
+    "10.0.0.1".parse().unwrap()
+  }
+
+  fn form(action: AutoReplyAction, password: &str, message: &str) -> AutoReplyForm {
+    AutoReplyForm {
+      uin: "1000".to_string(),
+      password: password.to_string(),
+      message: message.to_string(),
+      offline: Some("1".to_string()),
+      busy: None,
+      action,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_auto_reply_form() {
+    let app_state = test_app_state("").await;
+    let db_pool = app_state.db_pool();
+    UserRepository::new(db_pool).create(1000, "Ala", "ala@gg.pl", "tajne", true).await.unwrap();
+    let auto_replies = AutoReplyRepository::new(db_pool);
+
+    let page = auto_reply_page(&app_state, ip(), form(AutoReplyAction::Save, "zle", "Nie ma mnie")).await.unwrap();
+    assert!(page.error);
+    assert!(auto_replies.find(1000).await.unwrap().is_none());
+
+    let page = auto_reply_page(&app_state, ip(), form(AutoReplyAction::Save, "tajne", &"x".repeat(GG_MAX_MESSAGE_LENGTH + 1))).await.unwrap();
+    assert!(page.error);
+
+    let page = auto_reply_page(&app_state, ip(), form(AutoReplyAction::Save, "tajne", "Nie ma mnie")).await.unwrap();
+    assert!(!page.error);
+    let stored = auto_replies.find(1000).await.unwrap().unwrap();
+    assert_eq!(stored.message, "Nie ma mnie");
+    assert!(stored.when_offline && !stored.when_busy);
+
+    let page = auto_reply_page(&app_state, ip(), form(AutoReplyAction::Load, "tajne", "")).await.unwrap();
+    assert_eq!(page.message, "Nie ma mnie");
+    assert!(page.render().unwrap().contains("Nie ma mnie</textarea>"));
+
+    // Saving an empty message turns the auto-reply off
+    auto_reply_page(&app_state, ip(), form(AutoReplyAction::Save, "tajne", " ")).await.unwrap();
+    assert!(auto_replies.find(1000).await.unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn test_auto_reply_form_refuses_password_guessing() {
+    let app_state = test_app_state("").await;
+    let db_pool = app_state.db_pool();
+    UserRepository::new(db_pool).create(1000, "Ala", "ala@gg.pl", "tajne", true).await.unwrap();
+    AutoReplyRepository::new(db_pool).set(1000, "Nie ma mnie", true, true).await.unwrap();
+
+    for guess in 0..MAX_FAILED_LOGINS_PER_UIN {
+      let page = auto_reply_page(&app_state, ip(), form(AutoReplyAction::Load, &guess.to_string(), "")).await.unwrap();
+      assert_eq!(page.notice, Some("Nieprawidłowy numer GG lub hasło."));
+    }
+
+    // Even the right password is refused now, and the reply isn't shown
+    let page = auto_reply_page(&app_state, ip(), form(AutoReplyAction::Load, "tajne", "")).await.unwrap();
+    assert!(page.error);
+    assert!(page.notice.unwrap().starts_with("Zbyt wiele"));
+    assert_eq!(page.message, "");
+
+    // The owner can still get in from another address
+    let other = "10.0.0.2".parse().unwrap();
+    let page = auto_reply_page(&app_state, other, form(AutoReplyAction::Load, "tajne", "")).await.unwrap();
+    assert!(!page.error);
+    assert_eq!(page.message, "Nie ma mnie");
+  }
+}
@@ -1,5 +1,5 @@
-/// GG-Retro ASCII banner with Gadu-Gadu sun
-/// Colors match the classic GG orange/yellow theme
+//! GG-Retro ASCII banner with Gadu-Gadu sun
+//! Colors match the classic GG orange/yellow theme
 
 // ANSI color codes for GG theme
 const ORANGE: &str = "\x1b[38;5;208m";  // GG Orange
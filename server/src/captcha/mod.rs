@@ -0,0 +1,180 @@
+//! Captcha generation and rendering.
+//!
+//! The GG 6.0 client shows the captcha image in a fixed 60x20 box, so every
+//! engine renders into exactly that size. The engine is picked in the config:
+//!
+//! ```toml
+//! [captcha]
+//! mode = "noisy"   # "noisy", "plain" or "disabled"
+//! ```
+//!
+//! In `disabled` mode tokens are still issued (the client requires them), but
+//! any answer is accepted. Useful for LAN parties.
+
+mod noisy;
+mod plain;
+
+use std::fmt::Debug;
+use ab_glyph::FontRef;
+use image::{RgbaImage, codecs::gif::GifEncoder};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+pub use noisy::NoisyEngine;
+pub use plain::PlainEngine;
+
+/// Embedded font for captcha rendering (DejaVu Sans Mono).
+const FONT_DATA: &[u8] = include_bytes!("../../assets/DejaVuSansMono.ttf");
+/// Captcha image width expected by the GG 6.0 client.
+pub const WIDTH: u32 = 60;
+/// Captcha image height expected by the GG 6.0 client.
+pub const HEIGHT: u32 = 20;
+/// Number of characters in a captcha code.
+pub const CODE_LENGTH: usize = 4;
+/// Characters used in captcha codes. Skips look-alikes such as 0/O/D/Q, 1/I/L,
+/// 2/Z, 5/S, 6/G and 8/B.
+pub const ALPHABET: &[u8] = b"ACEFHJKMNPRTUVWXY34679";
+
+/// Captcha mode selected in the config.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaMode {
+  /// Noise lines, rotated and jittered glyphs, random colors.
+  #[default]
+  Noisy,
+  /// Black text on white background.
+  Plain,
+  /// Plain image, but any answer is accepted.
+  Disabled,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CaptchaConfig {
+  pub mode: CaptchaMode,
+}
+
+/// Renders a captcha code into a `WIDTH`x`HEIGHT` image.
+pub trait CaptchaEngine: Send + Sync + Debug {
+  fn draw(&self, font: &FontRef<'static>, code: &str, rng: &mut dyn RngCore) -> RgbaImage;
+}
+
+/// Captcha generator configured from `CaptchaConfig`.
+#[derive(Debug)]
+pub struct Captcha {
+  mode: CaptchaMode,
+  engine: Box<dyn CaptchaEngine>,
+  font: FontRef<'static>,
+}
+
+impl Captcha {
+  pub fn new(config: &CaptchaConfig) -> Self {
+    let engine: Box<dyn CaptchaEngine> = match config.mode {
+      CaptchaMode::Noisy => Box::new(NoisyEngine),
+      CaptchaMode::Plain | CaptchaMode::Disabled => Box::new(PlainEngine),
+    };
+
+    Self {
+      mode: config.mode,
+      engine,
+      font: FontRef::try_from_slice(FONT_DATA).expect("failed to load font"),
+    }
+  }
+
+  /// Returns false when any answer should be accepted.
+  pub fn requires_answer(&self) -> bool {
+    self.mode != CaptchaMode::Disabled
+  }
+
+  /// Generate a random captcha code from `ALPHABET`.
+  pub fn generate_code(&self) -> String {
+    let mut rng = rand::rng();
+    (0..CODE_LENGTH)
+      .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())] as char)
+      .collect()
+  }
+
+  /// Render the code to a GIF image.
+  pub fn render_gif(&self, code: &str) -> Vec<u8> {
+    self.encode_gif(self.engine.draw(&self.font, code, &mut rand::rng()))
+  }
+
+  /// Render the code to a GIF image using a fixed seed, so the output is
+  /// always the same. Used by snapshot tests.
+  #[cfg(test)]
+  pub fn render_gif_seeded(&self, code: &str, seed: u64) -> Vec<u8> {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    self.encode_gif(self.engine.draw(&self.font, code, &mut rng))
+  }
+
+  fn encode_gif(&self, img: RgbaImage) -> Vec<u8> {
+    let mut buf = Vec::new();
+    {
+      let mut encoder = GifEncoder::new(&mut buf);
+      encoder.encode_frame(image::Frame::new(img)).expect("failed to encode gif");
+    }
+    buf
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::ImageDecoder;
+  use image::codecs::gif::GifDecoder;
+
+  fn captcha(mode: CaptchaMode) -> Captcha {
+    Captcha::new(&CaptchaConfig { mode })
+  }
+
+  fn gif_dimensions(data: &[u8]) -> (u32, u32) {
+    GifDecoder::new(std::io::Cursor::new(data)).unwrap().dimensions()
+  }
+
+  #[test]
+  fn test_generated_code_uses_alphabet() {
+    let captcha = captcha(CaptchaMode::Noisy);
+
+    for _ in 0..100 {
+      let code = captcha.generate_code();
+      assert_eq!(code.len(), CODE_LENGTH);
+      assert!(code.bytes().all(|c| ALPHABET.contains(&c)));
+    }
+  }
+
+  #[test]
+  fn test_every_mode_renders_client_size() {
+    for mode in [CaptchaMode::Noisy, CaptchaMode::Plain, CaptchaMode::Disabled] {
+      let gif = captcha(mode).render_gif("AC34");
+      assert_eq!(gif_dimensions(&gif), (WIDTH, HEIGHT));
+    }
+  }
+
+  #[test]
+  fn test_seeded_rendering_is_deterministic() {
+    let captcha = captcha(CaptchaMode::Noisy);
+
+    assert_eq!(captcha.render_gif_seeded("XY79", 42), captcha.render_gif_seeded("XY79", 42));
+    assert_ne!(captcha.render_gif_seeded("XY79", 42), captcha.render_gif_seeded("XY79", 43));
+  }
+
+  #[test]
+  fn test_only_disabled_mode_skips_answer() {
+    assert!(captcha(CaptchaMode::Noisy).requires_answer());
+    assert!(captcha(CaptchaMode::Plain).requires_answer());
+    assert!(!captcha(CaptchaMode::Disabled).requires_answer());
+  }
+
+  #[test]
+  fn it_renders_noisy_captcha() {
+    let gif = captcha(CaptchaMode::Noisy).render_gif_seeded("KM7R", 1234);
+    insta::assert_binary_snapshot!(".gif", gif);
+  }
+
+  #[test]
+  fn it_renders_plain_captcha() {
+    let gif = captcha(CaptchaMode::Plain).render_gif_seeded("KM7R", 1234);
+    insta::assert_binary_snapshot!(".gif", gif);
+  }
+}
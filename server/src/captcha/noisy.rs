@@ -0,0 +1,84 @@
+//! Captcha engine that makes OCR harder.
+//!
+//! Each glyph is drawn on its own small canvas, rotated, colored and jittered
+//! before being placed on a tinted background crossed by noise lines.
+
+use ab_glyph::{FontRef, PxScale};
+use image::{Rgba, RgbaImage, imageops};
+use imageproc::drawing::{draw_line_segment_mut, draw_text_mut};
+use imageproc::geometric_transformations::{Interpolation, rotate_about_center};
+use rand::{Rng, RngCore};
+use crate::captcha::{CaptchaEngine, HEIGHT, WIDTH};
+
+/// Maximum glyph rotation in radians (about 25 degrees).
+const MAX_ROTATION: f32 = 0.44;
+/// Size of the canvas a single glyph is drawn on before rotation.
+const GLYPH_SIZE: u32 = 18;
+/// Horizontal distance between glyphs.
+const GLYPH_STEP: i64 = 14;
+/// Noise lines drawn under the glyphs.
+const LINES_BELOW: usize = 3;
+/// Noise lines drawn over the glyphs.
+const LINES_ABOVE: usize = 2;
+/// Random dots sprinkled over the image.
+const NOISE_DOTS: usize = 40;
+
+#[derive(Debug)]
+pub struct NoisyEngine;
+
+impl NoisyEngine {
+  fn random_color(rng: &mut dyn RngCore, range: std::ops::RangeInclusive<u8>) -> Rgba<u8> {
+    Rgba([
+      rng.random_range(range.clone()),
+      rng.random_range(range.clone()),
+      rng.random_range(range),
+      255,
+    ])
+  }
+
+  fn draw_noise_lines(img: &mut RgbaImage, rng: &mut dyn RngCore, count: usize) {
+    for _ in 0..count {
+      let start = (0.0, rng.random_range(0.0..HEIGHT as f32));
+      let end = (WIDTH as f32, rng.random_range(0.0..HEIGHT as f32));
+      let color = Self::random_color(rng, 60..=180);
+      draw_line_segment_mut(img, start, end, color);
+    }
+  }
+
+  fn draw_glyph(font: &FontRef<'static>, glyph: char, rng: &mut dyn RngCore) -> RgbaImage {
+    let mut canvas = RgbaImage::from_pixel(GLYPH_SIZE, GLYPH_SIZE, Rgba([0, 0, 0, 0]));
+    let color = Self::random_color(rng, 0..=110);
+    let scale = PxScale::from(rng.random_range(14.0..17.0));
+    draw_text_mut(&mut canvas, color, 3, 0, scale, font, glyph.encode_utf8(&mut [0; 4]));
+
+    let theta = rng.random_range(-MAX_ROTATION..MAX_ROTATION);
+    rotate_about_center(&canvas, theta, Interpolation::Bilinear, Rgba([0, 0, 0, 0]))
+  }
+}
+
+impl CaptchaEngine for NoisyEngine {
+  fn draw(&self, font: &FontRef<'static>, code: &str, rng: &mut dyn RngCore) -> RgbaImage {
+    let background = Self::random_color(rng, 225..=255);
+    let mut img = RgbaImage::from_pixel(WIDTH, HEIGHT, background);
+
+    Self::draw_noise_lines(&mut img, rng, LINES_BELOW);
+
+    for (i, glyph) in code.chars().enumerate() {
+      let glyph_img = Self::draw_glyph(font, glyph, rng);
+      let x = 1 + i as i64 * GLYPH_STEP + rng.random_range(-1..=1);
+      let y = rng.random_range(-2..=1);
+      imageops::overlay(&mut img, &glyph_img, x, y);
+    }
+
+    Self::draw_noise_lines(&mut img, rng, LINES_ABOVE);
+
+    for _ in 0..NOISE_DOTS {
+      let x = rng.random_range(0..WIDTH);
+      let y = rng.random_range(0..HEIGHT);
+      let color = Self::random_color(rng, 0..=255);
+      img.put_pixel(x, y, color);
+    }
+
+    img
+  }
+}
@@ -0,0 +1,21 @@
+//! Legacy captcha engine: black text on white background.
+
+use ab_glyph::{FontRef, PxScale};
+use image::{Rgba, RgbaImage};
+use imageproc::drawing::draw_text_mut;
+use rand::RngCore;
+use crate::captcha::{CaptchaEngine, HEIGHT, WIDTH};
+
+#[derive(Debug)]
+pub struct PlainEngine;
+
+impl CaptchaEngine for PlainEngine {
+  fn draw(&self, font: &FontRef<'static>, code: &str, _rng: &mut dyn RngCore) -> RgbaImage {
+    let white = Rgba([255u8, 255, 255, 255]);
+    let black = Rgba([0u8, 0, 0, 255]);
+
+    let mut img = RgbaImage::from_pixel(WIDTH, HEIGHT, white);
+    draw_text_mut(&mut img, black, 5, 2, PxScale::from(14.0), font, code);
+    img
+  }
+}
@@ -0,0 +1,55 @@
+//! `ban` subcommands.
+
+use std::net::IpAddr;
+use clap::Subcommand;
+use crate::cli::CliResult;
+use crate::core::{prepare_database, ServerConfig};
+use crate::models::IpBanRepository;
+
+#[derive(Subcommand, Debug)]
+pub enum BanCommand {
+  /// Ban an IP address. A running server drops new connections from it within a few seconds
+  Add {
+    ip: IpAddr,
+    /// Why the address is banned
+    #[arg(long)]
+    reason: Option<String>,
+  },
+  /// Lift an IP ban
+  Remove {
+    ip: IpAddr,
+  },
+  /// List banned IP addresses
+  List,
+}
+
+pub async fn run(command: BanCommand, config: &ServerConfig) -> CliResult {
+  let db_pool = prepare_database(config).await?;
+  let bans = IpBanRepository::new(&db_pool);
+
+  match command {
+    BanCommand::Add { ip, reason } => {
+      if !bans.ban(ip, reason.as_deref()).await? {
+        eprintln!("{} is already banned", ip);
+      }
+    },
+    BanCommand::Remove { ip } => {
+      if !bans.unban(ip).await? {
+        return Err(format!("{} is not banned", ip).into())
+      }
+    },
+    BanCommand::List => {
+      println!("ip\tcreated_at\treason");
+      for ban in bans.list().await? {
+        println!(
+          "{}\t{}\t{}",
+          ban.ip,
+          ban.created_at.as_deref().unwrap_or("-"),
+          ban.reason.as_deref().unwrap_or("-"),
+        );
+      }
+    },
+  }
+
+  Ok(())
+}
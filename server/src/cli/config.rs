@@ -0,0 +1,34 @@
+//! `config` subcommands.
+
+use std::net::SocketAddr;
+use clap::Subcommand;
+use crate::captcha::Captcha;
+use crate::cli::CliResult;
+use crate::core::ServerConfig;
+use crate::mail::Mailer;
+use crate::uin::UinAllocator;
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+  /// Validate the configuration and print the effective values
+  Check,
+}
+
+pub fn run(command: ConfigCommand, config: &ServerConfig) -> CliResult {
+  match command {
+    ConfigCommand::Check => {
+      for bind in config.gg_binds().into_iter().chain([config.api_bind()]) {
+        bind.parse::<SocketAddr>().map_err(|e| format!("invalid bind address {}: {}", bind, e))?;
+      }
+      config.validate()?;
+      UinAllocator::new(config.uin())?;
+      Mailer::new(config.mail())?;
+      Captcha::new(config.captcha());
+
+      println!("{:#?}", config);
+      println!("config ok");
+    },
+  }
+
+  Ok(())
+}
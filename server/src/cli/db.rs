@@ -0,0 +1,51 @@
+//! `db` subcommands.
+
+use std::path::PathBuf;
+use clap::Subcommand;
+use crate::cli::CliResult;
+use crate::core::{open_database, ServerConfig};
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+  /// Apply pending migrations
+  Migrate,
+  /// Write a consistent copy of the database, safe while the server is running
+  Backup {
+    /// Target file, must not exist
+    path: PathBuf,
+  },
+}
+
+pub async fn run(command: DbCommand, config: &ServerConfig) -> CliResult {
+  let db_pool = open_database(config).await?;
+
+  match command {
+    DbCommand::Migrate => {
+      let migrator = sqlx::migrate!("./migrations");
+      let applied: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+        .fetch_all(&db_pool)
+        .await
+        .unwrap_or_default();
+
+      migrator.run(&db_pool).await?;
+
+      for migration in migrator.iter().filter(|m| !applied.contains(&m.version)) {
+        println!("{} {}", migration.version, migration.description);
+      }
+    },
+    DbCommand::Backup { path } => {
+      if path.exists() {
+        return Err(format!("{} already exists", path.display()).into())
+      }
+
+      sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy())
+        .execute(&db_pool)
+        .await?;
+      println!("{}", path.display());
+    },
+  }
+
+  db_pool.close().await;
+  Ok(())
+}
@@ -0,0 +1,30 @@
+//! `messages` subcommands.
+
+use clap::Subcommand;
+use crate::cli::CliResult;
+use crate::core::{prepare_database, ServerConfig};
+use crate::models::MessageRepository;
+
+#[derive(Subcommand, Debug)]
+pub enum MessagesCommand {
+  /// Delete offline messages that were already delivered
+  Purge {
+    /// Only delete messages delivered more than this many days ago
+    #[arg(long, default_value_t = 30)]
+    days: u32,
+  },
+}
+
+pub async fn run(command: MessagesCommand, config: &ServerConfig) -> CliResult {
+  let db_pool = prepare_database(config).await?;
+  let messages = MessageRepository::new(&db_pool);
+
+  match command {
+    MessagesCommand::Purge { days } => {
+      let deleted = messages.cleanup_old_delivered(i64::from(days) * 24 * 60).await?;
+      println!("{}", deleted);
+    },
+  }
+
+  Ok(())
+}
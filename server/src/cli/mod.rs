@@ -0,0 +1,85 @@
+//! Command line interface.
+//!
+//! Running `gg-retro` without arguments starts the server. The other
+//! subcommands are admin tools that work on the same config and database,
+//! so they can be used in scripts:
+//!
+//! ```text
+//! gg-retro user add jan@example.com < password.txt
+//! gg-retro user list
+//! gg-retro user ban 1234567 --reason spam
+//! gg-retro user auto-reply 1234567 "Jestem na urlopie"
+//! gg-retro messages purge --days 30
+//! gg-retro system-message publish "Przerwa techniczna o 22:00"
+//! gg-retro ban add 203.0.113.7 --reason flood
+//! gg-retro db backup /var/backups/gg.db
+//! gg-retro config check
+//! ```
+
+mod ban;
+mod config;
+mod db;
+mod messages;
+mod system_message;
+mod user;
+
+use clap::{Parser, Subcommand};
+use crate::core::load_config;
+
+pub type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+#[derive(Parser, Debug)]
+#[command(name = "gg-retro", version, about = "Serwer protokolu Gadu-Gadu 6.0")]
+pub struct Cli {
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// Start the GG and HTTP servers (default)
+  Serve,
+  #[command(flatten)]
+  Admin(AdminCommand),
+}
+
+/// Subcommands that work on the config and database without starting the server.
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+  /// Manage user accounts
+  #[command(subcommand)]
+  User(user::UserCommand),
+  /// Manage invite codes
+  #[command(subcommand)]
+  Invite(user::InviteCommand),
+  /// Manage stored offline messages
+  #[command(subcommand)]
+  Messages(messages::MessagesCommand),
+  /// Publish system messages
+  #[command(subcommand)]
+  SystemMessage(system_message::SystemMessageCommand),
+  /// Manage banned IP addresses
+  #[command(subcommand)]
+  Ban(ban::BanCommand),
+  /// Database maintenance
+  #[command(subcommand)]
+  Db(db::DbCommand),
+  /// Inspect the configuration
+  #[command(subcommand)]
+  Config(config::ConfigCommand),
+}
+
+/// Run an admin subcommand.
+pub async fn run(command: AdminCommand) -> CliResult {
+  let config = load_config()?;
+
+  match command {
+    AdminCommand::User(command) => user::run(command, &config).await,
+    AdminCommand::Invite(command) => user::run_invite(command, &config).await,
+    AdminCommand::Messages(command) => messages::run(command, &config).await,
+    AdminCommand::SystemMessage(command) => system_message::run(command, &config).await,
+    AdminCommand::Ban(command) => ban::run(command, &config).await,
+    AdminCommand::Db(command) => db::run(command, &config).await,
+    AdminCommand::Config(command) => config::run(command, &config),
+  }
+}
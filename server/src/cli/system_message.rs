@@ -0,0 +1,45 @@
+//! `system-message` subcommands.
+
+use clap::Subcommand;
+use crate::cli::CliResult;
+use crate::core::{prepare_database, ServerConfig};
+use crate::models::SystemMessageRepository;
+
+#[derive(Subcommand, Debug)]
+pub enum SystemMessageCommand {
+  /// Publish a system message, printing its number. A running server sends it to online users within a few seconds
+  Publish {
+    /// Message text
+    text: String,
+  },
+  /// List published system messages
+  List,
+}
+
+pub async fn run(command: SystemMessageCommand, config: &ServerConfig) -> CliResult {
+  let db_pool = prepare_database(config).await?;
+  let messages = SystemMessageRepository::new(&db_pool);
+
+  match command {
+    SystemMessageCommand::Publish { text } => {
+      if text.trim().is_empty() {
+        return Err("system message can't be empty".into())
+      }
+      println!("{}", messages.publish(&text).await?.id);
+    },
+    SystemMessageCommand::List => {
+      println!("id\tcreated_at\tbroadcast_at\tbody");
+      for message in messages.list().await? {
+        println!(
+          "{}\t{}\t{}\t{}",
+          message.id,
+          message.created_at.as_deref().unwrap_or("-"),
+          message.broadcast_at.as_deref().unwrap_or("-"),
+          message.body.replace('\n', " "),
+        );
+      }
+    },
+  }
+
+  Ok(())
+}
@@ -0,0 +1,327 @@
+//! `user` and `invite` subcommands.
+
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::time::Duration;
+use clap::{Args, Subcommand};
+use gg_protocol::GGNumber;
+use gg_protocol::consts::GG_MAX_MESSAGE_LENGTH;
+use crate::cli::CliResult;
+use crate::core::{prepare_database, ServerConfig};
+use crate::models::{
+  AutoReply, AutoReplyRepository, DatabasePool, InviteRepository, ModerationKind, ModerationRepository, UserRepository
+};
+use crate::uin::UinAllocator;
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+  /// Create an account and print its UIN
+  Add {
+    /// Email address
+    email: String,
+    /// Password used to log in from the GG client. Read from standard input
+    /// if not given, which keeps it out of shell history and `ps`
+    #[arg(long)]
+    password: Option<String>,
+    /// Display name (defaults to the part of the email before `@`)
+    #[arg(long)]
+    name: Option<String>,
+    /// Assign this UIN instead of allocating one (reserved ranges allowed)
+    #[arg(long)]
+    uin: Option<GGNumber>,
+    /// Leave the account waiting for `user approve`
+    #[arg(long)]
+    unapproved: bool,
+  },
+  /// Change the password of an account
+  Passwd {
+    uin: GGNumber,
+    /// New password, read from standard input if not given
+    #[arg(long)]
+    password: Option<String>,
+  },
+  /// Approve an account created in approval mode
+  Approve {
+    uin: GGNumber,
+  },
+  /// Delete an account and everything stored for it
+  Delete {
+    uin: GGNumber,
+  },
+  /// List all accounts (tab separated)
+  List,
+  /// Ban an account: it can't log in and its online sessions are kicked
+  Ban {
+    uin: GGNumber,
+    #[command(flatten)]
+    entry: ModerationArgs,
+  },
+  /// Lift a ban
+  Unban {
+    uin: GGNumber,
+  },
+  /// Suspend an account for a while, like a ban that expires
+  Suspend {
+    uin: GGNumber,
+    #[arg(long, default_value_t = 0)]
+    days: u64,
+    #[arg(long, default_value_t = 0)]
+    hours: u64,
+    #[command(flatten)]
+    entry: ModerationArgs,
+  },
+  /// Lift a suspension before it expires
+  Unsuspend {
+    uin: GGNumber,
+  },
+  /// Mute an account: it can log in, but its messages are not delivered
+  Mute {
+    uin: GGNumber,
+    #[command(flatten)]
+    entry: ModerationArgs,
+  },
+  /// Lift a mute
+  Unmute {
+    uin: GGNumber,
+  },
+  /// List banned, suspended and muted accounts (tab separated)
+  Moderation,
+  /// Set the message sent back to people writing to the user while away
+  AutoReply {
+    uin: GGNumber,
+    message: String,
+    /// Only reply while the user is offline or invisible
+    #[arg(long, conflicts_with = "busy_only")]
+    offline_only: bool,
+    /// Only reply while the user is busy with a description
+    #[arg(long)]
+    busy_only: bool,
+  },
+  /// Turn off the auto-reply of an account
+  ClearAutoReply {
+    uin: GGNumber,
+  },
+  /// List auto-replies (tab separated)
+  AutoReplies,
+}
+
+#[derive(Args, Debug)]
+pub struct ModerationArgs {
+  /// Why the account is moderated
+  #[arg(long)]
+  reason: Option<String>,
+  /// Who moderates (defaults to $USER)
+  #[arg(long)]
+  moderator: Option<String>,
+}
+
+impl ModerationArgs {
+  fn moderator(&self) -> String {
+    self.moderator.clone()
+      .or_else(|| std::env::var("USER").ok())
+      .unwrap_or_else(|| "admin".to_string())
+  }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum InviteCommand {
+  /// Create invite codes for invite-only registration
+  Create {
+    /// How many codes to create
+    #[arg(long, default_value_t = 1)]
+    count: usize,
+  },
+  /// List invite codes and who used them
+  List,
+}
+
+pub async fn run(command: UserCommand, config: &ServerConfig) -> CliResult {
+  let db_pool = prepare_database(config).await?;
+  let users = UserRepository::new(&db_pool);
+
+  match command {
+    UserCommand::Add { email, password, name, uin, unapproved } => {
+      if users.find_by_email(&email).await?.is_some() {
+        return Err(format!("email {} is already registered", email).into())
+      }
+
+      let allocator = UinAllocator::new(config.uin())?;
+      let uin = match uin {
+        Some(uin) => allocator.check_manual(&users, uin).await?,
+        None => allocator.allocate(&users).await?,
+      };
+      let password = read_password(password)?;
+      let name = name.unwrap_or_else(|| email.split('@').next().unwrap_or("user").to_string());
+
+      let user = users.create(uin, &name, &email, &password, !unapproved).await?;
+      println!("{}", user.uin);
+    },
+    UserCommand::Passwd { uin, password } => {
+      let password = read_password(password)?;
+      if !users.update_password(uin, &password).await? {
+        return Err(format!("user {} not found", uin).into())
+      }
+    },
+    UserCommand::Approve { uin } => {
+      if !users.exists(uin).await? {
+        return Err(format!("user {} not found", uin).into())
+      }
+      if !users.approve(uin).await? {
+        eprintln!("user {} is already approved", uin);
+      }
+    },
+    UserCommand::Delete { uin } => {
+      if !users.delete(uin).await? {
+        return Err(format!("user {} not found", uin).into())
+      }
+    },
+    UserCommand::List => {
+      println!("uin\tname\temail\tapproved\tverified\tcreated_at");
+      for user in users.list().await? {
+        println!(
+          "{}\t{}\t{}\t{}\t{}\t{}",
+          user.uin,
+          user.name,
+          user.email,
+          if user.is_approved() { "yes" } else { "no" },
+          if user.is_email_verified() { "yes" } else { "no" },
+          user.created_at.as_deref().unwrap_or("-"),
+        );
+      }
+    },
+    UserCommand::Ban { uin, entry } => {
+      moderate(&db_pool, uin, ModerationKind::Ban, None, &entry).await?;
+    },
+    UserCommand::Suspend { uin, days, hours, entry } => {
+      let duration = Duration::from_secs((days * 24 + hours) * 3600);
+      if duration.is_zero() {
+        return Err("a suspension needs --days or --hours".into())
+      }
+      moderate(&db_pool, uin, ModerationKind::Suspension, Some(duration), &entry).await?;
+    },
+    UserCommand::Mute { uin, entry } => {
+      moderate(&db_pool, uin, ModerationKind::Mute, None, &entry).await?;
+    },
+    UserCommand::Unban { uin } => lift(&db_pool, uin, ModerationKind::Ban).await?,
+    UserCommand::Unsuspend { uin } => lift(&db_pool, uin, ModerationKind::Suspension).await?,
+    UserCommand::Unmute { uin } => lift(&db_pool, uin, ModerationKind::Mute).await?,
+    UserCommand::Moderation => {
+      println!("uin\tkind\treason\tmoderator\tuntil\tcreated_at");
+      for entry in ModerationRepository::new(&db_pool).list().await? {
+        println!(
+          "{}\t{}\t{}\t{}\t{}\t{}",
+          entry.uin,
+          entry.kind,
+          entry.reason.as_deref().unwrap_or("-"),
+          entry.moderator,
+          entry.until.as_deref().unwrap_or("-"),
+          entry.created_at.as_deref().unwrap_or("-"),
+        );
+      }
+    },
+    UserCommand::AutoReply { uin, message, offline_only, busy_only } => {
+      if !users.exists(uin).await? {
+        return Err(format!("user {} not found", uin).into())
+      }
+      if !AutoReply::is_valid_message(&message) {
+        return Err(format!("auto-reply must be 1-{} characters", GG_MAX_MESSAGE_LENGTH).into())
+      }
+      AutoReplyRepository::new(&db_pool).set(uin, &message, !busy_only, !offline_only).await?;
+    },
+    UserCommand::ClearAutoReply { uin } => {
+      if !AutoReplyRepository::new(&db_pool).delete(uin).await? {
+        eprintln!("user {} has no auto-reply", uin);
+      }
+    },
+    UserCommand::AutoReplies => {
+      println!("uin\toffline\tbusy\tupdated_at\tmessage");
+      for auto_reply in AutoReplyRepository::new(&db_pool).list().await? {
+        println!(
+          "{}\t{}\t{}\t{}\t{}",
+          auto_reply.uin,
+          if auto_reply.when_offline { "yes" } else { "no" },
+          if auto_reply.when_busy { "yes" } else { "no" },
+          auto_reply.updated_at.as_deref().unwrap_or("-"),
+          auto_reply.message.replace(['\t', '\n'], " "),
+        );
+      }
+    },
+  }
+
+  Ok(())
+}
+
+async fn moderate(
+  db_pool: &DatabasePool,
+  uin: GGNumber,
+  kind: ModerationKind,
+  duration: Option<Duration>,
+  entry: &ModerationArgs
+) -> CliResult {
+  if !UserRepository::new(db_pool).exists(uin).await? {
+    return Err(format!("user {} not found", uin).into())
+  }
+
+  let moderation = ModerationRepository::new(db_pool)
+    .apply(uin, kind, duration, entry.reason.as_deref(), &entry.moderator())
+    .await?;
+  if let Some(until) = moderation.until {
+    println!("{}", until);
+  }
+  Ok(())
+}
+
+async fn lift(db_pool: &DatabasePool, uin: GGNumber, kind: ModerationKind) -> CliResult {
+  if !ModerationRepository::new(db_pool).lift(uin, kind).await? {
+    eprintln!("user {} has no {}", uin, kind);
+  }
+  Ok(())
+}
+
+/// The password given with `--password`, or else the first line of
+/// standard input.
+fn read_password(password: Option<String>) -> Result<String, Box<dyn std::error::Error>> {
+  if let Some(password) = password {
+    return Ok(password)
+  }
+
+  let stdin = io::stdin();
+  if stdin.is_terminal() {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+  }
+  let mut line = String::new();
+  stdin.lock().read_line(&mut line)?;
+
+  let password = line.trim_end_matches(['\r', '\n']);
+  if password.is_empty() {
+    return Err("no password given".into())
+  }
+  Ok(password.to_string())
+}
+
+pub async fn run_invite(command: InviteCommand, config: &ServerConfig) -> CliResult {
+  let db_pool = prepare_database(config).await?;
+  let invites = InviteRepository::new(&db_pool);
+
+  match command {
+    InviteCommand::Create { count } => {
+      for _ in 0..count {
+        println!("{}", invites.create().await?.code);
+      }
+    },
+    InviteCommand::List => {
+      println!("code\tused_by\tcreated_at\tused_at");
+      for invite in invites.list().await? {
+        println!(
+          "{}\t{}\t{}\t{}",
+          invite.code,
+          invite.used_by_uin.map_or("-".to_string(), |uin| uin.to_string()),
+          invite.created_at.as_deref().unwrap_or("-"),
+          invite.used_at.as_deref().unwrap_or("-"),
+        );
+      }
+    },
+  }
+
+  Ok(())
+}
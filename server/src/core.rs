@@ -1,22 +1,78 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use axum::http;
 use axum::http::Uri;
 use figment::Figment;
 use figment::providers::{Env, Format, Serialized, Toml};
 use local_ip_address::local_ip;
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
-use crate::messenger::{MessageDispatcher, PresenceHub};
+use tokio_util::sync::CancellationToken;
+use crate::access::{AccessConfig, IpBans};
+use crate::api::LoginThrottle;
+use crate::captcha::{Captcha, CaptchaConfig};
+use crate::mail::{MailConfig, Mailer};
+use crate::messenger::{ConnectionLimits, FilterConfig, FloodConfig, FloodGuard, LimitsConfig, MessageDispatcher, MessageFilters, MotdConfig, PresenceHub, SessionConfig, SessionRegistry};
 use crate::models::DatabasePool;
+use crate::uin::{UinAllocator, UinConfig};
+
+/// Who is allowed to create new accounts.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationPolicy {
+  /// Anyone can register.
+  #[default]
+  Open,
+  /// Registration requires an invite code, entered as `email#CODE`.
+  Invite,
+  /// Accounts are created, but can't log in until an admin approves them.
+  Approval,
+  /// Registration is disabled.
+  Closed,
+}
+
+/// Extra address the GG server accepts connections on, e.g. port 443 for
+/// clients behind firewalls that block 8074.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct GGListenerConfig {
+  pub bind: String,
+  pub port: u16,
+}
+
+impl Default for GGListenerConfig {
+  fn default() -> Self {
+    Self {
+      bind: "0.0.0.0".to_string(),
+      port: 443,
+    }
+  }
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ServerConfig {
   bind: String,
   http_port: u16,
   gg_port: u16,
+  gg_listeners: Vec<GGListenerConfig>,
   db: String,
   hostname: String,
+  /// Address advertised to clients connecting from the internet.
+  public_address: Option<Ipv4Addr>,
+  /// GG port advertised to clients, if it differs from `gg_port`, e.g. behind NAT.
+  advertised_gg_port: Option<u16>,
+  registration: RegistrationPolicy,
+  captcha: CaptchaConfig,
+  mail: MailConfig,
+  uin: UinConfig,
+  session: SessionConfig,
+  limits: LimitsConfig,
+  flood: FloodConfig,
+  filter: FilterConfig,
+  access: AccessConfig,
+  motd: MotdConfig,
 }
 
 impl Default for ServerConfig {
@@ -25,8 +81,21 @@ impl Default for ServerConfig {
       bind: "0.0.0.0".to_string(),
       http_port: 80,
       gg_port: 8074,
+      gg_listeners: Vec::new(),
       db: "./gg.db".to_string(),
       hostname: "gg-retro.local".to_string(),
+      public_address: None,
+      advertised_gg_port: None,
+      registration: RegistrationPolicy::default(),
+      captcha: CaptchaConfig::default(),
+      mail: MailConfig::default(),
+      uin: UinConfig::default(),
+      session: SessionConfig::default(),
+      limits: LimitsConfig::default(),
+      flood: FloodConfig::default(),
+      filter: FilterConfig::default(),
+      access: AccessConfig::default(),
+      motd: MotdConfig::default(),
     }
   }
 }
@@ -40,9 +109,159 @@ impl ServerConfig {
     format!("{}:{}", self.bind, self.gg_port)
   }
 
+  /// Every address the GG server listens on: `bind`:`gg_port` first, then
+  /// the extra `gg_listeners`.
+  pub fn gg_binds(&self) -> Vec<String> {
+    let mut binds = vec![self.gg_bind()];
+    for listener in &self.gg_listeners {
+      let bind = format!("{}:{}", listener.bind, listener.port);
+      if !binds.contains(&bind) {
+        binds.push(bind);
+      }
+    }
+    binds
+  }
+
+  pub fn db(&self) -> &str {
+    &self.db
+  }
+
   pub fn hostname(&self) -> &str {
     &self.hostname
   }
+
+  /// Where a client at `client` should connect to. Clients on a private
+  /// network get `lan_ip`, everyone else `public_address` if it is set.
+  pub fn advertised_gg_addr(&self, client: IpAddr, lan_ip: IpAddr) -> SocketAddr {
+    let client = match client {
+      IpAddr::V4(ip) => ip,
+      IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED),
+    };
+    let on_lan = client.is_private() || client.is_loopback() || client.is_link_local();
+
+    let ip = match self.public_address {
+      Some(public) if !on_lan => IpAddr::V4(public),
+      _ => lan_ip,
+    };
+    SocketAddr::new(ip, self.advertised_gg_port.unwrap_or(self.gg_port))
+  }
+
+  /// Check the settings that serde can't.
+  pub fn validate(&self) -> Result<(), String> {
+    let unreachable = |ip: &Ipv4Addr| ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast();
+    if let Some(public) = self.public_address.filter(unreachable) {
+      return Err(format!("public_address {} can't be connected to", public));
+    }
+    if self.advertised_gg_port == Some(0) {
+      return Err("advertised_gg_port can't be 0".to_string());
+    }
+    self.motd.validate()?;
+    Ok(())
+  }
+
+  pub fn registration(&self) -> RegistrationPolicy {
+    self.registration
+  }
+
+  pub fn captcha(&self) -> &CaptchaConfig {
+    &self.captcha
+  }
+
+  pub fn mail(&self) -> &MailConfig {
+    &self.mail
+  }
+
+  pub fn uin(&self) -> &UinConfig {
+    &self.uin
+  }
+
+  pub fn session(&self) -> &SessionConfig {
+    &self.session
+  }
+
+  pub fn limits(&self) -> &LimitsConfig {
+    &self.limits
+  }
+
+  pub fn flood(&self) -> &FloodConfig {
+    &self.flood
+  }
+
+  pub fn filter(&self) -> &FilterConfig {
+    &self.filter
+  }
+
+  pub fn access(&self) -> &AccessConfig {
+    &self.access
+  }
+
+  pub fn motd(&self) -> &MotdConfig {
+    &self.motd
+  }
+
+  /// Copy the settings that only take effect on restart from `running`, and
+  /// return the names of those that differ.
+  pub fn keep_restart_settings(&mut self, running: &ServerConfig) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    if self.bind != running.bind {
+      changed.push("bind");
+      self.bind = running.bind.clone();
+    }
+    if self.http_port != running.http_port {
+      changed.push("http_port");
+      self.http_port = running.http_port;
+    }
+    if self.gg_port != running.gg_port {
+      changed.push("gg_port");
+      self.gg_port = running.gg_port;
+    }
+    if self.gg_listeners != running.gg_listeners {
+      changed.push("gg_listeners");
+      self.gg_listeners = running.gg_listeners.clone();
+    }
+    if self.db != running.db {
+      changed.push("db");
+      self.db = running.db.clone();
+    }
+    changed
+  }
+}
+
+/// The config and everything built from it, swapped as a whole on reload.
+#[derive(Debug)]
+struct Settings {
+  config: Arc<ServerConfig>,
+  captcha: Arc<Captcha>,
+  mailer: Arc<Mailer>,
+  uin_allocator: Arc<UinAllocator>,
+}
+
+impl Settings {
+  fn new(config: ServerConfig) -> Result<Self, Box<dyn std::error::Error>> {
+    config.validate()?;
+    tracing::info!(public_address = ?config.public_address, advertised_gg_port = ?config.advertised_gg_port, "Advertised GG address");
+    let captcha = Captcha::new(config.captcha());
+    tracing::info!(mode = ?config.captcha().mode, "Captcha ready");
+    tracing::info!(policy = ?config.registration(), "Registration policy");
+    let mailer = Mailer::new(config.mail())?;
+    tracing::info!(mailer = ?mailer, "Mailer ready");
+    let uin_allocator = UinAllocator::new(config.uin())?;
+    tracing::info!(uin = ?config.uin(), "UIN allocator ready");
+    tracing::info!(session = ?config.session(), "Session settings");
+    tracing::info!(limits = ?config.limits(), "Connection limits");
+    tracing::info!(flood = ?config.flood(), "Flood limits");
+    tracing::info!(access = ?config.access(), "Access lists");
+    tracing::info!(enabled = config.motd().is_enabled(), revision = config.motd().revision, "Message of the day");
+
+    Ok(
+      Self {
+        config: Arc::new(config),
+        captcha: Arc::new(captcha),
+        mailer: Arc::new(mailer),
+        uin_allocator: Arc::new(uin_allocator),
+      }
+    )
+  }
 }
 
 /// Shared application state containing resources needed across the server.
@@ -50,19 +269,88 @@ impl ServerConfig {
 pub struct AppState {
   db_pool: DatabasePool,
   pub host_ip: IpAddr,
-  presence_hub: PresenceHub,
+  session_registry: Arc<SessionRegistry>,
+  connection_limits: Arc<ConnectionLimits>,
+  ip_bans: IpBans,
+  flood_guard: FloodGuard,
+  login_throttle: LoginThrottle,
+  presence_hub: Arc<PresenceHub>,
   message_dispatcher: MessageDispatcher,
-  config: ServerConfig
+  settings: RwLock<Settings>,
 }
 
 impl AppState {
+  pub fn new(config: ServerConfig, db_pool: DatabasePool, host_ip: IpAddr) -> Result<Self, Box<dyn std::error::Error>> {
+    let session_registry = Arc::new(SessionRegistry::new());
+    let presence_hub = Arc::new(PresenceHub::new(session_registry.clone()));
+    let filters = MessageFilters::from_config(config.filter());
+    let message_dispatcher = MessageDispatcher::new(&db_pool, session_registry.clone(), presence_hub.clone(), filters);
+    let settings = Settings::new(config)?;
+
+    Ok(
+      Self {
+        db_pool,
+        host_ip,
+        session_registry,
+        connection_limits: ConnectionLimits::new(),
+        ip_bans: IpBans::new(),
+        flood_guard: FloodGuard::new(),
+        login_throttle: LoginThrottle::new(),
+        presence_hub,
+        message_dispatcher,
+        settings: RwLock::new(settings),
+      }
+    )
+  }
+
+  /// Swap in a new config. It is validated first and nothing changes if
+  /// it's invalid. Settings that need a restart keep their running values,
+  /// their names are returned.
+  pub fn reload(&self, mut config: ServerConfig) -> Result<Vec<&'static str>, Box<dyn std::error::Error>> {
+    let restart_required = config.keep_restart_settings(&self.config());
+    let settings = Settings::new(config)?;
+    // New filters would forget the messages counted so far
+    if settings.config.filter() != self.config().filter() {
+      self.message_dispatcher.set_filters(MessageFilters::from_config(settings.config.filter()));
+    }
+    *self.settings.write() = settings;
+    Ok(restart_required)
+  }
+
   /// Get a reference to the database pool.
   pub fn db_pool(&self) -> &DatabasePool {
     &self.db_pool
   }
 
-  pub fn config(&self) -> &ServerConfig {
-    &self.config
+  /// The current config. Hold on to it only as long as needed, a reload
+  /// doesn't change a copy already taken.
+  pub fn config(&self) -> Arc<ServerConfig> {
+    self.settings.read().config.clone()
+  }
+
+  pub fn session_registry(&self) -> &SessionRegistry {
+    &self.session_registry
+  }
+
+  pub fn connection_limits(&self) -> &Arc<ConnectionLimits> {
+    &self.connection_limits
+  }
+
+  pub fn ip_bans(&self) -> &IpBans {
+    &self.ip_bans
+  }
+
+  /// Whether `ip` may connect, according to the access lists and bans.
+  pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+    self.config().access().is_allowed(ip) && !self.ip_bans.contains(ip)
+  }
+
+  pub fn flood_guard(&self) -> &FloodGuard {
+    &self.flood_guard
+  }
+
+  pub fn login_throttle(&self) -> &LoginThrottle {
+    &self.login_throttle
   }
 
   pub fn presence_hub(&self) -> &PresenceHub {
@@ -73,52 +361,178 @@ impl AppState {
     &self.message_dispatcher
   }
 
+  pub fn captcha(&self) -> Arc<Captcha> {
+    self.settings.read().captcha.clone()
+  }
+
+  pub fn mailer(&self) -> Arc<Mailer> {
+    self.settings.read().mailer.clone()
+  }
+
+  pub fn uin_allocator(&self) -> Arc<UinAllocator> {
+    self.settings.read().uin_allocator.clone()
+  }
+
   pub fn host_uri(&self, path: &str) -> Result<Uri, http::Error> {
-    Ok(Uri::builder()
+    Uri::builder()
         .scheme("http")
-        .authority(self.config.hostname())
+        .authority(self.config().hostname())
         .path_and_query(path)
-        .build()?)
+        .build()
   }
 }
 
 pub type SharedAppState = Arc<AppState>;
 
-pub async fn create_app_state() -> Result<SharedAppState, Box<dyn std::error::Error>> {
-  let config : ServerConfig = Figment::new()
+/// Load the config from `/etc/gg-retro/config.toml`, `./config.toml` and `GG_*` variables.
+pub fn load_config() -> Result<ServerConfig, Box<figment::Error>> {
+  let config = Figment::new()
     .merge(Toml::file("/etc/gg-retro/config.toml"))
     .merge(Toml::file("config.toml"))
     .merge(Env::prefixed("GG_"))
     .join(Serialized::defaults(ServerConfig::default()))
     .extract()?;
+  Ok(config)
+}
 
-  tracing::info!(db = config.db, "Starting GG server, preparing database...");
+/// Open the SQLite database, creating the file if needed. Migrations are not run.
+pub async fn open_database(config: &ServerConfig) -> Result<DatabasePool, sqlx::Error> {
   let db_options = SqliteConnectOptions::new()
-    .filename(config.db.clone())
+    .filename(config.db())
     .create_if_missing(true)
     .journal_mode(SqliteJournalMode::Wal);
 
-  let db_pool = SqlitePoolOptions::new()
+  SqlitePoolOptions::new()
     .max_connections(32)
     .connect_with(db_options)
-    .await?;
+    .await
+}
 
+/// Open the database and bring its schema up to date.
+pub async fn prepare_database(config: &ServerConfig) -> Result<DatabasePool, Box<dyn std::error::Error>> {
+  tracing::info!(db = config.db(), "Preparing database...");
+  let db_pool = open_database(config).await?;
   sqlx::migrate!("./migrations").run(&db_pool).await?;
   tracing::info!("Database ready");
+  Ok(db_pool)
+}
+
+pub async fn create_app_state() -> Result<SharedAppState, Box<dyn std::error::Error>> {
+  let config = load_config()?;
+
+  tracing::info!("Starting GG server");
+  let db_pool = prepare_database(&config).await?;
 
   let host_ip = local_ip()?;
-  let presence_hub = PresenceHub::new();
-  let message_dispatcher = MessageDispatcher::new(&db_pool);
 
-  Ok(
-    Arc::new(
-      AppState {
-        db_pool,
-        host_ip,
-        presence_hub,
-        message_dispatcher,
-        config
-      }
-    )
-  )
-}
\ No newline at end of file
+  let app_state = Arc::new(AppState::new(config, db_pool, host_ip)?);
+  let bans = app_state.ip_bans().load(app_state.db_pool()).await?;
+  tracing::info!(bans, "IP bans loaded");
+
+  Ok(app_state)
+}
+
+/// App state backed by an in-memory database, with `overrides` merged over the defaults.
+/// Run `poll` every `period`, the first time right away, until `shutdown` is cancelled.
+///
+/// Admin commands change the database from another process, this is how a
+/// running server picks up their changes.
+pub async fn poll_every<F, Fut>(period: Duration, shutdown: CancellationToken, mut poll: F)
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = ()>,
+{
+  let mut interval = tokio::time::interval(period);
+
+  loop {
+    tokio::select! {
+      _ = shutdown.cancelled() => break,
+      _ = interval.tick() => poll().await,
+    }
+  }
+}
+
+#[cfg(test)]
+pub async fn test_app_state(overrides: &str) -> SharedAppState {
+  let config: ServerConfig = Figment::new()
+    .merge(Toml::string(overrides))
+    .join(Serialized::defaults(ServerConfig::default()))
+    .extract()
+    .expect("invalid test config");
+
+  // No reaper: closing the last connection would drop the in-memory database
+  let db_pool = SqlitePoolOptions::new()
+    .idle_timeout(None)
+    .max_lifetime(None)
+    .connect("sqlite::memory:")
+    .await
+    .expect("failed to open database");
+  sqlx::migrate!("./migrations").run(&db_pool).await.expect("migrations failed");
+
+  Arc::new(AppState::new(config, db_pool, IpAddr::from([127, 0, 0, 1])).expect("invalid test app state"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gg_protocol::consts::{AckStatus, GGMessageClass};
+  use gg_protocol::packets::GGSendMessage;
+  use crate::models::UserRepository;
+
+  fn config(toml: &str) -> ServerConfig {
+    Figment::new()
+      .merge(Toml::string(toml))
+      .join(Serialized::defaults(ServerConfig::default()))
+      .extract()
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_reload_applies_safe_settings_only() {
+    let app_state = test_app_state("").await;
+    let config_before = app_state.config();
+
+    let restart_required = app_state.reload(config("hostname = \"gg.example.com\"\ngg_port = 443\nregistration = \"closed\"")).unwrap();
+
+    assert_eq!(restart_required, vec!["gg_port"]);
+    assert_eq!(app_state.config().hostname(), "gg.example.com");
+    assert_eq!(app_state.config().registration(), RegistrationPolicy::Closed);
+    assert_eq!(app_state.config().gg_bind(), "0.0.0.0:8074");
+    // A copy taken before the reload stays as it was
+    assert_eq!(config_before.hostname(), "gg-retro.local");
+  }
+
+  #[tokio::test]
+  async fn test_reload_keeps_filter_state_unless_filters_change() {
+    let app_state = test_app_state("filter.duplicate_limit = 1").await;
+    let users = UserRepository::new(app_state.db_pool());
+    users.create(1000, "ala", "ala@gg.pl", "tajne", true).await.unwrap();
+    users.create(2000, "ola", "ola@gg.pl", "tajne", true).await.unwrap();
+
+    let send = || async {
+      let message = GGSendMessage {
+        recipient: 2000,
+        seq: 1,
+        class: GGMessageClass::Chat,
+        message: "Kup teraz!".to_string(),
+        formatting: None,
+      };
+      app_state.message_dispatcher().dispatch(1000, message).await.unwrap()
+    };
+
+    assert_eq!(send().await, AckStatus::Queued);
+    app_state.reload(config("hostname = \"gg.example.com\"\nfilter.duplicate_limit = 1")).unwrap();
+    assert_eq!(send().await, AckStatus::NotDelivered);
+
+    app_state.reload(config("filter.duplicate_limit = 2")).unwrap();
+    assert_eq!(send().await, AckStatus::Queued);
+  }
+
+  #[tokio::test]
+  async fn test_invalid_reload_keeps_running_config() {
+    let app_state = test_app_state("").await;
+
+    assert!(app_state.reload(config("hostname = \"gg.example.com\"\nmail.from = \"nope\"")).is_err());
+    assert_eq!(app_state.config().hostname(), "gg-retro.local");
+  }
+}
@@ -0,0 +1,221 @@
+//! Outgoing email.
+//!
+//! Used for email verification. The transport is picked in the config:
+//!
+//! ```toml
+//! [mail]
+//! transport = "smtp"   # "log", "smtp" or "sendmail"
+//! from = "GG-Retro <gg-retro@example.com>"
+//! smtp_host = "smtp.example.com"
+//! smtp_port = 587
+//! smtp_security = "starttls"   # "none", "starttls" or "tls"
+//! smtp_username = "gg-retro"
+//! smtp_password = "secret"
+//! ```
+//!
+//! The default `log` transport only writes emails to the log, which is
+//! enough for LAN setups where the admin can pass links by hand.
+
+use std::fmt::{Debug, Formatter};
+use lettre::{AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::message::Mailbox;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// How emails leave the server.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+  /// Write emails to the log instead of sending them.
+  #[default]
+  Log,
+  /// Send through an SMTP server.
+  Smtp,
+  /// Pipe to the local `sendmail` binary.
+  Sendmail,
+}
+
+/// Connection security for the SMTP transport.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+  /// Plain text connection, only for trusted local relays.
+  None,
+  /// Upgrade the connection with STARTTLS (usually port 587).
+  #[default]
+  StartTls,
+  /// TLS from the start (usually port 465).
+  Tls,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MailConfig {
+  pub transport: MailTransport,
+  pub from: String,
+  pub smtp_host: String,
+  pub smtp_port: u16,
+  pub smtp_security: SmtpSecurity,
+  pub smtp_username: Option<String>,
+  pub smtp_password: Option<String>,
+}
+
+impl Default for MailConfig {
+  fn default() -> Self {
+    Self {
+      transport: MailTransport::default(),
+      from: "GG-Retro <gg-retro@gg-retro.local>".to_string(),
+      smtp_host: "localhost".to_string(),
+      smtp_port: 587,
+      smtp_security: SmtpSecurity::default(),
+      smtp_username: None,
+      smtp_password: None,
+    }
+  }
+}
+
+impl Debug for MailConfig {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("MailConfig")
+      .field("transport", &self.transport)
+      .field("from", &self.from)
+      .field("smtp_host", &self.smtp_host)
+      .field("smtp_port", &self.smtp_port)
+      .field("smtp_security", &self.smtp_security)
+      .field("smtp_username", &self.smtp_username)
+      .finish()
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum MailError {
+  #[error("Invalid email address: {0}")]
+  InvalidAddress(#[from] lettre::address::AddressError),
+  #[error("Failed to build email: {0}")]
+  Build(#[from] lettre::error::Error),
+  #[error("SMTP error: {0}")]
+  Smtp(#[from] lettre::transport::smtp::Error),
+  #[error("Sendmail error: {0}")]
+  Sendmail(#[from] lettre::transport::sendmail::Error),
+}
+
+/// A plain text email.
+#[derive(Debug, Clone)]
+pub struct Mail {
+  pub to: String,
+  pub subject: String,
+  pub body: String,
+}
+
+enum Transport {
+  Log,
+  Smtp(AsyncSmtpTransport<Tokio1Executor>),
+  Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+}
+
+/// Sends emails using the transport configured in `MailConfig`.
+pub struct Mailer {
+  from: Mailbox,
+  transport: Transport,
+}
+
+impl Debug for Mailer {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let transport = match self.transport {
+      Transport::Log => "log",
+      Transport::Smtp(_) => "smtp",
+      Transport::Sendmail(_) => "sendmail",
+    };
+
+    f.debug_struct("Mailer")
+      .field("from", &self.from.to_string())
+      .field("transport", &transport)
+      .finish()
+  }
+}
+
+impl Mailer {
+  pub fn new(config: &MailConfig) -> Result<Self, MailError> {
+    let from = config.from.parse::<Mailbox>()?;
+
+    let transport = match config.transport {
+      MailTransport::Log => Transport::Log,
+      MailTransport::Sendmail => Transport::Sendmail(AsyncSendmailTransport::new()),
+      MailTransport::Smtp => {
+        let mut builder = match config.smtp_security {
+          SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host),
+          SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?,
+          SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)?,
+        }.port(config.smtp_port);
+
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+          builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Transport::Smtp(builder.build())
+      }
+    };
+
+    Ok(Self { from, transport })
+  }
+
+  /// Send an email.
+  #[tracing::instrument(skip(self, mail), fields(to = %mail.to, subject = %mail.subject))]
+  pub async fn send(&self, mail: Mail) -> Result<(), MailError> {
+    let message = Message::builder()
+      .from(self.from.clone())
+      .to(mail.to.parse::<Mailbox>()?)
+      .subject(&mail.subject)
+      .header(ContentType::TEXT_PLAIN)
+      .body(mail.body.clone())?;
+
+    match &self.transport {
+      Transport::Log => {
+        tracing::info!(body = %mail.body, "Email not sent, mail transport is set to log");
+      },
+      Transport::Smtp(transport) => {
+        transport.send(message).await?;
+        tracing::info!("Email sent");
+      },
+      Transport::Sendmail(transport) => {
+        transport.send(message).await?;
+        tracing::info!("Email sent");
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn mail(to: &str) -> Mail {
+    Mail {
+      to: to.to_string(),
+      subject: "Test".to_string(),
+      body: "Hello".to_string(),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_log_transport_accepts_valid_address() {
+    let mailer = Mailer::new(&MailConfig::default()).unwrap();
+    assert!(mailer.send(mail("jan@gg.pl")).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_invalid_recipient_is_rejected() {
+    let mailer = Mailer::new(&MailConfig::default()).unwrap();
+    assert!(matches!(mailer.send(mail("not an email")).await, Err(MailError::InvalidAddress(_))));
+  }
+
+  #[test]
+  fn test_invalid_sender_is_rejected() {
+    let config = MailConfig { from: "nope".to_string(), ..Default::default() };
+    assert!(Mailer::new(&config).is_err());
+  }
+}
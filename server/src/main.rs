@@ -1,15 +1,45 @@
+mod access;
 mod api;
 mod banner;
+mod captcha;
+mod cli;
+mod mail;
 mod messenger;
 mod core;
 mod models;
+mod uin;
 
+use clap::Parser;
 use tokio::net::TcpListener;
+use tokio::time::Instant;
 use tokio::signal;
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let cli = cli::Cli::parse();
+
+  match cli.command {
+    None | Some(cli::Command::Serve) => serve().await,
+    Some(cli::Command::Admin(command)) => {
+      // Admin commands print results on stdout, keep the logs out of the way
+      let subscriber = tracing_subscriber::FmtSubscriber::builder()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .finish();
+      tracing::subscriber::set_global_default(subscriber)?;
+
+      if let Err(e) = cli::run(command).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+  }
+}
+
+async fn serve() -> Result<(), Box<dyn std::error::Error>> {
   banner::print_banner();
 
   let subscriber = tracing_subscriber::FmtSubscriber::builder()
@@ -20,10 +50,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
   // Create a shutdown token for graceful shutdown coordination
   let shutdown = CancellationToken::new();
 
-  let gg_listener = TcpListener::bind(app_state.config().gg_bind()).await?;
+  let mut gg_listeners = Vec::new();
+  for bind in app_state.config().gg_binds() {
+    gg_listeners.push(TcpListener::bind(&bind).await?);
+    tracing::info!("GG messenger listening on {}", bind);
+  }
   let web_listener = TcpListener::bind(app_state.config().api_bind()).await?;
 
-  tracing::info!("GG messenger listening on {}", app_state.config().gg_bind());
   tracing::info!("Web API listening on http://{}", app_state.config().api_bind());
 
   // Clone tokens for each task
@@ -32,9 +65,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   let messenger_task_app_state = app_state.clone();
   let mut messenger_task = tokio::spawn(async move {
-    messenger::gg_server(gg_listener, messenger_shutdown, messenger_task_app_state).await
+    messenger::gg_server(gg_listeners, messenger_shutdown, messenger_task_app_state).await
   });
 
+  #[cfg(unix)]
+  tokio::spawn(reload_on_sighup(app_state.clone()));
+
+  // Pollers use the database, so they are awaited before it's closed
+  let pollers = TaskTracker::new();
+  pollers.spawn(messenger::system_message_broadcaster(shutdown.clone(), app_state.clone()));
+  pollers.spawn(access::ban_refresher(shutdown.clone(), app_state.clone()));
+  pollers.spawn(messenger::moderation_enforcer(shutdown.clone(), app_state.clone()));
+  pollers.close();
+
   let api_task_app_state = app_state.clone();
   let mut api_task = tokio::spawn(async move {
     api::http_server(web_listener, api_shutdown, api_task_app_state).await
@@ -42,8 +85,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   // Wait for shutdown signal or task failure
   tokio::select! {
-    _ = signal::ctrl_c() => {
-      tracing::info!("Received Ctrl+C, initiating graceful shutdown...");
+    signal = shutdown_signal() => {
+      tracing::info!("Received {}, initiating graceful shutdown...", signal);
     }
     result = &mut messenger_task => {
       tracing::error!("GG server exited unexpectedly: {:?}", result);
@@ -53,10 +96,73 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
   }
 
-  // Signal all tasks to shut down
+  // Stop accepting connections and let the sessions drain
+  let started = Instant::now();
   shutdown.cancel();
-  tracing::info!("Shutdown complete");
-  let _ = messenger_task.await;
-  let _ = api_task.await;
+  for task in [messenger_task, api_task] {
+    if !task.is_finished() {
+      let _ = task.await;
+    }
+  }
+  pollers.wait().await;
+
+  let still_online = app_state.session_registry().online();
+  app_state.db_pool().close().await;
+  tracing::info!(took = ?started.elapsed(), still_online, "Shutdown complete");
   Ok(())
 }
+
+/// Re-read the config on every SIGHUP, e.g. from `systemctl reload gg-retro`.
+#[cfg(unix)]
+async fn reload_on_sighup(app_state: core::SharedAppState) {
+  let mut sighup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+    Ok(sighup) => sighup,
+    Err(e) => {
+      tracing::error!("Failed to listen for SIGHUP, config reload disabled: {}", e);
+      return;
+    }
+  };
+
+  while sighup.recv().await.is_some() {
+    tracing::info!("Received SIGHUP, reloading config...");
+    let config = match core::load_config() {
+      Ok(config) => config,
+      Err(e) => {
+        tracing::error!("Config not reloaded, failed to read it: {}", e);
+        continue;
+      }
+    };
+
+    match app_state.reload(config) {
+      Ok(restart_required) if restart_required.is_empty() => tracing::info!("Config reloaded"),
+      Ok(restart_required) => tracing::warn!(settings = ?restart_required, "Config reloaded, but changes to these settings need a restart"),
+      Err(e) => tracing::error!("Config not reloaded, keeping the current one: {}", e),
+    }
+  }
+}
+
+/// Wait for Ctrl+C, or SIGTERM from systemd.
+async fn shutdown_signal() -> &'static str {
+  #[cfg(unix)]
+  {
+    let mut sigterm = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+      Ok(sigterm) => sigterm,
+      Err(e) => {
+        tracing::error!("Failed to listen for SIGTERM: {}", e);
+        let _ = signal::ctrl_c().await;
+        return "Ctrl+C";
+      }
+    };
+
+    tokio::select! {
+      _ = signal::ctrl_c() => "Ctrl+C",
+      _ = sigterm.recv() => "SIGTERM",
+    }
+  }
+
+  #[cfg(not(unix))]
+  {
+    let _ = signal::ctrl_c().await;
+    "Ctrl+C"
+  }
+}
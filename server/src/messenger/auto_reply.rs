@@ -0,0 +1,62 @@
+//! How often auto-replies go out.
+//!
+//! A user's auto-reply answers each sender at most once per
+//! `AUTO_REPLY_INTERVAL`, however many messages they send. Only replies
+//! actually sent count, so a sender writing while the user is online gets
+//! the reply as soon as the user goes away.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use gg_protocol::GGNumber;
+
+pub const AUTO_REPLY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// When each user last auto-replied to each sender.
+#[derive(Debug, Default)]
+pub struct AutoReplyLimiter {
+  sent: Mutex<HashMap<(GGNumber, GGNumber), Instant>>,
+}
+
+impl AutoReplyLimiter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Check if `owner` may auto-reply to `sender` now, and if so count the reply.
+  pub fn try_reply(&self, owner: GGNumber, sender: GGNumber) -> bool {
+    let now = Instant::now();
+    let mut sent = self.sent.lock();
+    sent.retain(|_, last| now.duration_since(*last) < AUTO_REPLY_INTERVAL);
+
+    if sent.contains_key(&(owner, sender)) {
+      return false;
+    }
+    sent.insert((owner, sender), now);
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test(start_paused = true)]
+  async fn test_once_per_sender_per_hour() {
+    let limiter = AutoReplyLimiter::new();
+
+    assert!(limiter.try_reply(1000, 2000));
+    assert!(!limiter.try_reply(1000, 2000));
+    // Other senders and other owners are counted separately
+    assert!(limiter.try_reply(1000, 3000));
+    assert!(limiter.try_reply(3000, 2000));
+
+    tokio::time::advance(AUTO_REPLY_INTERVAL - Duration::from_secs(1)).await;
+    assert!(!limiter.try_reply(1000, 2000));
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(limiter.try_reply(1000, 2000));
+    assert_eq!(limiter.sent.lock().len(), 1);
+  }
+}
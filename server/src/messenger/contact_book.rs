@@ -17,7 +17,7 @@ impl ContactBook {
   }
 
   pub fn is_blocked(&self, contact_uin: GGNumber) -> bool {
-    return self.blocked.contains(&contact_uin);
+    self.blocked.contains(&contact_uin)
   }
 
   pub fn set(&mut self, contacts: &Vec<ContactEntry>) {
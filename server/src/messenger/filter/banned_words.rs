@@ -0,0 +1,111 @@
+//! Masks or rejects messages with words from a configured list.
+
+use std::collections::HashSet;
+use gg_protocol::GGNumber;
+use gg_protocol::consts::AckStatus;
+use gg_protocol::packets::GGSendMessage;
+use crate::messenger::filter::{BannedWordsAction, FilterAction, MessageFilter, TextEdit};
+
+const MASK: &str = "***";
+
+/// Whole words are compared case insensitively, "spam" doesn't match "spamer".
+#[derive(Debug)]
+pub struct BannedWords {
+  words: HashSet<String>,
+  action: BannedWordsAction,
+}
+
+impl BannedWords {
+  pub fn new(words: &[String], action: BannedWordsAction) -> Self {
+    Self {
+      words: words.iter().map(|word| word.to_lowercase()).collect(),
+      action,
+    }
+  }
+
+  /// Character ranges of the banned words in `text`.
+  fn find(&self, text: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut word = String::new();
+    let mut start = 0;
+
+    for (position, c) in text.chars().chain(std::iter::once(' ')).enumerate() {
+      if c.is_alphanumeric() {
+        if word.is_empty() {
+          start = position;
+        }
+        word.extend(c.to_lowercase());
+      } else if !word.is_empty() {
+        if self.words.contains(&word) {
+          found.push((start, position));
+        }
+        word.clear();
+      }
+    }
+    found
+  }
+}
+
+impl MessageFilter for BannedWords {
+  fn name(&self) -> &'static str {
+    "banned_words"
+  }
+
+  fn check(&self, _sender: GGNumber, _recipient: GGNumber, message: &GGSendMessage) -> FilterAction {
+    let found = self.find(&message.message);
+    if found.is_empty() {
+      return FilterAction::Allow;
+    }
+
+    match self.action {
+      BannedWordsAction::Reject => FilterAction::Reject(AckStatus::NotDelivered),
+      BannedWordsAction::Mask => FilterAction::Rewrite(
+        found.into_iter()
+          .map(|(start, end)| TextEdit { start, end, replacement: MASK.to_string() })
+          .collect()
+      ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gg_protocol::packets::RichTextFormat;
+  use crate::messenger::filter::apply_edits;
+  use crate::messenger::filter::tests::message;
+
+  fn filter(action: BannedWordsAction) -> BannedWords {
+    BannedWords::new(&["spam".to_string(), "Żółw".to_string()], action)
+  }
+
+  #[test]
+  fn test_whole_words_case_insensitive() {
+    let filter = filter(BannedWordsAction::Mask);
+
+    assert_eq!(filter.find("SPAM, spamer i żÓŁW!"), vec![(0, 4), (15, 19)]);
+    assert!(filter.find("spamer").is_empty());
+    assert!(filter.find("").is_empty());
+  }
+
+  #[test]
+  fn test_mask_keeps_formatting() {
+    let filter = filter(BannedWordsAction::Mask);
+    let mut msg = message("kup spam teraz", Some(vec![RichTextFormat::new(0), RichTextFormat::bold(9)]));
+
+    let FilterAction::Rewrite(edits) = filter.check(1000, 2000, &msg) else {
+      panic!("expected rewrite");
+    };
+    apply_edits(&mut msg, edits);
+    assert_eq!(msg.message, "kup *** teraz");
+    assert_eq!(msg.formatting.unwrap()[1], RichTextFormat::bold(8));
+  }
+
+  #[test]
+  fn test_reject() {
+    let filter = filter(BannedWordsAction::Reject);
+
+    assert_eq!(filter.check(1000, 2000, &message("spam", None)), FilterAction::Reject(AckStatus::NotDelivered));
+    assert_eq!(filter.check(1000, 2000, &message("hej", None)), FilterAction::Allow);
+  }
+}
@@ -0,0 +1,91 @@
+//! Rejects the same text sent over and over, e.g. to everyone on a list.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use gg_protocol::GGNumber;
+use gg_protocol::consts::AckStatus;
+use gg_protocol::packets::GGSendMessage;
+use crate::messenger::filter::{FilterAction, MessageFilter};
+
+/// Messages remembered per sender, older ones are forgotten first.
+const HISTORY: usize = 256;
+
+/// A sender may send the same text `limit` times within `window`, to any
+/// recipients. Case and surrounding whitespace don't make a text different.
+#[derive(Debug)]
+pub struct DuplicateSpam {
+  limit: usize,
+  window: Duration,
+  sent: Mutex<HashMap<GGNumber, VecDeque<(u64, Instant)>>>,
+}
+
+impl DuplicateSpam {
+  pub fn new(limit: usize, window: Duration) -> Self {
+    Self { limit, window, sent: Mutex::new(HashMap::new()) }
+  }
+
+  fn fingerprint(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.trim().to_lowercase().hash(&mut hasher);
+    hasher.finish()
+  }
+}
+
+impl MessageFilter for DuplicateSpam {
+  fn name(&self) -> &'static str {
+    "duplicates"
+  }
+
+  fn check(&self, sender: GGNumber, _recipient: GGNumber, message: &GGSendMessage) -> FilterAction {
+    let now = Instant::now();
+    let fingerprint = DuplicateSpam::fingerprint(&message.message);
+
+    let mut sent = self.sent.lock();
+    if !sent.contains_key(&sender) {
+      // Drop senders that went quiet, so the map doesn't keep everyone forever
+      sent.retain(|_, history| history.back().is_some_and(|(_, at)| now.duration_since(*at) < self.window));
+    }
+
+    let history = sent.entry(sender).or_default();
+    while history.front().is_some_and(|(_, at)| now.duration_since(*at) >= self.window) {
+      history.pop_front();
+    }
+
+    let repeats = history.iter().filter(|(sent, _)| *sent == fingerprint).count();
+    if repeats >= self.limit {
+      return FilterAction::Reject(AckStatus::NotDelivered);
+    }
+
+    if history.len() == HISTORY {
+      history.pop_front();
+    }
+    history.push_back((fingerprint, now));
+    FilterAction::Allow
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::messenger::filter::tests::message;
+
+  #[tokio::test(start_paused = true)]
+  async fn test_same_text_over_the_limit() {
+    let filter = DuplicateSpam::new(2, Duration::from_secs(60));
+    let spam = message("Tanie kredyty!", None);
+
+    assert_eq!(filter.check(1000, 2000, &spam), FilterAction::Allow);
+    assert_eq!(filter.check(1000, 3000, &message("  TANIE kredyty! ", None)), FilterAction::Allow);
+    assert_eq!(filter.check(1000, 4000, &spam), FilterAction::Reject(AckStatus::NotDelivered));
+    // Other texts and other senders are fine
+    assert_eq!(filter.check(1000, 4000, &message("Hej", None)), FilterAction::Allow);
+    assert_eq!(filter.check(5000, 4000, &spam), FilterAction::Allow);
+
+    tokio::time::advance(Duration::from_secs(60)).await;
+    assert_eq!(filter.check(1000, 4000, &spam), FilterAction::Allow);
+  }
+}
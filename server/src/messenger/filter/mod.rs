@@ -0,0 +1,356 @@
+//! Filters every message goes through before it is stored or delivered.
+//!
+//! ```toml
+//! [filter]
+//! banned_words = ["spam"]         # whole words, case insensitive
+//! banned_words_action = "mask"    # "mask" replaces them with ***, "reject" drops the message
+//! block_links = false             # drop messages with links
+//! allowed_link_domains = []       # still allowed with block_links, subdomains included
+//! duplicate_limit = 0             # the same text sent more often within the window is spam, 0 disables
+//! duplicate_window_secs = 60
+//! ```
+//!
+//! A filter may let a message through, rewrite parts of its text or reject
+//! it with the ack status the sender gets. Rewrites are applied by the
+//! pipeline, which keeps the rich text formatting in place.
+
+mod banned_words;
+mod duplicates;
+mod links;
+
+use std::fmt::Debug;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use gg_protocol::GGNumber;
+use gg_protocol::consts::{AckStatus, GGMessageClass};
+use gg_protocol::packets::{GGSendMessage, RichTextFormat};
+
+pub use banned_words::BannedWords;
+pub use duplicates::DuplicateSpam;
+pub use links::LinkBlocker;
+
+/// What to do with a message containing a banned word.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BannedWordsAction {
+  /// Replace the words with `***`.
+  #[default]
+  Mask,
+  /// Don't deliver the message.
+  Reject,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct FilterConfig {
+  pub banned_words: Vec<String>,
+  pub banned_words_action: BannedWordsAction,
+  pub block_links: bool,
+  pub allowed_link_domains: Vec<String>,
+  pub duplicate_limit: usize,
+  pub duplicate_window_secs: u64,
+}
+
+impl Default for FilterConfig {
+  fn default() -> Self {
+    Self {
+      banned_words: Vec::new(),
+      banned_words_action: BannedWordsAction::default(),
+      block_links: false,
+      allowed_link_domains: Vec::new(),
+      duplicate_limit: 0,
+      duplicate_window_secs: 60,
+    }
+  }
+}
+
+impl FilterConfig {
+  pub fn duplicate_window(&self) -> Duration {
+    Duration::from_secs(self.duplicate_window_secs)
+  }
+}
+
+/// Replace the characters `start..end` of a message with `replacement`.
+/// Positions count characters, the same way rich text formatting does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+  pub start: usize,
+  pub end: usize,
+  pub replacement: String,
+}
+
+/// What a filter decided about a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterAction {
+  Allow,
+  /// Deliver the message with these parts of the text replaced.
+  Rewrite(Vec<TextEdit>),
+  /// Don't deliver the message, the sender gets this status.
+  Reject(AckStatus),
+}
+
+/// A single step of the pipeline.
+pub trait MessageFilter: Send + Sync + Debug {
+  /// Name used in logs.
+  fn name(&self) -> &'static str;
+
+  fn check(&self, sender: GGNumber, recipient: GGNumber, message: &GGSendMessage) -> FilterAction;
+}
+
+/// A message rejected by a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejected {
+  pub filter: &'static str,
+  pub status: AckStatus,
+}
+
+/// Filters run in order, each sees the message as rewritten by the ones before.
+#[derive(Debug, Default)]
+pub struct MessageFilters {
+  filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl MessageFilters {
+  pub fn new(filters: Vec<Box<dyn MessageFilter>>) -> Self {
+    Self { filters }
+  }
+
+  /// The built-in filters enabled in the config.
+  pub fn from_config(config: &FilterConfig) -> Self {
+    let mut filters: Vec<Box<dyn MessageFilter>> = Vec::new();
+    if config.block_links {
+      filters.push(Box::new(LinkBlocker::new(&config.allowed_link_domains)));
+    }
+    if config.duplicate_limit > 0 {
+      filters.push(Box::new(DuplicateSpam::new(config.duplicate_limit, config.duplicate_window())));
+    }
+    if !config.banned_words.is_empty() {
+      filters.push(Box::new(BannedWords::new(&config.banned_words, config.banned_words_action)));
+    }
+    let filters = Self::new(filters);
+    tracing::info!(filters = ?filters.names(), "Message filters ready");
+    filters
+  }
+
+  pub fn names(&self) -> Vec<&'static str> {
+    self.filters.iter().map(|filter| filter.name()).collect()
+  }
+
+  /// Run a message from `sender` through every filter.
+  pub fn apply(&self, sender: GGNumber, mut message: GGSendMessage) -> Result<GGSendMessage, Rejected> {
+    // CTCP messages only ask for a direct connection, there is no text to filter
+    if message.class == GGMessageClass::Ctcp {
+      return Ok(message)
+    }
+    for filter in &self.filters {
+      match filter.check(sender, message.recipient, &message) {
+        FilterAction::Allow => {},
+        FilterAction::Rewrite(edits) => {
+          tracing::info!(filter = filter.name(), sender, edits = edits.len(), "message rewritten");
+          apply_edits(&mut message, edits);
+        },
+        FilterAction::Reject(status) => {
+          return Err(Rejected { filter: filter.name(), status });
+        },
+      }
+    }
+    Ok(message)
+  }
+}
+
+/// Apply edits to the text and move the formatting along. Overlapping
+/// edits are skipped. Formatting that started inside replaced text starts
+/// at the same offset of the replacement, or right after it if that is
+/// shorter, and formatting past the new end of the text is dropped.
+pub fn apply_edits(message: &mut GGSendMessage, mut edits: Vec<TextEdit>) {
+  edits.sort_by_key(|edit| (edit.start, edit.end));
+  let chars = message.message.chars().collect::<Vec<_>>();
+
+  let mut text = String::with_capacity(message.message.len());
+  let mut applied = Vec::with_capacity(edits.len());
+  let mut cursor = 0;
+  for edit in edits {
+    if edit.start < cursor || edit.start > edit.end || edit.end > chars.len() {
+      continue;
+    }
+    text.extend(&chars[cursor..edit.start]);
+    text.push_str(&edit.replacement);
+    cursor = edit.end;
+    applied.push(edit);
+  }
+  text.extend(&chars[cursor..]);
+  message.message = text;
+
+  let Some(formats) = message.formatting.take() else {
+    return;
+  };
+  let length = message.message.chars().count();
+  let mut moved: Vec<RichTextFormat> = Vec::with_capacity(formats.len());
+  for mut format in formats {
+    let position = shift_position(format.position as usize, &applied);
+    if position >= length {
+      continue;
+    }
+    format.position = position as u16;
+    // A later entry at the same position wins
+    if moved.last().is_some_and(|last: &RichTextFormat| last.position == format.position) {
+      moved.pop();
+    }
+    moved.push(format);
+  }
+  message.formatting = Some(moved).filter(|formats| !formats.is_empty());
+}
+
+/// Where a position of the original text ends up after `edits`.
+fn shift_position(position: usize, edits: &[TextEdit]) -> usize {
+  let mut shift = 0isize;
+  for edit in edits {
+    let replaced = edit.end - edit.start;
+    let inserted = edit.replacement.chars().count();
+    if position >= edit.end {
+      shift += inserted as isize - replaced as isize;
+    } else if position > edit.start {
+      let start = (edit.start as isize + shift) as usize;
+      return start + (position - edit.start).min(inserted);
+    } else {
+      break;
+    }
+  }
+  (position as isize + shift) as usize
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  pub(super) fn message(text: &str, formatting: Option<Vec<RichTextFormat>>) -> GGSendMessage {
+    GGSendMessage {
+      recipient: 2000,
+      seq: 1,
+      class: GGMessageClass::Chat,
+      message: text.to_string(),
+      formatting,
+    }
+  }
+
+  fn edit(start: usize, end: usize, replacement: &str) -> TextEdit {
+    TextEdit { start, end, replacement: replacement.to_string() }
+  }
+
+  fn positions(message: &GGSendMessage) -> Vec<u16> {
+    message.formatting.iter().flatten().map(|f| f.position).collect()
+  }
+
+  #[test]
+  fn test_edits_move_formatting() {
+    // "Zażółć [gęślą] jaźń", bold from "jaźń"
+    let mut msg = message("Zażółć gęślą jaźń", Some(vec![RichTextFormat::new(0), RichTextFormat::bold(13)]));
+    apply_edits(&mut msg, vec![edit(7, 12, "***")]);
+    assert_eq!(msg.message, "Zażółć *** jaźń");
+    assert_eq!(positions(&msg), vec![0, 11]);
+
+    // Longer replacement moves it the other way
+    let mut msg = message("ab cd", Some(vec![RichTextFormat::bold(3)]));
+    apply_edits(&mut msg, vec![edit(0, 2, "[usunięte]")]);
+    assert_eq!(msg.message, "[usunięte] cd");
+    assert_eq!(positions(&msg), vec![11]);
+  }
+
+  #[test]
+  fn test_formatting_inside_replaced_text() {
+    let mut msg = message("aaXYZbb", Some(vec![RichTextFormat::new(0), RichTextFormat::bold(3), RichTextFormat::new(5)]));
+    apply_edits(&mut msg, vec![edit(2, 5, "*")]);
+    assert_eq!(msg.message, "aa*bb");
+    // Bold started on "Y", past the end of "*", so it collapses onto the next entry
+    assert_eq!(positions(&msg), vec![0, 3]);
+    assert!(!msg.formatting.as_ref().unwrap()[1].bold);
+  }
+
+  #[test]
+  fn test_formatting_past_the_end_is_dropped() {
+    let mut msg = message("hej spam", Some(vec![RichTextFormat::bold(0), RichTextFormat::italic(4)]));
+    apply_edits(&mut msg, vec![edit(3, 8, "")]);
+    assert_eq!(msg.message, "hej");
+    assert_eq!(positions(&msg), vec![0]);
+
+    let mut msg = message("spam", Some(vec![RichTextFormat::bold(0)]));
+    apply_edits(&mut msg, vec![edit(0, 4, "")]);
+    assert_eq!(msg.message, "");
+    assert_eq!(msg.formatting, None);
+  }
+
+  #[test]
+  fn test_several_and_overlapping_edits() {
+    let mut msg = message("a bb c dd e", Some(vec![RichTextFormat::bold(10)]));
+    apply_edits(&mut msg, vec![edit(7, 9, "*"), edit(2, 4, "*"), edit(3, 5, "!"), edit(6, 20, "?")]);
+    assert_eq!(msg.message, "a * c * e");
+    assert_eq!(positions(&msg), vec![8]);
+  }
+
+  #[test]
+  fn test_insertion_at_a_format_boundary() {
+    let mut msg = message("ab", Some(vec![RichTextFormat::new(0), RichTextFormat::bold(1)]));
+    apply_edits(&mut msg, vec![edit(1, 1, "--")]);
+    assert_eq!(msg.message, "a--b");
+    assert_eq!(positions(&msg), vec![0, 3]);
+  }
+
+  #[derive(Debug)]
+  struct Fixed(FilterAction);
+
+  impl MessageFilter for Fixed {
+    fn name(&self) -> &'static str {
+      "fixed"
+    }
+
+    fn check(&self, _sender: GGNumber, _recipient: GGNumber, _message: &GGSendMessage) -> FilterAction {
+      self.0.clone()
+    }
+  }
+
+  #[test]
+  fn test_pipeline_runs_in_order() {
+    let filters = MessageFilters::new(vec![
+      Box::new(Fixed(FilterAction::Rewrite(vec![edit(0, 3, "Cześć")]))),
+      Box::new(Fixed(FilterAction::Allow)),
+    ]);
+    assert_eq!(filters.apply(1000, message("Hej!", None)).unwrap().message, "Cześć!");
+
+    let filters = MessageFilters::new(vec![
+      Box::new(Fixed(FilterAction::Reject(AckStatus::Blocked))),
+      Box::new(Fixed(FilterAction::Rewrite(vec![edit(0, 3, "Cześć")]))),
+    ]);
+    assert_eq!(filters.apply(1000, message("Hej!", None)), Err(Rejected { filter: "fixed", status: AckStatus::Blocked }));
+  }
+
+  #[test]
+  fn test_ctcp_messages_skip_filters() {
+    let ctcp = || GGSendMessage { class: GGMessageClass::Ctcp, ..message("\u{2}", None) };
+
+    let filters = MessageFilters::new(vec![Box::new(Fixed(FilterAction::Reject(AckStatus::Blocked)))]);
+    assert!(filters.apply(1000, ctcp()).is_ok());
+
+    // Connection requests don't count as repeated text either
+    let filters = MessageFilters::new(vec![Box::new(DuplicateSpam::new(1, Duration::from_secs(60)))]);
+    assert!(filters.apply(1000, ctcp()).is_ok());
+    assert!(filters.apply(1000, ctcp()).is_ok());
+    assert!(filters.apply(1000, message("\u{2}", None)).is_ok());
+    assert!(filters.apply(1000, message("\u{2}", None)).is_err());
+  }
+
+  #[test]
+  fn test_config_enables_builtins() {
+    assert!(MessageFilters::from_config(&FilterConfig::default()).names().is_empty());
+
+    let config = FilterConfig {
+      banned_words: vec!["spam".to_string()],
+      block_links: true,
+      duplicate_limit: 3,
+      ..FilterConfig::default()
+    };
+    let filters = MessageFilters::from_config(&config);
+    assert_eq!(filters.names(), vec!["links", "duplicates", "banned_words"]);
+    assert_eq!(filters.apply(1000, message("to jest SPAM", None)).unwrap().message, "to jest ***");
+    assert_eq!(filters.apply(1000, message("www.gg.pl", None)).unwrap_err().filter, "links");
+  }
+}
@@ -0,0 +1,305 @@
+//! Message rate limits per sender.
+//!
+//! ```toml
+//! [flood]
+//! messages_per_second = 5        # messages sent
+//! recipients_per_minute = 30     # different people messaged
+//! bytes_per_minute = 20000       # message text
+//! offender_violations = 20       # limited messages within a minute that make a repeat offender
+//! offender_action = "none"       # "none", "disconnect" or "mute"
+//! offender_mute_minutes = 60
+//! ```
+//!
+//! Setting a limit to 0 disables it. Every limit is a token bucket that
+//! refills continuously, so a sender may burst up to the whole limit at once.
+//! Buckets are kept per UIN and shared by all of its sessions, messages over
+//! a limit are not stored and the sender gets `NotDelivered`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use gg_protocol::GGNumber;
+
+const SECOND: Duration = Duration::from_secs(1);
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// What happens to a sender that keeps running into the limits.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OffenderAction {
+  /// Only the messages over the limits are dropped.
+  #[default]
+  None,
+  /// The session is closed.
+  Disconnect,
+  /// The account is muted for `offender_mute_minutes`.
+  Mute,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct FloodConfig {
+  pub messages_per_second: u32,
+  pub recipients_per_minute: u32,
+  pub bytes_per_minute: u32,
+  pub offender_violations: u32,
+  pub offender_action: OffenderAction,
+  pub offender_mute_minutes: u64,
+}
+
+impl Default for FloodConfig {
+  fn default() -> Self {
+    Self {
+      messages_per_second: 5,
+      recipients_per_minute: 30,
+      bytes_per_minute: 20000,
+      offender_violations: 20,
+      offender_action: OffenderAction::default(),
+      offender_mute_minutes: 60,
+    }
+  }
+}
+
+impl FloodConfig {
+  pub fn offender_mute(&self) -> Duration {
+    Duration::from_secs(self.offender_mute_minutes * 60)
+  }
+}
+
+/// Outcome of checking a message against the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+  Allowed,
+  /// Over a limit, the message is dropped.
+  Limited,
+  /// Over a limit once too often, the message is dropped and
+  /// `offender_action` applies.
+  Offender,
+}
+
+/// Tokens left of a limit. The limit itself comes from the config on every
+/// check, so a reload applies right away.
+#[derive(Debug)]
+struct TokenBucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+impl TokenBucket {
+  fn full(now: Instant) -> Self {
+    Self { tokens: f64::MAX, updated: now }
+  }
+
+  /// Add the tokens earned since the last refill, `limit` per `period`.
+  /// A disabled limit keeps the bucket full, so enabling it by a reload
+  /// doesn't lock out senders that were busy meanwhile.
+  fn refill(&mut self, limit: u32, period: Duration, now: Instant) {
+    if limit == 0 {
+      *self = Self::full(now);
+      return
+    }
+
+    let earned = now.duration_since(self.updated).as_secs_f64() * limit as f64 / period.as_secs_f64();
+    self.tokens = (self.tokens + earned).min(limit as f64);
+    self.updated = now;
+  }
+
+  fn has(&self, amount: f64, limit: u32) -> bool {
+    limit == 0 || self.tokens >= amount
+  }
+
+  fn take(&mut self, amount: f64) {
+    self.tokens = (self.tokens - amount).max(0.0);
+  }
+}
+
+#[derive(Debug)]
+struct SenderState {
+  messages: TokenBucket,
+  recipients: TokenBucket,
+  bytes: TokenBucket,
+  /// Recipients messaged within the last minute, with the time of the last message.
+  recent_recipients: HashMap<GGNumber, Instant>,
+  violations: u32,
+  violations_since: Instant,
+}
+
+impl SenderState {
+  fn new(now: Instant) -> Self {
+    Self {
+      messages: TokenBucket::full(now),
+      recipients: TokenBucket::full(now),
+      bytes: TokenBucket::full(now),
+      recent_recipients: HashMap::new(),
+      violations: 0,
+      violations_since: now,
+    }
+  }
+
+  /// Count a violation. Returns true once there were `offender_violations`
+  /// within a minute, the count starts over after that.
+  fn violation(&mut self, offender_violations: u32, now: Instant) -> bool {
+    if now.duration_since(self.violations_since) >= MINUTE {
+      self.violations = 0;
+      self.violations_since = now;
+    }
+    self.violations += 1;
+
+    let offender = offender_violations > 0 && self.violations >= offender_violations;
+    if offender {
+      self.violations = 0;
+      self.violations_since = now;
+    }
+    offender
+  }
+}
+
+/// Rate limit state of every sender.
+#[derive(Debug, Default)]
+pub struct FloodGuard {
+  senders: Mutex<HashMap<GGNumber, SenderState>>,
+}
+
+impl FloodGuard {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Check a message of `bytes` from `sender` to `recipient`. Allowed
+  /// messages use up their share of every limit, the others use up nothing.
+  pub fn check(&self, sender: GGNumber, recipient: GGNumber, bytes: usize, config: &FloodConfig) -> Verdict {
+    let now = Instant::now();
+    let mut senders = self.senders.lock();
+    let state = senders.entry(sender).or_insert_with(|| SenderState::new(now));
+
+    state.recent_recipients.retain(|_, last| now.duration_since(*last) < MINUTE);
+    state.messages.refill(config.messages_per_second, SECOND, now);
+    state.recipients.refill(config.recipients_per_minute, MINUTE, now);
+    state.bytes.refill(config.bytes_per_minute, MINUTE, now);
+
+    let new_recipients = if state.recent_recipients.contains_key(&recipient) { 0.0 } else { 1.0 };
+    let bytes = bytes as f64;
+    let allowed = state.messages.has(1.0, config.messages_per_second)
+      && state.recipients.has(new_recipients, config.recipients_per_minute)
+      && state.bytes.has(bytes, config.bytes_per_minute);
+
+    if allowed {
+      state.messages.take(1.0);
+      state.recipients.take(new_recipients);
+      state.bytes.take(bytes);
+      state.recent_recipients.insert(recipient, now);
+      Verdict::Allowed
+    } else if state.violation(config.offender_violations, now) {
+      Verdict::Offender
+    } else {
+      Verdict::Limited
+    }
+  }
+
+  /// Drop the state of a sender, e.g. once its last session is gone.
+  pub fn forget(&self, sender: GGNumber) {
+    self.senders.lock().remove(&sender);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(overrides: impl FnOnce(&mut FloodConfig)) -> FloodConfig {
+    let mut config = FloodConfig {
+      messages_per_second: 0,
+      recipients_per_minute: 0,
+      bytes_per_minute: 0,
+      offender_violations: 0,
+      ..FloodConfig::default()
+    };
+    overrides(&mut config);
+    config
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_messages_per_second_refill() {
+    let guard = FloodGuard::new();
+    let config = config(|c| c.messages_per_second = 2);
+
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Limited);
+    // Other senders have their own buckets
+    assert_eq!(guard.check(3000, 2000, 3, &config), Verdict::Allowed);
+
+    tokio::time::advance(Duration::from_millis(500)).await;
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Limited);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_only_new_recipients_count() {
+    let guard = FloodGuard::new();
+    let config = config(|c| c.recipients_per_minute = 2);
+
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 3000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 4000, 3, &config), Verdict::Limited);
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(guard.check(1000, 4000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 5000, 3, &config), Verdict::Limited);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_limited_messages_use_up_nothing() {
+    let guard = FloodGuard::new();
+    let config = config(|c| c.bytes_per_minute = 100);
+
+    assert_eq!(guard.check(1000, 2000, 60, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 60, &config), Verdict::Limited);
+    assert_eq!(guard.check(1000, 2000, 40, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 1, &config), Verdict::Limited);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_limit_enabled_by_reload_starts_full() {
+    let guard = FloodGuard::new();
+    let disabled = config(|_| {});
+    for _ in 0..100 {
+      assert_eq!(guard.check(1000, 2000, 1000, &disabled), Verdict::Allowed);
+    }
+
+    let enabled = config(|c| {
+      c.messages_per_second = 2;
+      c.bytes_per_minute = 100;
+    });
+    assert_eq!(guard.check(1000, 2000, 50, &enabled), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 50, &enabled), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 1, &enabled), Verdict::Limited);
+
+    tokio::time::advance(Duration::from_secs(3)).await;
+    assert_eq!(guard.check(1000, 2000, 5, &enabled), Verdict::Allowed);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_repeat_offender() {
+    let guard = FloodGuard::new();
+    let config = config(|c| {
+      c.messages_per_second = 1;
+      c.offender_violations = 3;
+    });
+
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Limited);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Limited);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Offender);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Limited);
+
+    // Violations older than a minute don't add up
+    tokio::time::advance(MINUTE).await;
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Allowed);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Limited);
+    assert_eq!(guard.check(1000, 2000, 3, &config), Verdict::Limited);
+  }
+}
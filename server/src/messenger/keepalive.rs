@@ -0,0 +1,201 @@
+//! Idle and ping timeouts for logged in sessions.
+//!
+//! ```toml
+//! [session]
+//! idle_timeout_secs = 300   # no packet at all from the client
+//! ping_timeout_secs = 600   # no `Ping` from the client
+//! drain_timeout_secs = 5    # time to wind down on server shutdown
+//! ```
+//!
+//! Setting a timeout to 0 disables it. GG 6.0 pings the server every minute
+//! or so, so a silent connection usually means the client is gone.
+
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use gg_protocol::GGPacket;
+use crate::messenger::MultiLogin;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+  pub idle_timeout_secs: u64,
+  pub ping_timeout_secs: u64,
+  pub drain_timeout_secs: u64,
+  pub multi_login: MultiLogin,
+}
+
+impl Default for SessionConfig {
+  fn default() -> Self {
+    Self {
+      idle_timeout_secs: 300,
+      ping_timeout_secs: 600,
+      drain_timeout_secs: 5,
+      multi_login: MultiLogin::default(),
+    }
+  }
+}
+
+impl SessionConfig {
+  pub fn idle_timeout(&self) -> Option<Duration> {
+    Some(Duration::from_secs(self.idle_timeout_secs)).filter(|d| !d.is_zero())
+  }
+
+  pub fn ping_timeout(&self) -> Option<Duration> {
+    Some(Duration::from_secs(self.ping_timeout_secs)).filter(|d| !d.is_zero())
+  }
+
+  /// How long a session may finish its work after the server starts shutting down.
+  pub fn drain_timeout(&self) -> Duration {
+    Duration::from_secs(self.drain_timeout_secs)
+  }
+}
+
+/// Which timeout closed the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAliveExpiry {
+  /// Nothing was received from the client.
+  Idle,
+  /// The client kept sending packets, but no `Ping`.
+  Ping,
+}
+
+/// Tracks when the client was last heard from.
+#[derive(Debug)]
+pub struct KeepAlive {
+  idle_timeout: Option<Duration>,
+  ping_timeout: Option<Duration>,
+  last_packet: Instant,
+  last_ping: Instant,
+}
+
+impl KeepAlive {
+  pub fn new(config: &SessionConfig) -> Self {
+    let now = Instant::now();
+    Self {
+      idle_timeout: config.idle_timeout(),
+      ping_timeout: config.ping_timeout(),
+      last_packet: now,
+      last_ping: now,
+    }
+  }
+
+  /// Record a packet received from the client.
+  pub fn packet_received(&mut self, packet: &GGPacket) {
+    let now = Instant::now();
+    self.last_packet = now;
+    if matches!(packet, GGPacket::Ping) {
+      self.last_ping = now;
+    }
+  }
+
+  /// When the next timeout fires, `None` if both are disabled.
+  pub fn deadline(&self) -> Option<Instant> {
+    let idle = self.idle_timeout.map(|timeout| self.last_packet + timeout);
+    let ping = self.ping_timeout.map(|timeout| self.last_ping + timeout);
+
+    match (idle, ping) {
+      (Some(idle), Some(ping)) => Some(idle.min(ping)),
+      (idle, ping) => idle.or(ping),
+    }
+  }
+
+  /// Wait until the next deadline. Never resolves if both timeouts are disabled.
+  pub async fn wait(&self) {
+    match self.deadline() {
+      Some(deadline) => tokio::time::sleep_until(deadline).await,
+      None => std::future::pending().await,
+    }
+  }
+
+  /// Which timeout has passed, if any.
+  pub fn expired(&self) -> Option<KeepAliveExpiry> {
+    let now = Instant::now();
+
+    if self.idle_timeout.is_some_and(|timeout| now >= self.last_packet + timeout) {
+      return Some(KeepAliveExpiry::Idle);
+    }
+    if self.ping_timeout.is_some_and(|timeout| now >= self.last_ping + timeout) {
+      return Some(KeepAliveExpiry::Ping);
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gg_protocol::packets::NewStatus;
+  use gg_protocol::consts::GGStatus;
+
+  fn keepalive(idle_timeout_secs: u64, ping_timeout_secs: u64) -> KeepAlive {
+    KeepAlive::new(&SessionConfig { idle_timeout_secs, ping_timeout_secs, ..Default::default() })
+  }
+
+  fn status_change() -> GGPacket {
+    GGPacket::NewStatus(NewStatus { status: GGStatus::Busy, description: None, time: None })
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_idle_timeout_fires_after_silence() {
+    let keepalive = keepalive(300, 0);
+
+    tokio::time::advance(Duration::from_secs(299)).await;
+    assert_eq!(keepalive.expired(), None);
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert_eq!(keepalive.expired(), Some(KeepAliveExpiry::Idle));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_any_packet_resets_idle_timeout() {
+    let mut keepalive = keepalive(300, 0);
+
+    tokio::time::advance(Duration::from_secs(200)).await;
+    keepalive.packet_received(&status_change());
+    tokio::time::advance(Duration::from_secs(200)).await;
+    assert_eq!(keepalive.expired(), None);
+
+    let start = Instant::now();
+    keepalive.wait().await;
+    assert_eq!(start.elapsed(), Duration::from_secs(100));
+    assert_eq!(keepalive.expired(), Some(KeepAliveExpiry::Idle));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_ping_timeout_ignores_other_packets() {
+    let mut keepalive = keepalive(300, 600);
+
+    for _ in 0..9 {
+      tokio::time::advance(Duration::from_secs(60)).await;
+      assert_eq!(keepalive.expired(), None);
+      keepalive.packet_received(&status_change());
+    }
+
+    tokio::time::advance(Duration::from_secs(60)).await;
+    assert_eq!(keepalive.expired(), Some(KeepAliveExpiry::Ping));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_pings_keep_session_alive() {
+    let mut keepalive = keepalive(300, 600);
+
+    for _ in 0..60 {
+      tokio::time::advance(Duration::from_secs(60)).await;
+      keepalive.packet_received(&GGPacket::Ping);
+    }
+
+    assert_eq!(keepalive.expired(), None);
+    assert_eq!(keepalive.deadline(), Some(Instant::now() + Duration::from_secs(300)));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_disabled_timeouts_never_fire() {
+    let keepalive = keepalive(0, 0);
+    assert_eq!(keepalive.deadline(), None);
+
+    let waited = tokio::time::timeout(Duration::from_secs(86_400), keepalive.wait()).await;
+    assert!(waited.is_err());
+    assert_eq!(keepalive.expired(), None);
+  }
+}
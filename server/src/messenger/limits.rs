@@ -0,0 +1,227 @@
+//! Limits on concurrent GG connections.
+//!
+//! ```toml
+//! [limits]
+//! max_connections_per_ip = 20    # connections from a single address
+//! max_unauthenticated = 200      # connections that haven't logged in yet
+//! max_sessions = 5000            # all connections together
+//! ```
+//!
+//! Setting a limit to 0 disables it. Connections over a limit are closed
+//! right after they are accepted, before the welcome packet.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+  pub max_connections_per_ip: usize,
+  pub max_unauthenticated: usize,
+  pub max_sessions: usize,
+}
+
+impl Default for LimitsConfig {
+  fn default() -> Self {
+    Self {
+      max_connections_per_ip: 20,
+      max_unauthenticated: 200,
+      max_sessions: 5000,
+    }
+  }
+}
+
+/// Which limit a connection ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+  PerIp,
+  Unauthenticated,
+  Sessions,
+}
+
+impl Display for Rejection {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Rejection::PerIp => write!(f, "too many connections from this address"),
+      Rejection::Unauthenticated => write!(f, "too many connections waiting for login"),
+      Rejection::Sessions => write!(f, "too many sessions"),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+  per_ip: HashMap<IpAddr, usize>,
+  total: usize,
+  unauthenticated: usize,
+}
+
+/// Open connections, and how many were turned away.
+#[derive(Debug, Default)]
+pub struct ConnectionLimits {
+  counts: Mutex<Counts>,
+  rejected_per_ip: AtomicU64,
+  rejected_unauthenticated: AtomicU64,
+  rejected_sessions: AtomicU64,
+}
+
+impl ConnectionLimits {
+  pub fn new() -> Arc<Self> {
+    Arc::new(Self::default())
+  }
+
+  /// Let a new connection from `ip` in, if it fits within `config`.
+  pub fn acquire(self: &Arc<Self>, ip: IpAddr, config: &LimitsConfig) -> Result<ConnectionPermit, Rejection> {
+    let exceeds = |count: usize, max: usize| max > 0 && count >= max;
+
+    let mut counts = self.counts.lock();
+    let from_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+    let rejection = if exceeds(counts.total, config.max_sessions) {
+      Some(Rejection::Sessions)
+    } else if exceeds(from_ip, config.max_connections_per_ip) {
+      Some(Rejection::PerIp)
+    } else if exceeds(counts.unauthenticated, config.max_unauthenticated) {
+      Some(Rejection::Unauthenticated)
+    } else {
+      None
+    };
+
+    if let Some(rejection) = rejection {
+      drop(counts);
+      self.rejected_counter(rejection).fetch_add(1, Ordering::Relaxed);
+      return Err(rejection);
+    }
+
+    *counts.per_ip.entry(ip).or_default() += 1;
+    counts.total += 1;
+    counts.unauthenticated += 1;
+    Ok(ConnectionPermit { limits: self.clone(), ip, authenticated: false })
+  }
+
+  /// Connections turned away because of `rejection` since the server started.
+  pub fn rejected(&self, rejection: Rejection) -> u64 {
+    self.rejected_counter(rejection).load(Ordering::Relaxed)
+  }
+
+  /// Connections open right now.
+  pub fn open(&self) -> usize {
+    self.counts.lock().total
+  }
+
+  fn rejected_counter(&self, rejection: Rejection) -> &AtomicU64 {
+    match rejection {
+      Rejection::PerIp => &self.rejected_per_ip,
+      Rejection::Unauthenticated => &self.rejected_unauthenticated,
+      Rejection::Sessions => &self.rejected_sessions,
+    }
+  }
+}
+
+/// A connection counted against the limits until it is dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+  limits: Arc<ConnectionLimits>,
+  ip: IpAddr,
+  authenticated: bool,
+}
+
+impl ConnectionPermit {
+  /// The client logged in, it no longer counts as unauthenticated.
+  pub fn authenticated(&mut self) {
+    if !std::mem::replace(&mut self.authenticated, true) {
+      self.limits.counts.lock().unauthenticated -= 1;
+    }
+  }
+}
+
+impl Drop for ConnectionPermit {
+  fn drop(&mut self) {
+    let mut counts = self.limits.counts.lock();
+    counts.total -= 1;
+    if !self.authenticated {
+      counts.unauthenticated -= 1;
+    }
+    if let Some(from_ip) = counts.per_ip.get_mut(&self.ip) {
+      *from_ip -= 1;
+      if *from_ip == 0 {
+        counts.per_ip.remove(&self.ip);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limits(max_connections_per_ip: usize, max_unauthenticated: usize, max_sessions: usize) -> LimitsConfig {
+    LimitsConfig { max_connections_per_ip, max_unauthenticated, max_sessions }
+  }
+
+  fn ip(last: u8) -> IpAddr {
+    IpAddr::from([10, 0, 0, last])
+  }
+
+  #[test]
+  fn test_per_ip_limit_counts_each_address() {
+    let limits_state = ConnectionLimits::new();
+    let config = limits(2, 0, 0);
+
+    let _first = limits_state.acquire(ip(1), &config).unwrap();
+    let second = limits_state.acquire(ip(1), &config).unwrap();
+    assert_eq!(limits_state.acquire(ip(1), &config).unwrap_err(), Rejection::PerIp);
+    let _other = limits_state.acquire(ip(2), &config).unwrap();
+
+    drop(second);
+    let _third = limits_state.acquire(ip(1), &config).unwrap();
+    assert_eq!(limits_state.rejected(Rejection::PerIp), 1);
+    assert_eq!(limits_state.open(), 3);
+  }
+
+  #[test]
+  fn test_logging_in_frees_unauthenticated_slot() {
+    let limits_state = ConnectionLimits::new();
+    let config = limits(0, 1, 0);
+
+    let mut first = limits_state.acquire(ip(1), &config).unwrap();
+    assert_eq!(limits_state.acquire(ip(2), &config).unwrap_err(), Rejection::Unauthenticated);
+
+    first.authenticated();
+    first.authenticated();
+    let second = limits_state.acquire(ip(2), &config).unwrap();
+    drop(second);
+    drop(first);
+
+    assert_eq!(limits_state.rejected(Rejection::Unauthenticated), 1);
+    let counts = limits_state.counts.lock();
+    assert_eq!((counts.total, counts.unauthenticated), (0, 0));
+    assert!(counts.per_ip.is_empty());
+  }
+
+  #[test]
+  fn test_session_cap_includes_logged_in_clients() {
+    let limits_state = ConnectionLimits::new();
+    let config = limits(0, 0, 2);
+
+    let mut first = limits_state.acquire(ip(1), &config).unwrap();
+    first.authenticated();
+    let _second = limits_state.acquire(ip(2), &config).unwrap();
+    assert_eq!(limits_state.acquire(ip(3), &config).unwrap_err(), Rejection::Sessions);
+
+    drop(first);
+    assert!(limits_state.acquire(ip(3), &config).is_ok());
+  }
+
+  #[test]
+  fn test_zero_disables_limits() {
+    let limits_state = ConnectionLimits::new();
+    let config = limits(0, 0, 0);
+    let permits = (0..100).map(|_| limits_state.acquire(ip(1), &config).unwrap()).collect::<Vec<_>>();
+    assert_eq!(limits_state.open(), permits.len());
+  }
+}
@@ -0,0 +1,65 @@
+//! Connection counts of a single GG listener.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug)]
+pub struct ListenerStats {
+  pub addr: SocketAddr,
+  accepted: AtomicU64,
+  open: AtomicUsize,
+}
+
+impl ListenerStats {
+  pub fn new(addr: SocketAddr) -> Arc<Self> {
+    Arc::new(Self { addr, accepted: AtomicU64::new(0), open: AtomicUsize::new(0) })
+  }
+
+  /// Count a new connection. It stays open until the guard is dropped.
+  pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+    self.accepted.fetch_add(1, Ordering::Relaxed);
+    self.open.fetch_add(1, Ordering::Relaxed);
+    ConnectionGuard(self.clone())
+  }
+
+  /// Connections accepted since the server started.
+  pub fn accepted(&self) -> u64 {
+    self.accepted.load(Ordering::Relaxed)
+  }
+
+  /// Connections open right now.
+  pub fn open(&self) -> usize {
+    self.open.load(Ordering::Relaxed)
+  }
+}
+
+/// Keeps a connection counted as open, also if its task is dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard(Arc<ListenerStats>);
+
+impl Drop for ConnectionGuard {
+  fn drop(&mut self) {
+    let open = self.0.open.fetch_sub(1, Ordering::Relaxed) - 1;
+    tracing::debug!(listener = %self.0.addr, open, "GG connection closed");
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_guard_tracks_open_connections() {
+    let stats = ListenerStats::new("0.0.0.0:443".parse().unwrap());
+
+    let first = stats.connection_opened();
+    let second = stats.connection_opened();
+    assert_eq!((stats.accepted(), stats.open()), (2, 2));
+
+    drop(first);
+    assert_eq!((stats.accepted(), stats.open()), (2, 1));
+    drop(second);
+    assert_eq!((stats.accepted(), stats.open()), (2, 0));
+  }
+}
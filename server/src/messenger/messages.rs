@@ -1,79 +1,54 @@
-use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::Arc;
+use parking_lot::RwLock;
 use thiserror::Error;
-use tokio::sync::{mpsc, RwLock};
-use tokio::sync::mpsc::error::SendError;
-use tokio::time::error::Elapsed;
-use tokio_stream::wrappers::ReceiverStream;
 use tracing::instrument;
-use gg_protocol::consts::AckStatus;
-use gg_protocol::GGNumber;
+use gg_protocol::consts::{AckStatus, GGMessageClass, GG_MAX_MESSAGE_LENGTH};
+use gg_protocol::{cp1250_len, truncate_cp1250, GGNumber};
 use gg_protocol::packets::{GGRecvMessage, GGSendMessage};
-use crate::models::{DatabasePool, MessageRepository, RepositoryError, UserRepository, QueuedMessageId};
-
-#[derive(Clone, Debug)]
-pub enum SessionMessage {
-  Disconnect,
-  QueuedMessage(QueuedMessageId)
-}
-
-pub type MessagesStream = ReceiverStream<SessionMessage>;
+use crate::messenger::{MessageFilters, PresenceHub, SessionEvent, SessionRegistry};
+use crate::messenger::auto_reply::AutoReplyLimiter;
+use crate::models::{AutoReplyRepository, DatabasePool, MessageRepository, ModerationRepository, RepositoryError, UserRepository};
 
 #[derive(Debug)]
 pub struct MessageDispatcher {
-  sessions: RwLock<HashMap<GGNumber, mpsc::Sender<SessionMessage>>>,
+  registry: Arc<SessionRegistry>,
+  presence: Arc<PresenceHub>,
   db_pool: DatabasePool,
+  filters: RwLock<Arc<MessageFilters>>,
+  auto_replies: AutoReplyLimiter,
 }
 
 #[derive(Error, Debug)]
 pub enum MessageDispatcherError {
-  #[error("Failed to deliver message to recipient session")]
-  MessageDeliveryFailed(#[from] SendError<SessionMessage>),
   #[error("Failed to store message in database")]
   StorageError(#[from] RepositoryError),
-  #[error("Failed to send message to recipient session")]
-  SendTimeout(#[from] Elapsed)
 }
 
 impl MessageDispatcher {
-  pub fn new(db_pool: &DatabasePool) -> Self {
+  pub fn new(
+    db_pool: &DatabasePool,
+    registry: Arc<SessionRegistry>,
+    presence: Arc<PresenceHub>,
+    filters: MessageFilters
+  ) -> Self {
     Self {
-      sessions: RwLock::new(HashMap::new()),
-      db_pool: db_pool.clone()
+      registry,
+      presence,
+      db_pool: db_pool.clone(),
+      filters: RwLock::new(Arc::new(filters)),
+      auto_replies: AutoReplyLimiter::new(),
     }
   }
 
-  #[instrument(skip(self))]
-  pub async fn register(&self, uin: GGNumber) -> MessagesStream {
-    let mut sessions = self.sessions.write().await;
-    let (tx, rx) = mpsc::channel::<SessionMessage>(100);
-    sessions.insert(uin, tx);
-    ReceiverStream::new(rx)
-  }
-
-  #[instrument(skip(self))]
-  pub async fn unregister(&self, uin: GGNumber) {
-    let mut sessions = self.sessions.write().await;
-    sessions.remove(&uin);
-  }
-
-  #[instrument(skip(self))]
-  pub async fn kick(&self, uin: GGNumber) {
-    let mut sessions = self.sessions.write().await;
-    if let Some(sender) = sessions.get(&uin) {
-      tracing::info!(uin = uin, "User already signed in, kicking other session");
-      let _ = sender.send(SessionMessage::Disconnect).await;
-      sessions.remove(&uin);
-      tokio::time::sleep(Duration::from_millis(10)).await;// yield thread
-    }
+  /// Replace the filters, e.g. after a config reload. Messages already
+  /// being dispatched finish with the old ones.
+  pub fn set_filters(&self, filters: MessageFilters) {
+    *self.filters.write() = Arc::new(filters);
   }
 
   #[instrument(skip(self))]
   pub async fn dispatch(&self, sender: GGNumber, incoming_msg : GGSendMessage) -> Result<AckStatus, MessageDispatcherError> {
-    let messages = MessageRepository::new(&self.db_pool);
     let users = UserRepository::new(&self.db_pool);
-    let sessions = self.sessions.read().await;
-    let recipient = sessions.get(&incoming_msg.recipient);
     let now = std::time::SystemTime::now()
       .duration_since(std::time::UNIX_EPOCH)
       .unwrap_or_default()
@@ -84,6 +59,29 @@ impl MessageDispatcher {
       return Ok(AckStatus::NotDelivered)
     }
 
+    if ModerationRepository::new(&self.db_pool).is_muted(sender).await? {
+      tracing::warn!(sender = sender, recipient = incoming_msg.recipient, "message dropped, sender is muted");
+      return Ok(AckStatus::NotDelivered)
+    }
+
+    let length = cp1250_len(&incoming_msg.message);
+    if length > GG_MAX_MESSAGE_LENGTH {
+      tracing::warn!(sender = sender, recipient = incoming_msg.recipient, length = length, "message dropped, too long");
+      return Ok(AckStatus::NotDelivered)
+    }
+
+    let recipient = incoming_msg.recipient;
+    let filters = self.filters.read().clone();
+    let mut incoming_msg = match filters.apply(sender, incoming_msg) {
+      Ok(filtered) => filtered,
+      Err(rejected) => {
+        tracing::warn!(sender = sender, recipient = recipient, filter = rejected.filter, status = ?rejected.status, "message rejected by filter");
+        return Ok(rejected.status)
+      }
+    };
+    // A rewrite may have made the message longer
+    incoming_msg.truncate(GG_MAX_MESSAGE_LENGTH);
+
     let recv_msg = GGRecvMessage {
       message: incoming_msg.message,
       class: incoming_msg.class,
@@ -93,18 +91,290 @@ impl MessageDispatcher {
       formatting: incoming_msg.formatting,
     };
 
-    let msg = messages.store(incoming_msg.recipient, &recv_msg).await?;
+    let status = self.deliver(recipient, &recv_msg).await?;
+
+    if recv_msg.class != GGMessageClass::Ctcp
+      && let Err(e) = self.auto_reply(recipient, sender, now).await {
+      tracing::error!(owner = recipient, sender = sender, error = %e, "failed to send auto-reply");
+    }
+
+    Ok(status)
+  }
+
+  /// Store a message for `recipient` and hand it to its sessions.
+  async fn deliver(&self, recipient: GGNumber, recv_msg: &GGRecvMessage) -> Result<AckStatus, MessageDispatcherError> {
+    let msg = MessageRepository::new(&self.db_pool).store(recipient, recv_msg).await?;
+
+    // The first session delivers from the queue, any others get a copy
+    let mut delivered = 0;
+    for session in self.registry.sessions(recipient) {
+      let event = if delivered == 0 {
+        SessionEvent::Message(msg.id)
+      } else {
+        SessionEvent::CopiedMessage(msg.clone().into())
+      };
+      if self.registry.send(session, event) {
+        delivered += 1;
+      }
+    }
 
-    if let Some(tx) = recipient {
-      let _ = tokio::time::timeout(
-        Duration::from_secs(5),
-        tx.send(SessionMessage::QueuedMessage(msg.id))
-      ).await?;
-      tracing::info!(msg_id = msg.id, "Message send for delivery");
+    if delivered > 0 {
+      tracing::info!(msg_id = msg.id, sessions = delivered, "Message send for delivery");
       Ok(AckStatus::Delivered)
     } else {
       tracing::info!(msg_id = msg.id, "Message put to queue, user offline");
       Ok(AckStatus::Queued)
     }
   }
+
+  /// Answer `sender` with the auto-reply of `owner`, if it has one for its
+  /// current status. Auto-replies are delivered directly instead of being
+  /// dispatched, so they never trigger another auto-reply.
+  #[instrument(skip(self))]
+  async fn auto_reply(&self, owner: GGNumber, sender: GGNumber, now: u32) -> Result<(), MessageDispatcherError> {
+    if owner == sender {
+      return Ok(())
+    }
+
+    let Some(auto_reply) = AutoReplyRepository::new(&self.db_pool).find(owner).await? else {
+      return Ok(())
+    };
+    if !auto_reply.applies_to(self.presence.find(&owner).status) {
+      return Ok(())
+    }
+    if ModerationRepository::new(&self.db_pool).is_muted(owner).await? {
+      return Ok(())
+    }
+    if !self.auto_replies.try_reply(owner, sender) {
+      tracing::debug!("auto-reply already sent within the hour");
+      return Ok(())
+    }
+
+    let reply = GGRecvMessage {
+      sender: owner,
+      seq: 0,
+      time: now,
+      class: GGMessageClass::Chat,
+      message: truncate_cp1250(&auto_reply.message, GG_MAX_MESSAGE_LENGTH).to_string(),
+      formatting: None,
+    };
+    self.deliver(sender, &reply).await?;
+    tracing::info!("Auto-reply sent");
+    Ok(())
+  }
+
+  /// End every live session of a UIN, e.g. after a ban. Returns how many were kicked.
+  #[instrument(skip(self))]
+  pub fn kick(&self, uin: GGNumber) -> usize {
+    self.registry.sessions(uin)
+      .into_iter()
+      .filter(|session| self.registry.send(*session, SessionEvent::Kick))
+      .count()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio_stream::StreamExt;
+  use crate::core::{test_app_state, SharedAppState};
+  use gg_protocol::consts::GGStatus;
+  use crate::messenger::{MultiLogin, SessionId, UserPresence};
+  use gg_protocol::packets::RichTextFormat;
+  use crate::models::ModerationKind;
+
+  fn message(recipient: GGNumber) -> GGSendMessage {
+    GGSendMessage {
+      recipient,
+      seq: 1,
+      class: GGMessageClass::Chat,
+      message: "Hej".to_string(),
+      formatting: None,
+    }
+  }
+
+  async fn app_state_with_users() -> SharedAppState {
+    app_state_with_users_and("").await
+  }
+
+  async fn app_state_with_users_and(overrides: &str) -> SharedAppState {
+    let app_state = test_app_state(overrides).await;
+    let users = UserRepository::new(app_state.db_pool());
+    users.create(1000, "Ala", "ala@gg.pl", "tajne", true).await.unwrap();
+    users.create(2000, "Ola", "ola@gg.pl", "tajne", true).await.unwrap();
+    app_state
+  }
+
+  #[tokio::test]
+  async fn test_message_goes_to_newest_login() {
+    let app_state = app_state_with_users().await;
+    let registry = app_state.session_registry();
+    let dispatcher = app_state.message_dispatcher();
+
+    let old = SessionId::new(1000);
+    let new = SessionId::new(1000);
+    let _old_events = registry.register(old, MultiLogin::Kick).unwrap();
+    let mut new_events = registry.register(new, MultiLogin::Kick).unwrap();
+    assert!(!registry.unregister(old));
+
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Delivered);
+    assert!(matches!(new_events.next().await, Some(SessionEvent::Message(_))));
+
+    registry.unregister(new);
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Queued);
+  }
+
+  #[tokio::test]
+  async fn test_message_reaches_every_session() {
+    let app_state = app_state_with_users().await;
+    let registry = app_state.session_registry();
+    let dispatcher = app_state.message_dispatcher();
+
+    let mut phone = registry.register(SessionId::new(1000), MultiLogin::Allow).unwrap();
+    let mut desktop = registry.register(SessionId::new(1000), MultiLogin::Allow).unwrap();
+
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Delivered);
+    assert!(matches!(phone.next().await, Some(SessionEvent::Message(_))));
+    match desktop.next().await {
+      Some(SessionEvent::CopiedMessage(copy)) => {
+        assert_eq!(copy.sender, 2000);
+        assert_eq!(copy.message, "Hej");
+      },
+      other => panic!("expected a copy, got {:?}", other),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_muted_sender_is_not_delivered() {
+    let app_state = app_state_with_users().await;
+    let dispatcher = app_state.message_dispatcher();
+    let moderation = ModerationRepository::new(app_state.db_pool());
+
+    moderation.apply(2000, ModerationKind::Mute, None, Some("spam"), "admin").await.unwrap();
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::NotDelivered);
+    assert_eq!(dispatcher.dispatch(1000, message(2000)).await.unwrap(), AckStatus::Queued);
+    assert!(MessageRepository::new(app_state.db_pool()).find_pending(1000).await.unwrap().is_none());
+
+    moderation.lift(2000, ModerationKind::Mute).await.unwrap();
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Queued);
+  }
+
+  #[tokio::test]
+  async fn test_auto_reply_goes_out_once_and_is_never_answered() {
+    let app_state = app_state_with_users().await;
+    let dispatcher = app_state.message_dispatcher();
+    let messages = MessageRepository::new(app_state.db_pool());
+    let auto_replies = AutoReplyRepository::new(app_state.db_pool());
+    auto_replies.set(1000, "Jestem na urlopie", true, true).await.unwrap();
+    auto_replies.set(2000, "Nie ma mnie", true, true).await.unwrap();
+
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Queued);
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Queued);
+
+    let replies = messages.find_pending(2000).await.unwrap().unwrap();
+    assert_eq!(replies.len(), 1);
+    let reply = GGRecvMessage::from(replies[0].clone());
+    assert_eq!(reply.sender, 1000);
+    assert_eq!(reply.message, "Jestem na urlopie");
+
+    // 2000 is offline too, but the auto-reply doesn't get one back
+    assert_eq!(messages.find_pending(1000).await.unwrap().unwrap().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_auto_reply_follows_status() {
+    let app_state = app_state_with_users().await;
+    let dispatcher = app_state.message_dispatcher();
+    let messages = MessageRepository::new(app_state.db_pool());
+    AutoReplyRepository::new(app_state.db_pool()).set(1000, "Zajęty", false, true).await.unwrap();
+
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Queued);
+    assert!(messages.find_pending(2000).await.unwrap().is_none());
+
+    let session = SessionId::new(1000);
+    let _events = app_state.session_registry().register(session, MultiLogin::Allow).unwrap();
+    let busy = UserPresence { uin: 1000, status: GGStatus::BusyDescr, description: Some("Praca".to_string()), time: None };
+    assert!(app_state.presence_hub().update(session, busy));
+
+    // Client commands are not answered
+    let mut ctcp = message(1000);
+    ctcp.class = GGMessageClass::Ctcp;
+    assert_eq!(dispatcher.dispatch(2000, ctcp).await.unwrap(), AckStatus::Delivered);
+    assert!(messages.find_pending(2000).await.unwrap().is_none());
+
+    assert_eq!(dispatcher.dispatch(2000, message(1000)).await.unwrap(), AckStatus::Delivered);
+    let replies = messages.find_pending(2000).await.unwrap().unwrap();
+    assert_eq!(GGRecvMessage::from(replies[0].clone()).message, "Zajęty");
+  }
+
+  #[tokio::test]
+  async fn test_kick_ends_every_session() {
+    let app_state = app_state_with_users().await;
+    let registry = app_state.session_registry();
+
+    let mut phone = registry.register(SessionId::new(1000), MultiLogin::Allow).unwrap();
+    let mut desktop = registry.register(SessionId::new(1000), MultiLogin::Allow).unwrap();
+
+    assert_eq!(app_state.message_dispatcher().kick(1000), 2);
+    assert_eq!(phone.next().await, Some(SessionEvent::Kick));
+    assert_eq!(desktop.next().await, Some(SessionEvent::Kick));
+    assert_eq!(app_state.message_dispatcher().kick(2000), 0);
+  }
+
+  #[tokio::test]
+  async fn test_filters_rewrite_or_reject() {
+    let app_state = app_state_with_users_and("[filter]\nbanned_words = [\"spam\"]\nblock_links = true").await;
+    let dispatcher = app_state.message_dispatcher();
+    let messages = MessageRepository::new(app_state.db_pool());
+
+    let mut spam = message(1000);
+    spam.message = "kup spam, tanio".to_string();
+    spam.formatting = Some(vec![RichTextFormat::new(0), RichTextFormat::bold(10)]);
+    assert_eq!(dispatcher.dispatch(2000, spam).await.unwrap(), AckStatus::Queued);
+    let stored = messages.find_pending(1000).await.unwrap().unwrap().remove(0);
+    let stored = GGRecvMessage::from(stored);
+    assert_eq!(stored.message, "kup ***, tanio");
+    assert_eq!(stored.formatting, Some(vec![RichTextFormat::new(0), RichTextFormat::bold(9)]));
+
+    let mut link = message(1000);
+    link.message = "http://example.com".to_string();
+    assert_eq!(dispatcher.dispatch(2000, link.clone()).await.unwrap(), AckStatus::NotDelivered);
+
+    dispatcher.set_filters(MessageFilters::default());
+    assert_eq!(dispatcher.dispatch(2000, link).await.unwrap(), AckStatus::Queued);
+  }
+
+  #[tokio::test]
+  async fn test_message_length_limit() {
+    let app_state = app_state_with_users_and("[filter]\nbanned_words = [\"a\"]").await;
+    let dispatcher = app_state.message_dispatcher();
+    let messages = MessageRepository::new(app_state.db_pool());
+
+    // Polish letters are a single CP1250 byte
+    let mut longest = message(1000);
+    longest.message = "ż".repeat(GG_MAX_MESSAGE_LENGTH);
+    assert_eq!(dispatcher.dispatch(2000, longest).await.unwrap(), AckStatus::Queued);
+
+    let mut too_long = message(1000);
+    too_long.message = "ż".repeat(GG_MAX_MESSAGE_LENGTH + 1);
+    assert_eq!(dispatcher.dispatch(2000, too_long).await.unwrap(), AckStatus::NotDelivered);
+
+    // An emoji is sent as `&#128512;`
+    let mut emoji = message(1000);
+    emoji.message = format!("{}😀", "ż".repeat(GG_MAX_MESSAGE_LENGTH - 8));
+    assert_eq!(dispatcher.dispatch(2000, emoji).await.unwrap(), AckStatus::NotDelivered);
+
+    // Masking makes the message longer, it's cut back to the limit
+    let mut masked = message(1000);
+    masked.message = vec!["a"; 1000].join(" ");
+    masked.formatting = Some(vec![RichTextFormat::bold(0), RichTextFormat::italic(1990), RichTextFormat::new(2000)]);
+    assert_eq!(dispatcher.dispatch(2000, masked).await.unwrap(), AckStatus::Queued);
+
+    let stored = messages.find_pending(1000).await.unwrap().unwrap();
+    assert_eq!(stored.len(), 2);
+    let stored = GGRecvMessage::from(stored[1].clone());
+    assert_eq!(stored.message.len(), GG_MAX_MESSAGE_LENGTH);
+    assert!(stored.message.starts_with("*** ***"));
+    assert_eq!(stored.formatting, Some(vec![RichTextFormat::bold(0)]));
+  }
 }
@@ -1,21 +1,58 @@
+use std::io;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tokio::net::TcpListener;
 use crate::core::SharedAppState;
+use crate::messenger::listener::ListenerStats;
 
 mod session;
 mod presence;
 mod messages;
+mod auto_reply;
 mod contact_book;
+mod filter;
+mod flood;
+mod keepalive;
+mod limits;
+mod listener;
+mod moderation;
+mod motd;
+mod session_id;
+mod registry;
+mod system_messages;
 
-pub use messages::{MessageDispatcher, MessageDispatcherError, MessagesStream, SessionMessage};
-pub use presence::{PresenceHub, UserPresence, PresenceChangeStream};
+pub use filter::{FilterConfig, MessageFilters};
+pub use flood::{FloodConfig, FloodGuard, OffenderAction, Verdict};
+pub use keepalive::SessionConfig;
+pub use limits::{ConnectionLimits, ConnectionPermit, LimitsConfig, Rejection};
+pub use messages::{MessageDispatcher, MessageDispatcherError};
+pub use moderation::moderation_enforcer;
+pub use motd::MotdConfig;
+pub use presence::{PresenceHub, UserPresence};
+pub use registry::{SessionEvent, SessionEvents, SessionRegistry};
+pub use session_id::{MultiLogin, SessionId, Takeover};
+pub use system_messages::system_message_broadcaster;
 
+/// Accept GG connections on every listener until `shutdown` is cancelled,
+/// then wait for the open sessions to drain before returning.
 pub async fn gg_server(
-  listener: TcpListener,
+  listeners: Vec<TcpListener>,
   shutdown: CancellationToken,
   app_state: SharedAppState
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-  tracing::info!(bind = ?listener.local_addr()?, "Listening GG");
+  let mut stats = Vec::new();
+  let mut accepts = Vec::new();
+  for listener in listeners {
+    let listener_stats = ListenerStats::new(listener.local_addr()?);
+    tracing::info!(bind = %listener_stats.addr, "Listening GG");
+    stats.push(listener_stats.clone());
+    accepts.push(TcpListenerStream::new(listener).map(move |result| (listener_stats.clone(), result)));
+  }
+  let mut accepts = futures::stream::select_all(accepts);
+  let connections = TaskTracker::new();
 
   loop {
     tokio::select! {
@@ -26,40 +63,241 @@ pub async fn gg_server(
       }
 
       // Accept new connections
-      result = listener.accept() => {
-        match result {
-          Ok((socket, addr)) => {
-            tracing::info!("Accepted connection from {}", addr);
-            let conn_shutdown = shutdown.clone();
-            let conn_app_state = app_state.clone();
-            tokio::spawn(async move {
-              let mut session = session::UserSessionController::new(
-                socket,
-                conn_shutdown,
-                conn_app_state
-              );
-
-              let result = async {
-                session.establish_session().await?;
-                session.sync().await?;
-                session.run().await
-              }.await;
-
-              if let Err(e) = result {
-                tracing::error!("Session failed for {}: {}", addr, e);
-              }
-
-              session.cleanup().await;
-            });
+      Some((listener, result)) = accepts.next() => {
+        let socket = match result {
+          Ok(socket) => socket,
+          Err(e) if is_connection_error(&e) => {
+            tracing::warn!(listener = %listener.addr, "Error accepting connection: {}", e);
+            if is_out_of_files(&e) {
+              // Give open sessions a moment to free a descriptor
+              tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+            continue;
           },
           Err(e) => {
-            tracing::error!("Error accepting connection: {}", e);
+            tracing::error!(listener = %listener.addr, "Error accepting connection: {}", e);
             return Err(e.into());
           }
+        };
+        // A client may reset the connection before we get to it
+        let addr = match socket.peer_addr() {
+          Ok(addr) => addr,
+          Err(e) => {
+            tracing::warn!(listener = %listener.addr, "Dropped connection without peer address: {}", e);
+            continue;
+          }
+        };
+
+        if !app_state.is_ip_allowed(addr.ip()) {
+          tracing::warn!(listener = %listener.addr, "Rejected connection from {}: address not allowed", addr);
+          continue;
         }
+
+        let connection_limits = app_state.connection_limits();
+        let permit = match connection_limits.acquire(addr.ip(), app_state.config().limits()) {
+          Ok(permit) => permit,
+          Err(rejection) => {
+            tracing::warn!(
+              listener = %listener.addr,
+              open = connection_limits.open(),
+              rejected = connection_limits.rejected(rejection),
+              "Rejected connection from {}: {}", addr, rejection
+            );
+            continue;
+          }
+        };
+
+        let guard = listener.connection_opened();
+        tracing::info!(listener = %listener.addr, open = listener.open(), "Accepted connection from {}", addr);
+        let conn_shutdown = shutdown.clone();
+        let conn_app_state = app_state.clone();
+        connections.spawn(async move {
+          session::handle_connection(socket, addr, permit, conn_shutdown, conn_app_state).await;
+          drop(guard);
+        });
       }
     }
   }
 
+  // No new connections from here on
+  drop(accepts);
+  connections.close();
+
+  let open = connections.len();
+  tracing::info!(connections = open, "Draining GG connections...");
+
+  // Sessions stop on their own after the drain timeout, the extra second
+  // leaves room for their cleanup
+  let drain_timeout = app_state.config().session().drain_timeout() + Duration::from_secs(1);
+  let drained = tokio::time::timeout(drain_timeout, connections.wait()).await.is_ok();
+  let abandoned = if drained { 0 } else { connections.len() };
+  if abandoned > 0 {
+    tracing::warn!(connections = abandoned, "GG connections still open after the drain timeout, dropping them");
+  }
+  for listener in &stats {
+    tracing::info!(listener = %listener.addr, accepted = listener.accepted(), "GG listener closed");
+  }
+  let connection_limits = app_state.connection_limits();
+  tracing::info!(
+    per_ip = connection_limits.rejected(Rejection::PerIp),
+    unauthenticated = connection_limits.rejected(Rejection::Unauthenticated),
+    sessions = connection_limits.rejected(Rejection::Sessions),
+    "GG connections rejected over limits"
+  );
+  tracing::info!(drained = open.saturating_sub(abandoned), abandoned, "GG server stopped");
+
   Ok(())
-}
\ No newline at end of file
+}
+
+/// How long to wait before accepting again when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accept errors that concern a single connection or are temporary, the
+/// listener itself keeps working.
+fn is_connection_error(e: &io::Error) -> bool {
+  matches!(
+    e.kind(),
+    io::ErrorKind::ConnectionAborted
+      | io::ErrorKind::ConnectionReset
+      | io::ErrorKind::ConnectionRefused
+      | io::ErrorKind::NotConnected
+      | io::ErrorKind::TimedOut
+      | io::ErrorKind::Interrupted
+      | io::ErrorKind::WouldBlock
+  ) || is_out_of_files(e)
+}
+
+/// The process (EMFILE) or the system (ENFILE) ran out of file descriptors.
+fn is_out_of_files(e: &io::Error) -> bool {
+  matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::net::TcpStream;
+  use tokio_util::codec::Framed;
+  use gg_protocol::{GGCodec, GGPacket};
+  use crate::core::test_app_state;
+  use crate::models::IpBanRepository;
+
+  #[tokio::test]
+  async fn test_every_listener_serves_gg() {
+    let app_state = test_app_state("").await;
+    let listeners = vec![
+      TcpListener::bind("127.0.0.1:0").await.unwrap(),
+      TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let addrs = listeners.iter().map(|l| l.local_addr().unwrap()).collect::<Vec<_>>();
+
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(gg_server(listeners, shutdown.clone(), app_state));
+
+    let mut clients = Vec::new();
+    for addr in addrs {
+      let mut client = Framed::new(TcpStream::connect(addr).await.unwrap(), GGCodec::client());
+      assert!(matches!(client.next().await, Some(Ok(GGPacket::Welcome { .. }))));
+      clients.push(client);
+    }
+
+    shutdown.cancel();
+    for client in &mut clients {
+      assert!(matches!(client.next().await, Some(Ok(GGPacket::Disconnect))));
+    }
+    server.await.unwrap().unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_connections_over_limit_are_closed_before_welcome() {
+    let app_state = test_app_state("[limits]\nmax_connections_per_ip = 1").await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(gg_server(vec![listener], shutdown.clone(), app_state.clone()));
+
+    let mut first = Framed::new(TcpStream::connect(addr).await.unwrap(), GGCodec::client());
+    assert!(matches!(first.next().await, Some(Ok(GGPacket::Welcome { .. }))));
+
+    let mut second = Framed::new(TcpStream::connect(addr).await.unwrap(), GGCodec::client());
+    assert!(second.next().await.is_none());
+    assert_eq!(app_state.connection_limits().rejected(Rejection::PerIp), 1);
+
+    // A closed connection makes room again
+    drop(first);
+    let mut third = loop {
+      let mut client = Framed::new(TcpStream::connect(addr).await.unwrap(), GGCodec::client());
+      if let Some(Ok(GGPacket::Welcome { .. })) = client.next().await {
+        break client;
+      }
+    };
+
+    shutdown.cancel();
+    assert!(matches!(third.next().await, Some(Ok(GGPacket::Disconnect))));
+    server.await.unwrap().unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_denied_and_banned_addresses_are_closed_before_welcome() {
+    let app_state = test_app_state("[access]\nallow = [\"127.0.0.0/8\"]\ndeny = [\"127.0.0.2\"]").await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(gg_server(vec![listener], shutdown.clone(), app_state.clone()));
+
+    let connect_from = |local: &str| {
+      let local = local.parse().unwrap();
+      async move {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind(local).unwrap();
+        Framed::new(socket.connect(addr).await.unwrap(), GGCodec::client())
+      }
+    };
+
+    let mut denied = connect_from("127.0.0.2:0").await;
+    assert!(denied.next().await.is_none());
+
+    let mut allowed = connect_from("127.0.0.3:0").await;
+    assert!(matches!(allowed.next().await, Some(Ok(GGPacket::Welcome { .. }))));
+
+    IpBanRepository::new(app_state.db_pool()).ban("127.0.0.3".parse().unwrap(), None).await.unwrap();
+    app_state.ip_bans().load(app_state.db_pool()).await.unwrap();
+    let mut banned = connect_from("127.0.0.3:0").await;
+    assert!(banned.next().await.is_none());
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_reset_connection_does_not_stop_the_server() {
+    let app_state = test_app_state("").await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Reset before the server gets to accept it, `peer_addr` of the accepted socket fails
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.set_zero_linger().unwrap();
+    drop(socket.connect(addr).await.unwrap());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(gg_server(vec![listener], shutdown.clone(), app_state));
+
+    let mut client = Framed::new(TcpStream::connect(addr).await.unwrap(), GGCodec::client());
+    assert!(matches!(client.next().await, Some(Ok(GGPacket::Welcome { .. }))));
+    assert!(!server.is_finished());
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+  }
+
+  #[test]
+  fn test_accept_errors_of_one_connection_are_not_fatal() {
+    assert!(is_connection_error(&io::Error::from(io::ErrorKind::ConnectionAborted)));
+    assert!(is_connection_error(&io::Error::from(io::ErrorKind::NotConnected)));
+    assert!(is_connection_error(&io::Error::from_raw_os_error(24)));
+    assert!(!is_connection_error(&io::Error::from(io::ErrorKind::PermissionDenied)));
+  }
+}
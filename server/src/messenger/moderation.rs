@@ -0,0 +1,32 @@
+//! Ends the open sessions of users who get banned or suspended.
+
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use crate::core::{poll_every, AppState, SharedAppState};
+use crate::models::{ModerationRepository, RepositoryError};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Kick newly banned and suspended users until `shutdown` is cancelled.
+pub async fn moderation_enforcer(shutdown: CancellationToken, app_state: SharedAppState) {
+  poll_every(POLL_INTERVAL, shutdown, || async {
+    if let Err(e) = enforce_pending(&app_state).await {
+      tracing::error!("Failed to enforce user moderation: {}", e);
+    }
+  }).await
+}
+
+/// Kick the sessions of users banned or suspended since the last check.
+/// Returns how many entries were enforced.
+pub async fn enforce_pending(app_state: &AppState) -> Result<usize, RepositoryError> {
+  let moderation = ModerationRepository::new(app_state.db_pool());
+  let pending = moderation.find_not_enforced().await?;
+
+  for entry in &pending {
+    moderation.mark_enforced(entry.uin, entry.kind).await?;
+    let sessions = app_state.message_dispatcher().kick(entry.uin);
+    tracing::info!(uin = entry.uin, kind = %entry.kind, moderator = entry.moderator, sessions, "User moderation enforced");
+  }
+
+  Ok(pending.len())
+}
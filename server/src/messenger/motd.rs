@@ -0,0 +1,146 @@
+//! Message of the day, sent to every user once they are logged in.
+//!
+//! ```toml
+//! [motd]
+//! text = "Przerwa techniczna w niedzielę o 22:00"   # empty disables it
+//! sender = 0        # UIN the message comes from
+//! revision = 1      # bump to send the message again to everyone
+//! bold = true
+//! color = "#cc0000"
+//! ```
+//!
+//! Each user gets a revision at most once, deliveries are tracked in the
+//! database.
+
+use serde::{Deserialize, Serialize};
+use gg_protocol::{cp1250_len, GGNumber};
+use gg_protocol::consts::{GGMessageClass, GG_MAX_MESSAGE_LENGTH};
+use gg_protocol::packets::{GGRecvMessage, RgbColor, RichTextFormat};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct MotdConfig {
+  pub text: String,
+  pub sender: GGNumber,
+  pub revision: u32,
+  pub bold: bool,
+  pub italic: bool,
+  pub underline: bool,
+  /// Text color as `#rrggbb`.
+  pub color: Option<String>,
+}
+
+impl Default for MotdConfig {
+  fn default() -> Self {
+    Self {
+      text: String::new(),
+      sender: 0,
+      revision: 1,
+      bold: false,
+      italic: false,
+      underline: false,
+      color: None,
+    }
+  }
+}
+
+impl MotdConfig {
+  pub fn is_enabled(&self) -> bool {
+    !self.text.trim().is_empty()
+  }
+
+  /// Check the settings that serde can't.
+  pub fn validate(&self) -> Result<(), String> {
+    if cp1250_len(&self.text) > GG_MAX_MESSAGE_LENGTH {
+      return Err(format!("motd.text is longer than {} characters", GG_MAX_MESSAGE_LENGTH))
+    }
+    self.color().map(|_| ())
+  }
+
+  fn color(&self) -> Result<Option<RgbColor>, String> {
+    let Some(color) = &self.color else {
+      return Ok(None);
+    };
+
+    let invalid = || format!("motd.color {} is not a #rrggbb color", color);
+    let hex = color.strip_prefix('#').filter(|hex| hex.len() == 6).ok_or_else(invalid)?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid());
+    Ok(Some(RgbColor::new(channel(0)?, channel(2)?, channel(4)?)))
+  }
+
+  /// Styling for the whole text, `None` if it is plain.
+  fn formatting(&self) -> Option<Vec<RichTextFormat>> {
+    let format = RichTextFormat {
+      position: 0,
+      bold: self.bold,
+      italic: self.italic,
+      underline: self.underline,
+      color: self.color().ok().flatten(),
+    };
+    if format == RichTextFormat::new(0) { None } else { Some(vec![format]) }
+  }
+
+  /// The MOTD as a message sent at `time`.
+  pub fn message(&self, time: u32) -> GGRecvMessage {
+    GGRecvMessage {
+      sender: self.sender,
+      seq: 0,
+      time,
+      class: GGMessageClass::Msg,
+      message: self.text.clone(),
+      formatting: self.formatting(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_plain_motd_has_no_formatting() {
+    let motd = MotdConfig { text: "Witaj".to_string(), ..Default::default() };
+    assert!(motd.is_enabled());
+    assert_eq!(motd.message(0).formatting, None);
+    assert!(!MotdConfig { text: "  ".to_string(), ..Default::default() }.is_enabled());
+  }
+
+  #[test]
+  fn test_styling_covers_whole_text() {
+    let motd = MotdConfig {
+      text: "Przerwa techniczna".to_string(),
+      sender: 1000,
+      bold: true,
+      color: Some("#CC0010".to_string()),
+      ..Default::default()
+    };
+
+    let message = motd.message(1234);
+    assert_eq!(message.sender, 1000);
+    assert_eq!(message.formatting, Some(vec![RichTextFormat {
+      position: 0,
+      bold: true,
+      italic: false,
+      underline: false,
+      color: Some(RgbColor::new(0xcc, 0x00, 0x10)),
+    }]));
+  }
+
+  #[test]
+  fn test_invalid_color_is_rejected() {
+    for color in ["cc0000", "#cc00", "#gg0000", "#cc00000", "#żż00"] {
+      let motd = MotdConfig { color: Some(color.to_string()), ..Default::default() };
+      assert!(motd.validate().is_err(), "{}", color);
+    }
+  }
+
+  #[test]
+  fn test_text_must_fit_in_one_message() {
+    let motd = MotdConfig { text: "ż".repeat(GG_MAX_MESSAGE_LENGTH), ..Default::default() };
+    assert!(motd.validate().is_ok());
+
+    // Emoji are sent as numeric references like `&#128512;`
+    let motd = MotdConfig { text: "😀".repeat(GG_MAX_MESSAGE_LENGTH / 4), ..Default::default() };
+    assert!(motd.validate().is_err());
+  }
+}
@@ -1,12 +1,15 @@
 use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
 use std::net::Ipv4Addr;
+use std::sync::Arc;
 use parking_lot::RwLock;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
 use tracing::instrument;
 use gg_protocol::consts::{GGStatus, version::GG_VERSION_60};
 use gg_protocol::packets::ContactStatus;
-use gg_protocol::{GGLogin60, GGNumber};
+use gg_protocol::consts::GG_MAX_DESCRIPTION_LENGTH;
+use gg_protocol::{truncate_cp1250, GGLogin60, GGNumber};
+use crate::messenger::{SessionEvent, SessionId, SessionRegistry};
+use crate::models::StoredPresence;
 
 #[derive(Debug, Clone, Default)]
 pub struct UserPresence {
@@ -36,13 +39,34 @@ impl UserPresence {
   }
 }
 
+/// Cut a description to `GG_MAX_DESCRIPTION_LENGTH` before it reaches watchers.
+pub fn truncate_description(description: Option<String>) -> Option<String> {
+  description.map(|description| truncate_cp1250(&description, GG_MAX_DESCRIPTION_LENGTH).to_string())
+}
+
 impl From<GGLogin60> for UserPresence {
   fn from(value: GGLogin60) -> Self {
     Self {
       uin: value.uin,
       status: value.status,
       time: value.time,
-      description: value.description,
+      description: truncate_description(value.description),
+    }
+  }
+}
+
+impl From<StoredPresence> for UserPresence {
+  /// Presence shown while the user is offline. Only a description left
+  /// when going `NotAvailDescr` is kept, anything else reads as `NotAvail`.
+  fn from(stored: StoredPresence) -> Self {
+    match GGStatus::try_from(stored.status) {
+      Ok(GGStatus::NotAvailDescr) => Self {
+        uin: stored.uin,
+        status: GGStatus::NotAvailDescr,
+        description: truncate_description(stored.description),
+        time: stored.time,
+      },
+      _ => Self::offline(stored.uin),
     }
   }
 }
@@ -63,26 +87,123 @@ impl From<UserPresence> for ContactStatus {
   }
 }
 
-pub type PresenceChangeStream = ReceiverStream<GGNumber>;
+/// How available a status is, used to pick what contacts see when
+/// a UIN is logged in more than once.
+fn availability(status: GGStatus) -> u8 {
+  match status {
+    GGStatus::Avail | GGStatus::AvailDescr => 3,
+    GGStatus::Busy | GGStatus::BusyDescr => 2,
+    GGStatus::Invisible | GGStatus::InvisibleDescr => 1,
+    GGStatus::NotAvail | GGStatus::NotAvailDescr | GGStatus::Blocked => 0,
+  }
+}
+
+/// What the hub knows about a registered session: whom it watches and
+/// the presence it set.
+#[derive(Debug)]
+struct PresenceSession {
+  id: SessionId,
+  watching: HashSet<GGNumber>,
+  presence: UserPresence,
+}
+
+impl PresenceSession {
+  /// A fresh session starts offline, so it only shows once it sets a status.
+  fn new(id: SessionId) -> Self {
+    Self { id, watching: HashSet::new(), presence: UserPresence::offline(id.uin) }
+  }
+}
+
+type Sessions = HashMap<GGNumber, Vec<PresenceSession>>;
+type Observers = HashMap<GGNumber, HashSet<SessionId>>;
 
+/// Presence of every UIN and who watches it. Changes reach watching
+/// sessions through the `SessionRegistry`, which also decides which
+/// sessions are still logged in: a session it no longer knows can't
+/// change anything here.
+///
+/// Lock order is `observers`, then `sessions`, then `state`, and the
+/// registry's own lock after all of them.
 #[derive(Debug)]
 pub struct PresenceHub {
+  registry: Arc<SessionRegistry>,
+  /// Presence contacts see, the most available one of the UIN's sessions.
   state: RwLock<HashMap<GGNumber, UserPresence>>,
-  observers: RwLock<HashMap<GGNumber, HashSet<GGNumber>>>,
-  sessions: RwLock<HashMap<GGNumber, mpsc::Sender<GGNumber>>>
+  /// Watched UIN -> sessions watching it. The reverse of `PresenceSession::watching`.
+  observers: RwLock<Observers>,
+  /// Sessions of each UIN, the one that changed its presence last at the end.
+  sessions: RwLock<Sessions>
+}
+
+/// Remove `watcher` from the observers of every UIN in `watched`, dropping empty sets.
+fn remove_edges<'a>(
+  observers: &mut Observers,
+  watcher: SessionId,
+  watched: impl Iterator<Item = &'a GGNumber>
+) {
+  for watched_uin in watched {
+    if let Entry::Occupied(mut entry) = observers.entry(*watched_uin) {
+      entry.get_mut().remove(&watcher);
+      if entry.get().is_empty() {
+        entry.remove();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+fn find_session(sessions: &Sessions, id: SessionId) -> Option<&PresenceSession> {
+  sessions.get(&id.uin)?.iter().find(|s| s.id == id)
+}
+
+/// Hub entry of a session, created on first use.
+fn session_entry(sessions: &mut Sessions, id: SessionId) -> &mut PresenceSession {
+  let existing = sessions.entry(id.uin).or_default();
+  let position = match existing.iter().position(|s| s.id == id) {
+    Some(position) => position,
+    None => {
+      existing.insert(0, PresenceSession::new(id));
+      0
+    }
+  };
+  &mut existing[position]
+}
+
+/// Drop the hub entry of a session and the subscriptions it owned.
+fn remove_session(observers: &mut Observers, sessions: &mut Sessions, id: SessionId) -> Option<PresenceSession> {
+  let existing = sessions.get_mut(&id.uin)?;
+  let position = existing.iter().position(|s| s.id == id)?;
+  let removed = existing.remove(position);
+  if existing.is_empty() {
+    sessions.remove(&id.uin);
+  }
+  remove_edges(observers, id, removed.watching.iter());
+  Some(removed)
 }
 
 impl PresenceHub {
-  pub fn new() -> Self {
+  pub fn new(registry: Arc<SessionRegistry>) -> Self {
     Self {
+      registry,
       state: RwLock::new(HashMap::new()),
       observers: RwLock::new(HashMap::new()),
       sessions: RwLock::new(HashMap::new())
     }
   }
 
+  /// Number of UINs with at least one session.
   pub fn online(&self) -> usize {
-    return self.sessions.read().len();
+    self.registry.online()
+  }
+
+  /// Number of UINs watched by at least one session.
+  #[cfg(test)]
+  pub fn observed(&self) -> usize {
+    self.observers.read().len()
+  }
+
+  pub fn is_online(&self, uin: &GGNumber) -> bool {
+    self.registry.is_online(uin)
   }
 
   #[instrument(skip(self))]
@@ -90,52 +211,97 @@ impl PresenceHub {
     self.state.read().get(uin).cloned().unwrap_or_else(|| UserPresence::offline(*uin))
   }
 
-  #[instrument(skip(self))]
-  pub fn register(&self, uin: GGNumber) -> PresenceChangeStream {
-    let mut sessions = self.sessions.write();
-    let mut state = self.state.write();
-    state.insert(uin, UserPresence::offline(uin));
-    let (tx, rx) = mpsc::channel::<GGNumber>(10);
-    sessions.remove(&uin);
-    sessions.insert(uin, tx);
+  /// The most available presence among the UIN's registered sessions,
+  /// ties go to the latest change.
+  fn combined(&self, sessions: &Sessions, uin: GGNumber) -> UserPresence {
+    sessions.get(&uin)
+      .into_iter()
+      .flatten()
+      .filter(|s| self.registry.is_registered(s.id))
+      .max_by_key(|s| availability(s.presence.status))
+      .map(|s| s.presence.clone())
+      .unwrap_or_else(|| UserPresence::offline(uin))
+  }
+
+  /// Store `presence` and queue it for every session watching its UIN.
+  fn publish(&self, observers: &Observers, presence: UserPresence) {
+    let uin = presence.uin;
+    self.state.write().insert(uin, presence);
 
-    ReceiverStream::new(rx)
+    if let Some(watchers) = observers.get(&uin) {
+      for watcher in watchers {
+        self.registry.send(*watcher, SessionEvent::Presence(uin));
+      }
+    }
   }
 
+  /// Set the UINs a session watches, replacing what it watched before.
   #[instrument(skip(self))]
-  pub fn subscribe(&self, uin: GGNumber, watched: &[GGNumber]) {
+  pub fn subscribe(&self, session: SessionId, watched: &[GGNumber]) {
     let mut observers = self.observers.write();
+    let mut sessions = self.sessions.write();
+
+    if !self.registry.is_registered(session) {
+      tracing::warn!(uin = session.uin, "Subscribe without registered session, ignoring");
+      return;
+    }
 
-    for &watched_uid in watched {
-      observers.entry(watched_uid).or_default().insert(uin);
+    let registered = session_entry(&mut sessions, session);
+    let watched: HashSet<GGNumber> = watched.iter().copied().collect();
+    remove_edges(&mut observers, session, registered.watching.difference(&watched));
+    for &watched_uin in watched.difference(&registered.watching) {
+      observers.entry(watched_uin).or_default().insert(session);
     }
+    registered.watching = watched;
   }
 
+  /// Stop watching some UINs, keeping the rest of the subscriptions.
+  /// Nothing calls this until the server handles GG_REMOVE_NOTIFY.
+  #[cfg(test)]
   #[instrument(skip(self))]
-  pub fn unsubscribe(&self, uin: GGNumber, watched: &[GGNumber]) {
+  pub fn unsubscribe(&self, session: SessionId, watched: &[GGNumber]) {
     let mut observers = self.observers.write();
+    let mut sessions = self.sessions.write();
 
-    for &watched_uid in watched {
-      if let Some(set) = observers.get_mut(&watched_uid) {
-        set.remove(&uin);
+    if let Some(registered) = sessions.get_mut(&session.uin).and_then(|e| e.iter_mut().find(|s| s.id == session)) {
+      remove_edges(&mut observers, session, watched.iter().filter(|w| registered.watching.contains(w)));
+      for watched_uin in watched {
+        registered.watching.remove(watched_uin);
       }
     }
   }
 
+  /// Publish a presence regardless of who set it.
   #[instrument(skip(self))]
   pub fn notify(&self, presence : UserPresence) {
     let observers = self.observers.read();
-    let sessions = self.sessions.read();
-    let uin = presence.uin;
-    self.state.write().insert(uin, presence);
+    self.publish(&observers, presence);
+  }
 
-    if let Some(watchers) = observers.get(&uin) {
-      for watcher in watchers {
-        if let Some(channel) = sessions.get(watcher) {
-          let _ = channel.try_send(uin);
-        }
-      }
+  /// Publish the presence set by a session, or what its UIN's other sessions
+  /// show if they are more available. Ignored, returning `false`, when the
+  /// session isn't registered anymore.
+  #[instrument(skip(self))]
+  pub fn update(&self, session: SessionId, presence: UserPresence) -> bool {
+    let observers = self.observers.read();
+    let mut sessions = self.sessions.write();
+
+    if !self.registry.is_registered(session) {
+      tracing::warn!(uin = session.uin, "Presence from a replaced session, ignoring");
+      return false;
     }
+
+    let existing = sessions.entry(session.uin).or_default();
+    let mut updated = match existing.iter().position(|s| s.id == session) {
+      Some(position) => existing.remove(position),
+      None => PresenceSession::new(session),
+    };
+    updated.presence = presence;
+    existing.push(updated);
+
+    let presence = self.combined(&sessions, session.uin);
+    self.publish(&observers, presence);
+    true
   }
 
   #[instrument(skip(self))]
@@ -143,23 +309,44 @@ impl PresenceHub {
     self.notify(self.find(&uin))
   }
 
+  /// Unregister a session from the `SessionRegistry`, drop every
+  /// subscription it owned and publish what the UIN shows now.
+  ///
+  /// Returns the offline presence when this was the UIN's last session,
+  /// keeping a description left with `NotAvailDescr`. `None` when other
+  /// sessions are still there or the session had already been replaced,
+  /// which then leaves the presence untouched.
   #[instrument(skip(self))]
-  pub fn unregister(&self, uin: GGNumber, watched: &[GGNumber]) {
-    self.sessions.write().remove(&uin);
-    // self.state.write().remove(&uin);
-
+  pub fn unregister(&self, session: SessionId) -> Option<UserPresence> {
     let mut observers = self.observers.write();
-    for &watched_uin in watched {
-      if let Some(set) = observers.get_mut(&watched_uin) {
-        set.remove(&uin);
-      }
+    let mut sessions = self.sessions.write();
+
+    let removed = remove_session(&mut observers, &mut sessions, session);
+    if !self.registry.unregister(session) {
+      return None;
     }
+
+    if self.registry.is_online(&session.uin) {
+      let presence = self.combined(&sessions, session.uin);
+      self.publish(&observers, presence);
+      return None;
+    }
+
+    let offline = match removed {
+      Some(removed) if removed.presence.status == GGStatus::NotAvailDescr => removed.presence,
+      _ => UserPresence::offline(session.uin),
+    };
+    self.publish(&observers, offline.clone());
+
+    Some(offline)
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::messenger::{MultiLogin, SessionEvents};
+  use futures::FutureExt;
   use tokio_stream::StreamExt;
   use std::time::Duration;
 
@@ -167,19 +354,38 @@ mod tests {
     UserPresence { uin, status: GGStatus::Avail, description: None, time: None }
   }
 
-  async fn recv(stream: &mut PresenceChangeStream) -> Option<GGNumber> {
-    tokio::time::timeout(Duration::from_millis(100), stream.next())
+  fn new_hub() -> PresenceHub {
+    PresenceHub::new(Arc::new(SessionRegistry::new()))
+  }
+
+  /// Log `uin` in, returning the session id and its events.
+  fn login(hub: &PresenceHub, uin: GGNumber) -> (SessionId, SessionEvents) {
+    let session = SessionId::new(uin);
+    (session, hub.registry.register(session, MultiLogin::Kick).unwrap())
+  }
+
+  /// UIN of a presence event, `None` once the session is kicked or gone.
+  fn changed_uin(event: Option<SessionEvent>) -> Option<GGNumber> {
+    match event {
+      Some(SessionEvent::Presence(uin)) => Some(uin),
+      Some(SessionEvent::Kick) | None => None,
+      Some(other) => panic!("expected presence, got {:?}", other),
+    }
+  }
+
+  async fn recv(events: &mut SessionEvents) -> Option<GGNumber> {
+    tokio::time::timeout(Duration::from_millis(100), events.next())
       .await
       .ok()
-      .flatten()
+      .and_then(changed_uin)
   }
 
   #[tokio::test]
   async fn test_subscriber_receives_presence_updates() {
-    let hub = PresenceHub::new();
+    let hub = new_hub();
 
-    let mut rx = hub.register(1000);
-    hub.subscribe(1000, &[5000]);
+    let (s1000, mut rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000]);
 
     hub.notify(presence(5000));
 
@@ -188,12 +394,12 @@ mod tests {
 
   #[tokio::test]
   async fn test_multiple_subscribers_receive_same_update() {
-    let hub = PresenceHub::new();
+    let hub = new_hub();
 
-    let mut rx1 = hub.register(1000);
-    let mut rx2 = hub.register(2000);
-    hub.subscribe(1000, &[5000]);
-    hub.subscribe(2000, &[5000]);
+    let (s1000, mut rx1) = login(&hub, 1000);
+    let (s2000, mut rx2) = login(&hub, 2000);
+    hub.subscribe(s1000, &[5000]);
+    hub.subscribe(s2000, &[5000]);
 
     hub.notify(presence(5000));
 
@@ -203,12 +409,12 @@ mod tests {
 
   #[tokio::test]
   async fn test_unsubscribed_user_does_not_receive_updates() {
-    let hub = PresenceHub::new();
+    let hub = new_hub();
 
-    let mut rx1 = hub.register(1000);
-    let mut rx2 = hub.register(2000);
-    hub.subscribe(1000, &[5000]);
-    hub.subscribe(2000, &[6000]); // watches different user
+    let (s1000, mut rx1) = login(&hub, 1000);
+    let (s2000, mut rx2) = login(&hub, 2000);
+    hub.subscribe(s1000, &[5000]);
+    hub.subscribe(s2000, &[6000]); // watches different user
 
     hub.notify(presence(5000));
 
@@ -218,15 +424,15 @@ mod tests {
 
   #[tokio::test]
   async fn test_unregister_stops_receiving_updates() {
-    let hub = PresenceHub::new();
+    let hub = new_hub();
 
-    let mut rx = hub.register(1000);
-    hub.subscribe(1000, &[5000]);
+    let (s1000, mut rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000]);
 
     hub.notify(presence(5000));
     assert!(recv(&mut rx).await.is_some());
 
-    hub.unregister(1000, &[5000]);
+    hub.unregister(s1000);
 
     hub.notify(presence(5000));
     assert!(recv(&mut rx).await.is_none());
@@ -234,13 +440,13 @@ mod tests {
 
   #[tokio::test]
   async fn test_reregister_receives_updates_on_new_channel() {
-    let hub = PresenceHub::new();
+    let hub = new_hub();
 
-    let mut old_rx = hub.register(1000);
-    hub.subscribe(1000, &[5000]);
+    let (s1000, mut old_rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000]);
 
-    let mut new_rx = hub.register(1000); // re-register
-    hub.subscribe(1000, &[5000]);
+    let (s1000, mut new_rx) = login(&hub, 1000); // re-register
+    hub.subscribe(s1000, &[5000]);
 
     hub.notify(presence(5000));
 
@@ -250,12 +456,12 @@ mod tests {
 
   #[tokio::test]
   async fn test_bidirectional_watching() {
-    let hub = PresenceHub::new();
+    let hub = new_hub();
 
-    let mut rx1 = hub.register(1000);
-    let mut rx2 = hub.register(2000);
-    hub.subscribe(1000, &[2000]);
-    hub.subscribe(2000, &[1000]);
+    let (s1000, mut rx1) = login(&hub, 1000);
+    let (s2000, mut rx2) = login(&hub, 2000);
+    hub.subscribe(s1000, &[2000]);
+    hub.subscribe(s2000, &[1000]);
 
     hub.notify(presence(1000));
     hub.notify(presence(2000));
@@ -266,15 +472,15 @@ mod tests {
 
   #[tokio::test]
   async fn test_unsubscribe_stops_updates() {
-    let hub = PresenceHub::new();
+    let hub = new_hub();
 
-    let mut rx = hub.register(1000);
-    hub.subscribe(1000, &[5000, 6000]);
+    let (s1000, mut rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000, 6000]);
 
     hub.notify(presence(5000));
     assert_eq!(recv(&mut rx).await, Some(5000));
 
-    hub.unsubscribe(1000, &[5000]);
+    hub.unsubscribe(s1000, &[5000]);
 
     hub.notify(presence(5000));
     hub.notify(presence(6000));
@@ -282,4 +488,369 @@ mod tests {
     assert_eq!(recv(&mut rx).await, Some(6000));
     assert!(recv(&mut rx).await.is_none());
   }
-}
\ No newline at end of file
+
+  /// Everything the stream can yield right now, without waiting.
+  fn drain(events: &mut SessionEvents) -> Vec<GGNumber> {
+    let mut uins = Vec::new();
+    while let Some(Some(uin)) = events.next().now_or_never().map(changed_uin) {
+      uins.push(uin);
+    }
+    uins
+  }
+
+  fn described(uin: GGNumber, description: &str) -> UserPresence {
+    UserPresence { uin, status: GGStatus::BusyDescr, description: Some(description.to_string()), time: None }
+  }
+
+  #[tokio::test]
+  async fn test_burst_collapses_into_latest_state() {
+    let hub = new_hub();
+
+    let (s1000, mut rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000]);
+
+    for i in 0..100 {
+      hub.notify(described(5000, &format!("opis {}", i)));
+    }
+
+    assert_eq!(drain(&mut rx), vec![5000]);
+    assert_eq!(hub.find(&5000).description.as_deref(), Some("opis 99"));
+  }
+
+  #[tokio::test]
+  async fn test_slow_session_loses_no_updates() {
+    let hub = new_hub();
+    let watched: Vec<GGNumber> = (5000..5050).collect();
+
+    let (s1000, mut rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &watched);
+
+    // Far more changes than the old channel capacity of 10, nobody reading
+    for round in 0..20 {
+      for &uin in &watched {
+        hub.notify(described(uin, &format!("runda {}", round)));
+      }
+    }
+
+    assert_eq!(drain(&mut rx), watched);
+    assert!(watched.iter().all(|uin| hub.find(uin).description.as_deref() == Some("runda 19")));
+  }
+
+  #[tokio::test]
+  async fn test_update_after_drain_wakes_session() {
+    let hub = new_hub();
+
+    let (s1000, mut rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000, 6000]);
+
+    hub.notify(presence(5000));
+    assert_eq!(drain(&mut rx), vec![5000]);
+
+    hub.notify(presence(6000));
+    hub.notify(presence(5000));
+    assert_eq!(recv(&mut rx).await, Some(6000));
+    assert_eq!(recv(&mut rx).await, Some(5000));
+    assert!(recv(&mut rx).await.is_none());
+  }
+
+  #[tokio::test]
+  async fn test_load_hundreds_of_watchers() {
+    let hub = new_hub();
+    let watched: Vec<GGNumber> = (5000..5100).collect();
+
+    let mut streams: Vec<_> = (1..=500)
+      .map(|watcher| {
+        let (session, rx) = login(&hub, watcher);
+        hub.subscribe(session, &watched);
+        rx
+      })
+      .collect();
+
+    for round in 0..10 {
+      for &uin in &watched {
+        hub.notify(described(uin, &format!("runda {}", round)));
+      }
+    }
+
+    for rx in &mut streams {
+      assert_eq!(drain(rx), watched);
+    }
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_load_concurrent_slow_watchers_see_final_state() {
+    let hub = Arc::new(new_hub());
+    let watchers: Vec<GGNumber> = (1..=300).collect();
+
+    let tasks: Vec<_> = watchers.iter()
+      .map(|&watcher| {
+        let (session, mut rx) = login(&hub, watcher);
+        hub.subscribe(session, &[5000]);
+        let hub = hub.clone();
+
+        tokio::spawn(async move {
+          while let Some(uin) = changed_uin(rx.next().await) {
+            // Slow consumer, a real session writes to a socket here
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            if hub.find(&uin).description.as_deref() == Some("koniec") {
+              return true;
+            }
+          }
+          false
+        })
+      })
+      .collect();
+
+    let notifiers: Vec<_> = (0..4)
+      .map(|n| {
+        let hub = hub.clone();
+        tokio::spawn(async move {
+          for i in 0..250 {
+            hub.notify(described(5000, &format!("{} {}", n, i)));
+            tokio::task::yield_now().await;
+          }
+        })
+      })
+      .collect();
+
+    for notifier in notifiers {
+      notifier.await.unwrap();
+    }
+    hub.notify(described(5000, "koniec"));
+
+    for task in tasks {
+      let finished = tokio::time::timeout(Duration::from_secs(10), task).await;
+      assert!(finished.expect("watcher missed the final update").unwrap());
+    }
+  }
+
+  #[test]
+  fn test_stored_presence_keeps_only_offline_description() {
+    let stored = |status: GGStatus| StoredPresence {
+      uin: 1000,
+      status: status as u32,
+      description: Some("opis".to_string()),
+      time: Some(1234567890),
+      last_seen: None,
+    };
+
+    let away: UserPresence = stored(GGStatus::NotAvailDescr).into();
+    assert_eq!(away.status, GGStatus::NotAvailDescr);
+    assert_eq!(away.description.as_deref(), Some("opis"));
+    assert_eq!(away.time, Some(1234567890));
+
+    // Server crashed while the user was online, don't show them as busy
+    let busy: UserPresence = stored(GGStatus::BusyDescr).into();
+    assert_eq!(busy.status, GGStatus::NotAvail);
+    assert_eq!(busy.description, None);
+  }
+
+  #[test]
+  fn test_long_descriptions_are_truncated() {
+    let long = format!("{}ęź", "ą".repeat(69));
+    let stored = StoredPresence {
+      uin: 1000,
+      status: GGStatus::NotAvailDescr as u32,
+      description: Some(long.clone()),
+      time: None,
+      last_seen: None,
+    };
+    let away: UserPresence = stored.into();
+    assert_eq!(away.description, Some(format!("{}ę", "ą".repeat(69))));
+
+    let login = GGLogin60 { uin: 1000, description: Some(format!("{}😀", "x".repeat(65))), ..Default::default() };
+    assert_eq!(UserPresence::from(login).description, Some("x".repeat(65)));
+
+    assert_eq!(truncate_description(Some(String::new())), Some(String::new()));
+    assert_eq!(truncate_description(None), None);
+  }
+
+  /// `observers` must be exactly the reverse of the sessions' `watching` sets.
+  fn assert_consistent(hub: &PresenceHub) {
+    let observers = hub.observers.read();
+    let sessions = hub.sessions.read();
+
+    for (watched, watchers) in observers.iter() {
+      assert!(!watchers.is_empty(), "empty observer set left for {}", watched);
+      for watcher in watchers {
+        let session = find_session(&sessions, *watcher).expect("observer without a session");
+        assert!(session.watching.contains(watched));
+      }
+    }
+
+    for (uin, existing) in sessions.iter() {
+      assert!(!existing.is_empty(), "empty session list left for {}", uin);
+      for session in existing {
+        for watched in &session.watching {
+          assert!(observers.get(watched).is_some_and(|w| w.contains(&session.id)));
+        }
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_resubscribe_replaces_previous_set() {
+    let hub = new_hub();
+
+    let (s1000, mut rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000, 6000]);
+    hub.subscribe(s1000, &[6000, 7000]); // contact list sent again
+
+    hub.notify(presence(5000));
+    hub.notify(presence(7000));
+
+    assert_eq!(drain(&mut rx), vec![7000]);
+    assert!(!hub.observers.read().contains_key(&5000));
+    assert_consistent(&hub);
+  }
+
+  #[tokio::test]
+  async fn test_reregister_drops_previous_subscriptions() {
+    let hub = new_hub();
+
+    let (s1000, _old_rx) = login(&hub, 1000);
+    hub.subscribe(s1000, &[5000]);
+    let (_, mut new_rx) = login(&hub, 1000);
+
+    hub.notify(presence(5000));
+    assert!(drain(&mut new_rx).is_empty());
+
+    // The replaced session's edges go away once it cleans up
+    assert!(hub.unregister(s1000).is_none());
+    assert!(hub.observers.read().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_stale_session_cannot_touch_newer_one() {
+    let hub = new_hub();
+    let (s2000, mut watcher) = login(&hub, 2000);
+    hub.subscribe(s2000, &[1000]);
+
+    // Old login still cleaning up while the new one is already in
+    let (old, mut old_rx) = login(&hub, 1000);
+    let (new, _new_rx) = login(&hub, 1000);
+    assert!(recv(&mut old_rx).await.is_none()); // replaced, stream ended
+    hub.subscribe(new, &[5000]);
+    assert!(hub.update(new, presence(1000)));
+    drain(&mut watcher);
+
+    assert!(!hub.update(old, UserPresence::offline(1000)));
+    hub.subscribe(old, &[6000]);
+    assert!(hub.unregister(old).is_none());
+    assert!(hub.registry.register(old, MultiLogin::Kick).is_none()); // a late register can't take over either
+
+    assert!(hub.is_online(&1000));
+    assert_eq!(hub.find(&1000).status, GGStatus::Avail);
+    assert!(drain(&mut watcher).is_empty());
+    assert!(hub.observers.read().contains_key(&5000));
+    assert!(!hub.observers.read().contains_key(&6000));
+    assert_consistent(&hub);
+
+    assert_eq!(hub.unregister(new).unwrap().status, GGStatus::NotAvail);
+    assert_eq!(drain(&mut watcher), vec![1000]);
+  }
+
+  #[tokio::test]
+  async fn test_multiple_sessions_show_most_available() {
+    let hub = new_hub();
+    let (s2000, mut watcher) = login(&hub, 2000);
+    hub.subscribe(s2000, &[1000]);
+
+    let (phone, mut phone_rx) = login(&hub, 1000);
+    let desktop = SessionId::new(1000);
+    let _desktop_rx = hub.registry.register(desktop, MultiLogin::Allow).unwrap();
+    assert!(recv(&mut phone_rx).now_or_never().is_none()); // not kicked
+    assert_eq!(hub.online(), 2);
+
+    let status = |status: GGStatus| UserPresence { uin: 1000, status, description: None, time: None };
+    hub.update(phone, status(GGStatus::Busy));
+    hub.update(desktop, status(GGStatus::Avail));
+    hub.update(phone, status(GGStatus::Invisible));
+    assert_eq!(hub.find(&1000).status, GGStatus::Avail);
+
+    // Same availability, the latest change wins
+    hub.update(phone, status(GGStatus::AvailDescr));
+    assert_eq!(hub.find(&1000).status, GGStatus::AvailDescr);
+
+    assert!(hub.unregister(phone).is_none());
+    assert!(hub.is_online(&1000));
+    assert_eq!(hub.find(&1000).status, GGStatus::Avail);
+    assert_consistent(&hub);
+
+    drain(&mut watcher);
+    assert_eq!(hub.unregister(desktop).unwrap().status, GGStatus::NotAvail);
+    assert_eq!(drain(&mut watcher), vec![1000]);
+    assert!(!hub.is_online(&1000));
+  }
+
+  #[tokio::test]
+  async fn test_unregister_removes_every_edge() {
+    let hub = new_hub();
+
+    let (s1000, _rx1) = login(&hub, 1000);
+    let (s2000, mut rx2) = login(&hub, 2000);
+    hub.subscribe(s1000, &[5000, 6000, 2000]);
+    hub.subscribe(s2000, &[5000, 1000]);
+
+    hub.unregister(s1000);
+    assert!(hub.unregister(SessionId::new(3000)).is_none()); // never registered
+
+    assert_eq!(hub.observers.read().keys().copied().collect::<HashSet<_>>(), HashSet::from([5000, 1000]));
+    hub.notify(presence(5000));
+    assert_eq!(drain(&mut rx2), vec![1000, 5000]); // 1000 went offline first
+    assert_consistent(&hub);
+  }
+
+  #[tokio::test]
+  async fn test_observer_map_empty_after_all_sessions_end() {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    let hub = new_hub();
+    let mut rng = StdRng::seed_from_u64(33);
+    let mut sessions = HashMap::new();
+    let mut stale = Vec::new();
+
+    // Random logins, contact lists, re-logins and logouts, with replaced
+    // sessions still trying to change things
+    for _ in 0..2000 {
+      let uin = rng.random_range(1..=100);
+      let current = sessions.get(&uin).map(|(session, _)| *session);
+      let session = match rng.random_range(0..5) {
+        0 => stale.get(rng.random_range(0..stale.len().max(1))).copied(),
+        _ => current,
+      }.unwrap_or_else(|| SessionId::new(uin));
+
+      match rng.random_range(0..4) {
+        0 => {
+          if let Some((previous, _)) = sessions.insert(uin, login(&hub, uin)) {
+            stale.push(previous);
+          }
+        },
+        1 | 2 => {
+          let contacts: Vec<GGNumber> = (0..rng.random_range(0..30)).map(|_| rng.random_range(1..=150)).collect();
+          hub.subscribe(session, &contacts);
+        },
+        _ => {
+          let online = hub.online();
+          if hub.unregister(session).is_some() {
+            sessions.remove(&session.uin);
+          } else {
+            assert_eq!(hub.online(), online, "stale session unregistered a newer one");
+          }
+        },
+      }
+      assert_consistent(&hub);
+    }
+
+    for session in stale {
+      assert!(hub.unregister(session).is_none());
+    }
+    for (session, _) in sessions.into_values() {
+      assert!(hub.unregister(session).is_some());
+    }
+
+    assert!(hub.observers.read().is_empty());
+    assert_eq!(hub.online(), 0);
+  }
+}
@@ -0,0 +1,339 @@
+//! Logged in sessions and their outbound events.
+//!
+//! Every session has exactly one queue of `SessionEvent`s, shared by the
+//! presence hub, the message dispatcher and anything else that needs to
+//! reach a client. Pushing never blocks and never awaits, so no lock is held
+//! across an `.await`: a slow client only grows its own queue, and presence
+//! changes of the same UIN collapse into one event.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tracing::instrument;
+use gg_protocol::GGNumber;
+use gg_protocol::packets::GGRecvMessage;
+use crate::messenger::{MultiLogin, SessionId, Takeover};
+use crate::models::QueuedMessageId;
+
+/// Something a session has to pass on to its client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+  /// Presence of a watched UIN changed, the latest state is in the hub.
+  Presence(GGNumber),
+  /// A stored message to deliver from the queue.
+  Message(QueuedMessageId),
+  /// A message another session of the same UIN delivers from the queue.
+  CopiedMessage(GGRecvMessage),
+  /// The session has to end, e.g. another login took over.
+  Kick,
+  /// A notice from the server, shown as a message from UIN 0.
+  SystemNotice(String),
+}
+
+/// UINs whose presence changed since the session last looked.
+///
+/// Each UIN is kept once, so a burst of changes collapses into a single
+/// update and the session reads the latest state from the hub.
+#[derive(Debug, Default)]
+struct DirtyUins {
+  order: VecDeque<GGNumber>,
+  members: HashSet<GGNumber>,
+}
+
+impl DirtyUins {
+  fn insert(&mut self, uin: GGNumber) {
+    if self.members.insert(uin) {
+      self.order.push_back(uin);
+    }
+  }
+
+  fn take(&mut self) -> VecDeque<GGNumber> {
+    self.members.clear();
+    std::mem::take(&mut self.order)
+  }
+}
+
+/// Events waiting for a session. A kick goes first, then everything else
+/// in order, then presence changes.
+#[derive(Debug, Default)]
+struct Outbox {
+  kicked: bool,
+  events: VecDeque<SessionEvent>,
+  presence: DirtyUins,
+}
+
+/// Registry side of a session's queue.
+#[derive(Debug)]
+struct RegisteredSession {
+  id: SessionId,
+  outbox: Arc<Mutex<Outbox>>,
+  /// Wakes the session up, a full channel means a wake up is already pending.
+  wake: mpsc::Sender<()>,
+}
+
+impl RegisteredSession {
+  fn new(id: SessionId) -> (Self, SessionEvents) {
+    let outbox = Arc::new(Mutex::new(Outbox::default()));
+    let (wake, wake_rx) = mpsc::channel(1);
+
+    let events = SessionEvents {
+      outbox: outbox.clone(),
+      wake: wake_rx,
+      pending: VecDeque::new(),
+    };
+    (Self { id, outbox, wake }, events)
+  }
+
+  fn push(&self, event: SessionEvent) {
+    {
+      let mut outbox = self.outbox.lock();
+      match event {
+        SessionEvent::Presence(uin) => outbox.presence.insert(uin),
+        SessionEvent::Kick => outbox.kicked = true,
+        other => outbox.events.push_back(other),
+      }
+    }
+    let _ = self.wake.try_send(());
+  }
+}
+
+/// Stream of a session's events. Ends once the session is unregistered or
+/// replaced, after the events queued before that.
+#[derive(Debug)]
+pub struct SessionEvents {
+  outbox: Arc<Mutex<Outbox>>,
+  wake: mpsc::Receiver<()>,
+  pending: VecDeque<GGNumber>,
+}
+
+impl Stream for SessionEvents {
+  type Item = SessionEvent;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    loop {
+      let dirty = {
+        let mut outbox = self.outbox.lock();
+        if std::mem::take(&mut outbox.kicked) {
+          return Poll::Ready(Some(SessionEvent::Kick));
+        }
+        if let Some(event) = outbox.events.pop_front() {
+          return Poll::Ready(Some(event));
+        }
+        if self.pending.is_empty() { outbox.presence.take() } else { VecDeque::new() }
+      };
+
+      if !dirty.is_empty() {
+        self.pending = dirty;
+      }
+      if let Some(uin) = self.pending.pop_front() {
+        return Poll::Ready(Some(SessionEvent::Presence(uin)));
+      }
+
+      match self.wake.poll_recv(cx) {
+        Poll::Ready(Some(())) => continue,
+        Poll::Ready(None) => return Poll::Ready(None),
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+  }
+}
+
+/// Every logged in session, by UIN, in the order they registered.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+  sessions: RwLock<HashMap<GGNumber, Vec<RegisteredSession>>>,
+}
+
+impl SessionRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Register a session. Depending on `multi_login`, older sessions of the
+  /// same UIN are kicked, or they stay next to the new one. Returns `None`
+  /// when the session may not register.
+  #[instrument(skip(self))]
+  pub fn register(&self, session: SessionId, multi_login: MultiLogin) -> Option<SessionEvents> {
+    let mut sessions = self.sessions.write();
+    let existing = sessions.entry(session.uin).or_default();
+
+    match multi_login.takeover(&session, existing.iter().map(|s| &s.id)) {
+      Takeover::Refuse => {
+        tracing::warn!(uin = session.uin, "Session not allowed next to the existing ones, refusing");
+        if existing.is_empty() {
+          sessions.remove(&session.uin);
+        }
+        return None;
+      },
+      Takeover::Replace => {
+        for previous in existing.drain(..) {
+          tracing::info!(uin = session.uin, "User already signed in, kicking other session");
+          previous.push(SessionEvent::Kick);
+        }
+      },
+      Takeover::Join => {
+        tracing::info!(uin = session.uin, sessions = existing.len() + 1, "User signed in once more");
+      },
+    }
+
+    let (registered, events) = RegisteredSession::new(session);
+    existing.push(registered);
+    Some(events)
+  }
+
+  /// Remove a session. Returns `false` if it wasn't registered, e.g. because
+  /// another login had already replaced it.
+  #[instrument(skip(self))]
+  pub fn unregister(&self, session: SessionId) -> bool {
+    let mut sessions = self.sessions.write();
+    let Some(existing) = sessions.get_mut(&session.uin) else {
+      return false;
+    };
+
+    let before = existing.len();
+    existing.retain(|s| s.id != session);
+    let removed = existing.len() < before;
+    if existing.is_empty() {
+      sessions.remove(&session.uin);
+    }
+    removed
+  }
+
+  pub fn is_registered(&self, session: SessionId) -> bool {
+    self.sessions.read()
+      .get(&session.uin)
+      .is_some_and(|existing| existing.iter().any(|s| s.id == session))
+  }
+
+  pub fn is_online(&self, uin: &GGNumber) -> bool {
+    self.sessions.read().contains_key(uin)
+  }
+
+  /// Number of UINs with at least one session.
+  pub fn online(&self) -> usize {
+    self.sessions.read().len()
+  }
+
+  /// Sessions of a UIN, oldest first.
+  pub fn sessions(&self, uin: GGNumber) -> Vec<SessionId> {
+    self.sessions.read()
+      .get(&uin)
+      .map(|existing| existing.iter().map(|s| s.id).collect())
+      .unwrap_or_default()
+  }
+
+  /// Queue an event for a session. Returns `false` if it isn't registered.
+  pub fn send(&self, session: SessionId, event: SessionEvent) -> bool {
+    let sessions = self.sessions.read();
+    let Some(registered) = sessions.get(&session.uin).and_then(|e| e.iter().find(|s| s.id == session)) else {
+      return false;
+    };
+    registered.push(event);
+    true
+  }
+
+  /// Queue an event for every session. Returns how many got it.
+  #[instrument(skip(self))]
+  pub fn broadcast(&self, event: SessionEvent) -> usize {
+    let sessions = self.sessions.read();
+    let mut sent = 0;
+    for registered in sessions.values().flatten() {
+      registered.push(event.clone());
+      sent += 1;
+    }
+    sent
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::FutureExt;
+  use tokio_stream::StreamExt;
+
+  fn drain(events: &mut SessionEvents) -> Vec<SessionEvent> {
+    let mut drained = Vec::new();
+    while let Some(Some(event)) = events.next().now_or_never() {
+      drained.push(event);
+    }
+    drained
+  }
+
+  #[tokio::test]
+  async fn test_kick_comes_first_and_ends_stream() {
+    let registry = SessionRegistry::new();
+    let old = SessionId::new(1000);
+    let mut events = registry.register(old, MultiLogin::Kick).unwrap();
+
+    registry.send(old, SessionEvent::Presence(5000));
+    registry.send(old, SessionEvent::Message(1));
+    let _new_events = registry.register(SessionId::new(1000), MultiLogin::Kick).unwrap();
+
+    assert_eq!(events.next().await, Some(SessionEvent::Kick));
+    assert_eq!(events.next().await, Some(SessionEvent::Message(1)));
+    assert_eq!(events.next().await, Some(SessionEvent::Presence(5000)));
+    assert_eq!(events.next().await, None);
+
+    assert!(!registry.send(old, SessionEvent::Message(2)));
+    assert!(!registry.unregister(old));
+    assert_eq!(registry.online(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_events_keep_order_and_presence_collapses() {
+    let registry = SessionRegistry::new();
+    let session = SessionId::new(1000);
+    let mut events = registry.register(session, MultiLogin::Kick).unwrap();
+
+    for _ in 0..50 {
+      registry.send(session, SessionEvent::Presence(5000));
+    }
+    registry.send(session, SessionEvent::Message(1));
+    registry.send(session, SessionEvent::SystemNotice("Przerwa techniczna".to_string()));
+    registry.send(session, SessionEvent::Message(2));
+
+    assert_eq!(drain(&mut events), vec![
+      SessionEvent::Message(1),
+      SessionEvent::SystemNotice("Przerwa techniczna".to_string()),
+      SessionEvent::Message(2),
+      SessionEvent::Presence(5000),
+    ]);
+  }
+
+  #[tokio::test]
+  async fn test_broadcast_reaches_every_session() {
+    let registry = SessionRegistry::new();
+    let mut first = registry.register(SessionId::new(1000), MultiLogin::Allow).unwrap();
+    let mut second = registry.register(SessionId::new(1000), MultiLogin::Allow).unwrap();
+    let mut other = registry.register(SessionId::new(2000), MultiLogin::Allow).unwrap();
+
+    let notice = SessionEvent::SystemNotice("Hej".to_string());
+    assert_eq!(registry.broadcast(notice.clone()), 3);
+    for events in [&mut first, &mut second, &mut other] {
+      assert_eq!(drain(events), vec![notice.clone()]);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_unregister_only_removes_own_session() {
+    let registry = SessionRegistry::new();
+    let first = SessionId::new(1000);
+    let second = SessionId::new(1000);
+    let _first_events = registry.register(first, MultiLogin::Allow).unwrap();
+    let mut second_events = registry.register(second, MultiLogin::Allow).unwrap();
+    assert_eq!(registry.sessions(1000), vec![first, second]);
+
+    assert!(registry.unregister(first));
+    assert!(!registry.unregister(first));
+    assert!(registry.is_online(&1000));
+    assert!(registry.is_registered(second));
+
+    assert!(registry.unregister(second));
+    assert!(!registry.is_online(&1000));
+    assert_eq!(second_events.next().await, None);
+  }
+}
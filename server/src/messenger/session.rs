@@ -1,33 +1,40 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
 use std::time::Duration;
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use rand::Rng;
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
 use tokio_util::sync::CancellationToken;
 use tracing::instrument;
-use gg_protocol::{GGCodec, GGError, GGPacket, GGNumber};
-use gg_protocol::packets::{ContactEntry, ContactStatus, ContactType, GGSendMessageAck};
+use gg_protocol::{cp1250_len, GGCodec, GGError, GGPacket, GGNumber};
+use gg_protocol::consts::{AckStatus, GGMessageClass};
+use gg_protocol::packets::{ContactEntry, ContactStatus, ContactType, GGRecvMessage, GGSendMessage, GGSendMessageAck};
 use crate::core::SharedAppState;
-use crate::messenger::{MessageDispatcherError, MessagesStream, PresenceChangeStream, SessionMessage, UserPresence};
+use crate::messenger::{ConnectionPermit, MessageDispatcherError, MultiLogin, OffenderAction, SessionEvent, SessionEvents, SessionId, UserPresence, Verdict};
 use crate::messenger::contact_book::ContactBook;
-use crate::models::{MessageRepository, UserRepository};
+use crate::messenger::presence::truncate_description;
+use crate::messenger::keepalive::{KeepAlive, KeepAliveExpiry};
+use crate::models::{MessageRepository, ModerationKind, ModerationRepository, MotdRepository, PresenceRepository, UserRepository};
 
-pub struct UserSessionController {
+pub struct UserSessionController<S = TcpStream> {
   seed: u32,
   uin: Option<GGNumber>,
+  session_id: Option<SessionId>,
   initial_presence: Option<UserPresence>,
   contacts: ContactBook,
-  protocol: Framed<TcpStream, GGCodec>,
-  peer_addr: std::net::SocketAddr,
+  protocol: Framed<S, GGCodec>,
+  peer_addr: SocketAddr,
+  permit: ConnectionPermit,
   shutdown: CancellationToken,
   app_state: SharedAppState,
-  presence_change_stream: Option<PresenceChangeStream>,
-  messages_stream: Option<MessagesStream>
+  events: Option<SessionEvents>
 }
 
-impl Debug for UserSessionController {
+impl<S> Debug for UserSessionController<S> {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     f.debug_struct("UserSessionController")
       .field("uin", &self.uin)
@@ -46,10 +53,24 @@ pub enum UserSessionError {
   AuthenticateTimeout,
   #[error("Authentication failed: invalid credentials")]
   InvalidCredentials,
+  #[error("Authentication failed: account waiting for approval")]
+  AccountNotApproved,
+  #[error("Authentication failed: account already signed in")]
+  AlreadySignedIn,
+  #[error("Authentication failed: account banned")]
+  AccountBanned,
+  #[error("Authentication failed: account suspended")]
+  AccountSuspended,
   #[error("GG protocol error: {0}")]
   ProtocolError(#[from] GGError),
-  #[error("Session timed out")]
+  #[error("Session timed out: no packets from client")]
   SessionTimeout,
+  #[error("Session timed out: no ping from client")]
+  PingTimeout,
+  #[error("Session replaced by a newer login")]
+  SessionReplaced,
+  #[error("Session closed: sender keeps flooding")]
+  Flooding,
   #[error("Database error: {0}")]
   DatabaseError(#[from] sqlx::Error),
   #[error("Repository error: {0}")]
@@ -58,23 +79,46 @@ pub enum UserSessionError {
   DeliveryError(#[from] MessageDispatcherError)
 }
 
-impl UserSessionController {
-  #[instrument(skip(stream, app_state))]
-  pub fn new(stream: TcpStream, shutdown: CancellationToken, app_state: SharedAppState) -> Self {
-    let peer_addr = stream.peer_addr().expect("Well no ip in this stream WTF");
+/// Run a GG connection from the welcome packet until it closes.
+pub async fn handle_connection<S>(stream: S, addr: SocketAddr, permit: ConnectionPermit, shutdown: CancellationToken, app_state: SharedAppState)
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut session = UserSessionController::new(stream, addr, permit, shutdown, app_state);
+
+  let result = async {
+    session.establish_session().await?;
+    session.sync().await?;
+    session.run().await
+  }.await;
+
+  if let Err(e) = result {
+    tracing::error!("Session failed for {}: {}", addr, e);
+  }
+
+  session.cleanup().await;
+}
+
+impl<S> UserSessionController<S>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  #[instrument(skip(stream, permit, app_state))]
+  pub fn new(stream: S, peer_addr: SocketAddr, permit: ConnectionPermit, shutdown: CancellationToken, app_state: SharedAppState) -> Self {
     let protocol = Framed::new(stream, GGCodec::server());
     let seed = rand::rng().random_range(100_000..1_000_000);
 
     Self {
       peer_addr,
+      permit,
       seed,
       contacts: ContactBook::new(),
       uin: None,
+      session_id: None,
       protocol,
       app_state,
       shutdown,
-      presence_change_stream: None,
-      messages_stream: None,
+      events: None,
       initial_presence: None
     }
   }
@@ -125,19 +169,42 @@ impl UserSessionController {
                 tracing::info!("User found for UIN: {}", login_info.uin);
                 let expected_password = gg_protocol::gg_login_hash(&user.password, self.seed);
 
-                if login_info.hash == expected_password {
+                if login_info.hash == expected_password && !user.is_approved() {
+                  tracing::warn!("Account {} is waiting for admin approval", login_info.uin);
+                  self.protocol.send(GGPacket::LoginFailed).await?;
+                  return Err(UserSessionError::AccountNotApproved)
+                } else if login_info.hash != expected_password {
+                  tracing::error!("Invalid password for {}", login_info.uin);
+                  self.protocol.send(GGPacket::LoginFailed).await?;
+                  return Err(UserSessionError::InvalidCredentials)
+                } else if let Some(block) = ModerationRepository::new(self.app_state.db_pool()).find_login_block(login_info.uin).await? {
+                  tracing::warn!(
+                    kind = %block.kind,
+                    reason = ?block.reason,
+                    moderator = block.moderator,
+                    until = ?block.until,
+                    "Account {} is moderated, rejecting login", login_info.uin
+                  );
+                  self.protocol.send(GGPacket::LoginFailed).await?;
+                  return Err(match block.kind {
+                    ModerationKind::Suspension => UserSessionError::AccountSuspended,
+                    _ => UserSessionError::AccountBanned,
+                  })
+                } else if self.app_state.config().session().multi_login == MultiLogin::Reject
+                  && self.app_state.presence_hub().is_online(&login_info.uin) {
+                  tracing::warn!("Account {} is already signed in, rejecting login", login_info.uin);
+                  self.protocol.send(GGPacket::LoginFailed).await?;
+                  return Err(UserSessionError::AlreadySignedIn)
+                } else {
                   tracing::info!("Authentication successful for {}", self.peer_addr);
                   let uin = login_info.uin;
                   self.uin = Some(uin);
                   self.initial_presence = Some(login_info.into());
                   tracing::info!(presence = ?self.initial_presence, uin = uin, "Initial user presence");
+                  self.permit.authenticated();
 
                   self.protocol.send(GGPacket::LoginOk).await?;
                   return Ok(())
-                } else {
-                  tracing::error!("Invalid password for {}", login_info.uin);
-                  self.protocol.send(GGPacket::LoginFailed).await?;
-                  return Err(UserSessionError::InvalidCredentials)
                 }
               } else {
                 tracing::error!("User not found for UIN: {}", login_info.uin);
@@ -156,17 +223,32 @@ impl UserSessionController {
   #[instrument]
   pub async fn sync(&mut self) -> Result<(), UserSessionError> {
     let current_uin = self.uin.expect("Missing uin");
+    let session_id = SessionId::new(current_uin);
+    self.session_id = Some(session_id);
 
-    self.app_state.message_dispatcher().kick(current_uin).await;
-    self.presence_change_stream = Some(self.app_state.presence_hub().register(current_uin));
-    self.messages_stream = Some(self.app_state.message_dispatcher().register(current_uin).await);
+    // Refused when the multi-login policy settles on another login of this UIN
+    let multi_login = self.app_state.config().session().multi_login;
+    self.events = self.app_state.session_registry().register(session_id, multi_login);
+    if self.events.is_none() {
+      self.protocol.send(GGPacket::Disconnect).await?;
+      return Err(UserSessionError::SessionReplaced);
+    }
 
     let presence = self.initial_presence.take().unwrap_or_else(|| UserPresence::available(current_uin));
-    self.app_state.presence_hub().notify(presence);
+    if self.app_state.presence_hub().update(session_id, presence.clone()) {
+      self.save_presence(&presence).await?;
+    }
 
     Ok(())
   }
 
+  async fn save_presence(&self, presence: &UserPresence) -> Result<(), UserSessionError> {
+    PresenceRepository::new(self.app_state.db_pool())
+      .save(presence.uin, presence.status, presence.description.as_deref(), presence.time)
+      .await?;
+    Ok(())
+  }
+
   #[instrument]
   async fn deliver_pending_messages(&mut self) -> Result<(), UserSessionError> {
     let current_uin = self.uin.expect("Missing uin");
@@ -201,63 +283,80 @@ impl UserSessionController {
     Ok(())
   }
 
+  /// Send the message of the day, unless this user already got its revision.
+  #[instrument]
+  async fn deliver_motd(&mut self) -> Result<(), UserSessionError> {
+    let current_uin = self.uin.expect("Missing uin");
+    let config = self.app_state.config();
+    let motd = config.motd();
+    if !motd.is_enabled() {
+      return Ok(())
+    }
+
+    // Claimed before sending, so two sessions logging in at once don't both get it
+    let deliveries = MotdRepository::new(self.app_state.db_pool());
+    if deliveries.claim(current_uin, motd.revision).await? {
+      tracing::info!(uin = current_uin, revision = motd.revision, "Delivering message of the day");
+      let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32;
+      if let Err(e) = self.protocol.send(GGPacket::RecvMessage(motd.message(time))).await {
+        deliveries.release(current_uin, motd.revision).await?;
+        return Err(e.into())
+      }
+    }
+    Ok(())
+  }
+
   #[instrument]
   pub async fn run(&mut self) -> Result<(), UserSessionError> {
     let mut contacts: Vec<ContactEntry> = Vec::new();
     let current_uin = self.uin.expect("Missing uin");
+    let session_id = self.session_id.expect("Missing session id");
+    let mut keepalive = KeepAlive::new(self.app_state.config().session());
     tracing::info!("Starting user session for {}", current_uin);
 
     loop {
-      let presence_change_stream = self.presence_change_stream.as_mut().expect("Presence subscription not set");
-      let messages_stream = self.messages_stream.as_mut().expect("Message subscription not set");
+      let events = self.events.as_mut().expect("Session not registered");
 
       tokio::select! {
         _ = self.shutdown.cancelled() => {
           tracing::info!("User session {} shutting down...", current_uin);
-          self.protocol.send(GGPacket::Disconnect).await?;
-          self.protocol.flush().await?;
+          self.drain().await?;
           break;
         },
 
-        _ = tokio::time::sleep(Duration::from_mins(5)) => {
-          tracing::error!("User timeout");
-          return Err(UserSessionError::SessionTimeout);
+        _ = keepalive.wait() => {
+          if let Some(expiry) = keepalive.expired() {
+            tracing::warn!(uin = current_uin, expiry = ?expiry, "Client went silent, closing session");
+            self.protocol.send(GGPacket::Disconnect).await?;
+            self.protocol.flush().await?;
+            return match expiry {
+              KeepAliveExpiry::Idle => Err(UserSessionError::SessionTimeout),
+              KeepAliveExpiry::Ping => Err(UserSessionError::PingTimeout),
+            };
+          }
         },
 
-        Some(session_msg) = messages_stream.next() => {
-          match session_msg {
-            SessionMessage::Disconnect => {
-              tracing::info!(uin = ?current_uin, "already signed in, kicking current session");
+        event = events.next() => {
+          match event {
+            // The stream also ends when the session was unregistered
+            Some(SessionEvent::Kick) | None => {
+              tracing::info!(uin = ?current_uin, "session kicked, disconnecting");
               self.protocol.send(GGPacket::Disconnect).await?;
               break;
             },
 
-            SessionMessage::QueuedMessage(msg_id) => {
-              tracing::info!(msg_id = msg_id, uin = ?current_uin, "delivering message");
-
-              let messages = MessageRepository::new(self.app_state.db_pool());
-              if let Some(message) = messages.find_one_pending(msg_id).await? {
-                if self.contacts.is_blocked(message.sender_uin) {
-                  tracing::error!(sender = message.sender_uin, uin = current_uin, "is blocked, skipping message delivery");
-                } else {
-                  self.protocol.send(GGPacket::RecvMessage(message.into())).await?;
-                }
-
-                messages.mark_single_delivered(msg_id).await?;
-              } else {
-                tracing::error!(msg_id = msg_id, "message already delivered");
-              }
-            }
+            Some(event) => self.handle_event(event).await?,
           }
         },
 
-        Some(uin) = presence_change_stream.next() => {
-          let presence = self.app_state.presence_hub().find(&uin);
-          tracing::info!(presence = ?presence, uin = ?current_uin, "Presence changed, sending new presence to client");
-          self.protocol.send(GGPacket::Status60(presence.into())).await?;
-        },
-
         result = self.protocol.next() => {
+          if let Some(Ok(packet)) = &result {
+            keepalive.packet_received(packet);
+          }
+
           match result {
             None => {
               tracing::info!("Connection closed from {}", current_uin);
@@ -270,12 +369,7 @@ impl UserSessionController {
             },
 
             Some(Ok(GGPacket::SendMessage(incoming_message))) => {
-              tracing::info!(incoming_message = ?incoming_message, uin = current_uin, "received message, relaying it to recipient");
-
-              let recipient = incoming_message.recipient;
-              let seq = incoming_message.seq;
-              let status = self.app_state.message_dispatcher().dispatch(current_uin, incoming_message).await?;
-              self.protocol.send(GGPacket::SendMessageAck(GGSendMessageAck { seq, status, recipient })).await?;
+              self.relay_message(incoming_message).await?;
             },
 
             Some(Ok(GGPacket::NewStatus(new_status))) => {
@@ -283,15 +377,20 @@ impl UserSessionController {
               let presence = UserPresence {
                 status: new_status.status,
                 uin: current_uin,
-                description: new_status.description,
+                description: truncate_description(new_status.description),
                 time: new_status.time
               };
-              self.app_state.presence_hub().notify(presence);
+              if self.app_state.presence_hub().update(session_id, presence.clone()) {
+                self.save_presence(&presence).await?;
+              }
             },
 
             Some(Ok(GGPacket::ListEmpty)) => {
               tracing::info!(uin = current_uin, "client has empty contact list");
+              self.contacts.set(&Vec::new());
+              self.app_state.presence_hub().subscribe(session_id, &[]);
               self.deliver_pending_messages().await?;
+              self.deliver_motd().await?;
             },
 
             Some(Ok(GGPacket::Ping)) => {
@@ -310,6 +409,7 @@ impl UserSessionController {
               self.handle_contact_list(&contacts).await?;
               contacts.clear();
               self.deliver_pending_messages().await?;
+              self.deliver_motd().await?;
             },
 
             _ => {
@@ -323,9 +423,135 @@ impl UserSessionController {
     return Ok(())
   }
 
+  /// Pass an event from the registry on to the client.
+  async fn handle_event(&mut self, event: SessionEvent) -> Result<(), UserSessionError> {
+    let current_uin = self.uin.expect("Missing uin");
+
+    match event {
+      SessionEvent::Presence(uin) => {
+        let presence = self.app_state.presence_hub().find(&uin);
+        tracing::info!(presence = ?presence, uin = ?current_uin, "Presence changed, sending new presence to client");
+        self.protocol.send(GGPacket::Status60(presence.into())).await?;
+      },
+
+      SessionEvent::Message(msg_id) => {
+        tracing::info!(msg_id = msg_id, uin = ?current_uin, "delivering message");
+
+        let messages = MessageRepository::new(self.app_state.db_pool());
+        if let Some(message) = messages.find_one_pending(msg_id).await? {
+          if self.contacts.is_blocked(message.sender_uin) {
+            tracing::error!(sender = message.sender_uin, uin = current_uin, "is blocked, skipping message delivery");
+          } else {
+            self.protocol.send(GGPacket::RecvMessage(message.into())).await?;
+          }
+
+          messages.mark_single_delivered(msg_id).await?;
+        } else {
+          tracing::error!(msg_id = msg_id, "message already delivered");
+        }
+      },
+
+      SessionEvent::CopiedMessage(message) => {
+        if self.contacts.is_blocked(message.sender) {
+          tracing::error!(sender = message.sender, uin = current_uin, "is blocked, skipping message copy");
+        } else {
+          self.protocol.send(GGPacket::RecvMessage(message)).await?;
+        }
+      },
+
+      SessionEvent::SystemNotice(text) => {
+        tracing::info!(uin = current_uin, "delivering system notice");
+        let time = std::time::SystemTime::now()
+          .duration_since(std::time::UNIX_EPOCH)
+          .unwrap_or_default()
+          .as_secs() as u32;
+        let notice = GGRecvMessage { sender: 0, seq: 0, time, class: GGMessageClass::Msg, message: text, formatting: None };
+        self.protocol.send(GGPacket::RecvMessage(notice)).await?;
+      },
+
+      // Ending the session is up to the caller
+      SessionEvent::Kick => {},
+    }
+
+    Ok(())
+  }
+
+  async fn relay_message(&mut self, incoming_message: GGSendMessage) -> Result<(), UserSessionError> {
+    let current_uin = self.uin.expect("Missing uin");
+    tracing::info!(incoming_message = ?incoming_message, uin = current_uin, "received message, relaying it to recipient");
+
+    let recipient = incoming_message.recipient;
+    let seq = incoming_message.seq;
+    let config = self.app_state.config();
+    let verdict = self.app_state.flood_guard().check(current_uin, recipient, cp1250_len(&incoming_message.message), config.flood());
+    let status = if verdict == Verdict::Allowed {
+      self.app_state.message_dispatcher().dispatch(current_uin, incoming_message).await?
+    } else {
+      tracing::warn!(uin = current_uin, recipient = recipient, "message over the flood limits, dropping it");
+      AckStatus::NotDelivered
+    };
+    self.protocol.send(GGPacket::SendMessageAck(GGSendMessageAck { seq, status, recipient })).await?;
+
+    if verdict == Verdict::Offender {
+      match config.flood().offender_action {
+        OffenderAction::None => {},
+        OffenderAction::Disconnect => {
+          tracing::warn!(uin = current_uin, "repeat flood offender, disconnecting");
+          self.protocol.send(GGPacket::Disconnect).await?;
+          return Err(UserSessionError::Flooding);
+        },
+        OffenderAction::Mute => {
+          tracing::warn!(uin = current_uin, "repeat flood offender, muting");
+          ModerationRepository::new(self.app_state.db_pool())
+            .apply(current_uin, ModerationKind::Mute, Some(config.flood().offender_mute()), Some("flood"), "server")
+            .await?;
+        },
+      }
+    }
+    Ok(())
+  }
+
+  /// Wind the session down on server shutdown. Events already queued reach
+  /// the client first, then it is told to disconnect, and messages it sent
+  /// before noticing are still stored and acked until it closes the
+  /// connection or the drain timeout passes.
+  #[instrument]
+  async fn drain(&mut self) -> Result<(), UserSessionError> {
+    let deadline = tokio::time::sleep(self.app_state.config().session().drain_timeout());
+    tokio::pin!(deadline);
+
+    while let Some(event) = self.events.as_mut().and_then(|events| events.next().now_or_never().flatten()) {
+      if event == SessionEvent::Kick {
+        break;
+      }
+      self.handle_event(event).await?;
+    }
+
+    self.protocol.send(GGPacket::Disconnect).await?;
+
+    loop {
+      tokio::select! {
+        _ = &mut deadline => {
+          tracing::warn!(uin = ?self.uin, "Client did not close the connection in time");
+          break;
+        },
+
+        result = self.protocol.next() => match result {
+          Some(Ok(GGPacket::SendMessage(incoming_message))) => self.relay_message(incoming_message).await?,
+          Some(Ok(GGPacket::Disconnect)) | Some(Err(_)) | None => break,
+          Some(Ok(_)) => {},
+        }
+      }
+    }
+
+    self.protocol.flush().await?;
+    Ok(())
+  }
+
   #[instrument]
   async fn handle_contact_list(&mut self, contacts: &Vec<ContactEntry>) -> Result<(), UserSessionError> {
     let current_uin = self.uin.expect("Missing uin");
+    let session_id = self.session_id.expect("Missing session id");
     let users = UserRepository::new(self.app_state.db_pool());
     let friends = contacts.iter()
       .filter(|u| u.user_type != ContactType::Blocked)
@@ -340,10 +566,22 @@ impl UserSessionController {
     tracing::info!("Sending contact list to {}: {} friends, and filtered from: {}", current_uin, existing_users.len(), friends.len());
 
     let presence_hub = self.app_state.presence_hub();
-    presence_hub.subscribe(current_uin, &existing_users);
+    presence_hub.subscribe(session_id, &existing_users);
+
+    // Contacts that are offline get the presence they left with
+    let offline = existing_users.iter()
+      .filter(|uin| !presence_hub.is_online(uin))
+      .copied()
+      .collect::<Vec<GGNumber>>();
+    let mut stored = PresenceRepository::new(self.app_state.db_pool())
+      .find_by_uins(&offline)
+      .await?
+      .into_iter()
+      .map(|p| (p.uin, UserPresence::from(p)))
+      .collect::<HashMap<GGNumber, UserPresence>>();
 
     let presences = existing_users.iter()
-      .map(|uin| self.app_state.presence_hub().find(uin).into())
+      .map(|uin| stored.remove(uin).unwrap_or_else(|| presence_hub.find(uin)).into())
       .collect::<Vec<ContactStatus>>();
 
     self.protocol.send(GGPacket::NotifyReply60(presences)).await?;
@@ -357,12 +595,543 @@ impl UserSessionController {
   pub async fn cleanup(&mut self) {
     tracing::info!(uin = ?self.uin, "Cleaning up user session");
 
-    self.presence_change_stream = None;
-    if let Some(uin) = self.uin {
-      self.app_state.message_dispatcher().unregister(uin).await;
-      self.app_state.presence_hub().notify(UserPresence::offline(uin));
-      self.app_state.presence_hub().unregister(uin, &[uin]); // todo: track contact uids
+    self.events = None;
+    if let Some(session_id) = self.session_id {
+      // A session replaced by a newer login leaves the presence alone
+      if let Some(offline) = self.app_state.presence_hub().unregister(session_id)
+        && let Err(e) = self.save_presence(&offline).await {
+        tracing::error!(uin = session_id.uin, error = %e, "Failed to save presence");
+      }
+      if !self.app_state.session_registry().is_online(&session_id.uin) {
+        self.app_state.flood_guard().forget(session_id.uin);
+      }
     }
     let _ =self.protocol.flush().await;
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::DuplexStream;
+  use tokio::task::JoinHandle;
+  use tokio::time::Instant;
+  use gg_protocol::GGLogin60;
+  use gg_protocol::consts::{AckStatus, GGStatus};
+  use gg_protocol::packets::GGSendMessage;
+  use gg_protocol::packets::NewStatus;
+  use crate::core::test_app_state;
+
+  fn buddies(uins: &[GGNumber]) -> GGPacket {
+    GGPacket::NotifyLast(uins.iter().map(|&uin| ContactEntry { uin, user_type: ContactType::Buddy }).collect())
+  }
+
+  /// Skip packets until the reply to a contact list arrives.
+  async fn contact_statuses(client: &mut Client) -> Vec<ContactStatus> {
+    loop {
+      match client.next().await {
+        Some(Ok(GGPacket::NotifyReply60(statuses))) => return statuses,
+        Some(Ok(_)) => continue,
+        other => panic!("expected contact statuses, got {:?}", other),
+      }
+    }
+  }
+
+  type Client = Framed<DuplexStream, GGCodec>;
+
+  const PASSWORD: &str = "tajne";
+
+  /// Create an account, log in and return the client side of the connection.
+  async fn login(app_state: &SharedAppState, uin: GGNumber) -> (Client, JoinHandle<()>) {
+    UserRepository::new(app_state.db_pool())
+      .create(uin, "Test", &format!("{}@gg.pl", uin), PASSWORD, true)
+      .await
+      .unwrap();
+
+    connect(app_state, uin).await
+  }
+
+  fn permit(app_state: &SharedAppState, addr: SocketAddr) -> ConnectionPermit {
+    app_state.connection_limits().acquire(addr.ip(), app_state.config().limits()).expect("over connection limits")
+  }
+
+  /// Log in to an existing account.
+  async fn connect(app_state: &SharedAppState, uin: GGNumber) -> (Client, JoinHandle<()>) {
+    connect_until(app_state, uin, CancellationToken::new()).await
+  }
+
+  /// Log in to an existing account, with a server shutting down on `shutdown`.
+  async fn connect_until(app_state: &SharedAppState, uin: GGNumber, shutdown: CancellationToken) -> (Client, JoinHandle<()>) {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let addr = "127.0.0.1:1550".parse().unwrap();
+    let handle = tokio::spawn(handle_connection(server, addr, permit(app_state, addr), shutdown, app_state.clone()));
+
+    let mut client = Framed::new(client, GGCodec::client());
+    let Some(Ok(GGPacket::Welcome { seed })) = client.next().await else {
+      panic!("expected welcome packet");
+    };
+    client.send(GGPacket::Login60(GGLogin60::login(uin, seed, PASSWORD))).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), GGPacket::LoginOk);
+
+    // Pong only comes back once the session is done syncing with the database
+    client.send(GGPacket::Ping).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), GGPacket::Pong);
+
+    (client, handle)
+  }
+
+  /// Try to log in to an existing account that is not allowed in.
+  async fn expect_login_failed(app_state: &SharedAppState, uin: GGNumber) {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let addr = "127.0.0.1:1552".parse().unwrap();
+    let handle = tokio::spawn(handle_connection(server, addr, permit(app_state, addr), CancellationToken::new(), app_state.clone()));
+
+    let mut client = Framed::new(client, GGCodec::client());
+    let Some(Ok(GGPacket::Welcome { seed })) = client.next().await else {
+      panic!("expected welcome packet");
+    };
+    client.send(GGPacket::Login60(GGLogin60::login(uin, seed, PASSWORD))).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), GGPacket::LoginFailed);
+    handle.await.unwrap();
+  }
+
+  async fn expect_disconnect(client: &mut Client) {
+    match client.next().await {
+      Some(Ok(GGPacket::Disconnect)) => {},
+      other => panic!("expected disconnect, got {:?}", other),
+    }
+  }
+
+  /// Timers have millisecond resolution, and login may finish just before time is paused.
+  fn assert_elapsed(start: Instant, secs: u64) {
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!((elapsed - secs as f64).abs() < 1.0, "expected ~{}s, got {}s", secs, elapsed);
+  }
+
+  // The database runs on its own thread, so time is only paused after login,
+  // when the session doesn't touch it anymore.
+
+  #[tokio::test]
+  async fn test_silent_session_is_disconnected() {
+    let app_state = test_app_state("[session]\nidle_timeout_secs = 300\nping_timeout_secs = 0").await;
+    let (mut client, handle) = login(&app_state, 1000).await;
+
+    tokio::time::pause();
+    let start = Instant::now();
+    expect_disconnect(&mut client).await;
+
+    assert_elapsed(start, 300);
+    handle.await.unwrap();
+    assert_eq!(app_state.presence_hub().online(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_pings_keep_session_alive() {
+    let app_state = test_app_state("[session]\nidle_timeout_secs = 300\nping_timeout_secs = 600").await;
+    let (mut client, handle) = login(&app_state, 1000).await;
+
+    tokio::time::pause();
+    for _ in 0..30 {
+      tokio::time::sleep(Duration::from_secs(60)).await;
+      client.send(GGPacket::Ping).await.unwrap();
+      assert_eq!(client.next().await.unwrap().unwrap(), GGPacket::Pong);
+    }
+    assert_eq!(app_state.presence_hub().online(), 1);
+
+    let last_ping = Instant::now();
+    expect_disconnect(&mut client).await;
+    assert_elapsed(last_ping, 300);
+    handle.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_client_without_pings_is_disconnected() {
+    let app_state = test_app_state("[session]\nidle_timeout_secs = 300\nping_timeout_secs = 600").await;
+    let (mut client, handle) = login(&app_state, 1000).await;
+
+    tokio::time::pause();
+    let start = Instant::now();
+    for _ in 0..5 {
+      tokio::time::sleep(Duration::from_secs(100)).await;
+      client.send(GGPacket::NotifyFirst(Vec::new())).await.unwrap();
+    }
+
+    expect_disconnect(&mut client).await;
+    assert_elapsed(start, 600);
+    handle.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_sessions_leave_no_subscriptions_behind() {
+    let app_state = test_app_state("").await;
+    let (mut client1, handle1) = login(&app_state, 1000).await;
+    let (mut client2, handle2) = login(&app_state, 2000).await;
+
+    client1.send(buddies(&[2000])).await.unwrap();
+    let statuses = contact_statuses(&mut client1).await;
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].uin, 2000);
+    assert_eq!(statuses[0].status, GGStatus::Avail as u8);
+
+    client2.send(buddies(&[1000])).await.unwrap();
+    contact_statuses(&mut client2).await;
+
+    // Contact list sent again replaces the previous one
+    client1.send(GGPacket::ListEmpty).await.unwrap();
+    client1.send(buddies(&[2000])).await.unwrap();
+    contact_statuses(&mut client1).await;
+
+    drop(client1);
+    drop(client2);
+    handle1.await.unwrap();
+    handle2.await.unwrap();
+
+    assert_eq!(app_state.presence_hub().online(), 0);
+    assert_eq!(app_state.presence_hub().observed(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_offline_contacts_show_last_description() {
+    let app_state = test_app_state("").await;
+
+    let (mut away, away_handle) = login(&app_state, 2000).await;
+    let status = NewStatus { status: GGStatus::NotAvailDescr, description: Some("Wracam o 18".to_string()), time: None };
+    away.send(GGPacket::NewStatus(status)).await.unwrap();
+    drop(away);
+    away_handle.await.unwrap();
+
+    // Left from before a server restart
+    UserRepository::new(app_state.db_pool()).create(3000, "Urlop", "3000@gg.pl", PASSWORD, true).await.unwrap();
+    PresenceRepository::new(app_state.db_pool())
+      .save(3000, GGStatus::NotAvailDescr, Some("Na urlopie"), Some(1234567890))
+      .await
+      .unwrap();
+
+    let (mut client, _handle) = login(&app_state, 1000).await;
+    client.send(buddies(&[2000, 3000])).await.unwrap();
+    let mut statuses = contact_statuses(&mut client).await;
+    statuses.sort_by_key(|s| s.uin);
+
+    assert_eq!(statuses[0].status, GGStatus::NotAvailDescr as u8);
+    assert_eq!(statuses[0].description.as_deref(), Some("Wracam o 18"));
+    assert_eq!(statuses[1].status, GGStatus::NotAvailDescr as u8);
+    assert_eq!(statuses[1].description.as_deref(), Some("Na urlopie"));
+    assert_eq!(statuses[1].time, Some(1234567890));
+
+    let saved = PresenceRepository::new(app_state.db_pool()).find_by_uins(&[2000]).await.unwrap();
+    assert!(saved[0].last_seen.is_some());
+  }
+
+  #[tokio::test]
+  async fn test_relogin_survives_old_session_cleanup() {
+    let app_state = test_app_state("").await;
+    let (mut watcher, watcher_handle) = login(&app_state, 2000).await;
+    watcher.send(buddies(&[1000])).await.unwrap();
+    contact_statuses(&mut watcher).await;
+
+    let (mut old, old_handle) = login(&app_state, 1000).await;
+    let (mut new, new_handle) = connect(&app_state, 1000).await;
+
+    // The old session is kicked and fully cleaned up after the new one is in
+    expect_disconnect(&mut old).await;
+    old_handle.await.unwrap();
+
+    assert!(app_state.presence_hub().is_online(&1000));
+    assert_eq!(app_state.presence_hub().find(&1000).status, GGStatus::Avail);
+    new.send(GGPacket::Ping).await.unwrap();
+    assert_eq!(new.next().await.unwrap().unwrap(), GGPacket::Pong);
+
+    // Messages still reach the new session
+    let message = GGSendMessage { recipient: 1000, seq: 7, class: GGMessageClass::Chat, message: "Hej".to_string(), formatting: None };
+    watcher.send(GGPacket::SendMessage(message)).await.unwrap();
+    loop {
+      match new.next().await {
+        Some(Ok(GGPacket::RecvMessage(msg))) => {
+          assert_eq!(msg.sender, 2000);
+          break;
+        },
+        Some(Ok(_)) => continue,
+        other => panic!("expected message, got {:?}", other),
+      }
+    }
+
+    drop(new);
+    new_handle.await.unwrap();
+    assert!(!app_state.presence_hub().is_online(&1000));
+
+    drop(watcher);
+    watcher_handle.await.unwrap();
+    assert_eq!(app_state.presence_hub().online(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_reject_policy_refuses_second_login() {
+    let app_state = test_app_state("[session]\nmulti_login = \"reject\"").await;
+    let (mut first, first_handle) = login(&app_state, 1000).await;
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let addr = "127.0.0.1:1551".parse().unwrap();
+    let second_handle = tokio::spawn(handle_connection(server, addr, permit(&app_state, addr), CancellationToken::new(), app_state.clone()));
+    let mut second = Framed::new(client, GGCodec::client());
+    let Some(Ok(GGPacket::Welcome { seed })) = second.next().await else {
+      panic!("expected welcome packet");
+    };
+    second.send(GGPacket::Login60(GGLogin60::login(1000, seed, PASSWORD))).await.unwrap();
+    assert_eq!(second.next().await.unwrap().unwrap(), GGPacket::LoginFailed);
+    second_handle.await.unwrap();
+
+    // The first session carries on
+    first.send(GGPacket::Ping).await.unwrap();
+    assert_eq!(first.next().await.unwrap().unwrap(), GGPacket::Pong);
+    assert!(app_state.presence_hub().is_online(&1000));
+
+    drop(first);
+    first_handle.await.unwrap();
+
+    // Signed out, so logging in works again
+    let (_again, _) = connect(&app_state, 1000).await;
+  }
+
+  #[tokio::test]
+  async fn test_allow_policy_delivers_to_every_session() {
+    let app_state = test_app_state("[session]\nmulti_login = \"allow\"").await;
+    let (mut sender, _sender_handle) = login(&app_state, 2000).await;
+    let (mut phone, phone_handle) = login(&app_state, 1000).await;
+    let (mut desktop, _desktop_handle) = connect(&app_state, 1000).await;
+
+    let message = GGSendMessage { recipient: 1000, seq: 3, class: GGMessageClass::Chat, message: "Do obu".to_string(), formatting: None };
+    sender.send(GGPacket::SendMessage(message)).await.unwrap();
+
+    for client in [&mut phone, &mut desktop] {
+      loop {
+        match client.next().await {
+          Some(Ok(GGPacket::RecvMessage(msg))) => {
+            assert_eq!(msg.message, "Do obu");
+            break;
+          },
+          Some(Ok(_)) => continue,
+          other => panic!("expected message, got {:?}", other),
+        }
+      }
+    }
+
+    // One session leaving keeps the user online
+    drop(phone);
+    phone_handle.await.unwrap();
+    assert!(app_state.presence_hub().is_online(&1000));
+    assert_eq!(app_state.presence_hub().find(&1000).status, GGStatus::Avail);
+  }
+
+  #[tokio::test]
+  async fn test_system_notice_comes_from_uin_zero() {
+    let app_state = test_app_state("").await;
+    let (mut client, _handle) = login(&app_state, 1000).await;
+
+    let notice = SessionEvent::SystemNotice("Przerwa techniczna o 22:00".to_string());
+    assert_eq!(app_state.session_registry().broadcast(notice), 1);
+
+    match client.next().await {
+      Some(Ok(GGPacket::RecvMessage(msg))) => {
+        assert_eq!(msg.sender, 0);
+        assert_eq!(msg.message, "Przerwa techniczna o 22:00");
+      },
+      other => panic!("expected notice, got {:?}", other),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_shutdown_drains_session() {
+    let app_state = test_app_state("").await;
+    let users = UserRepository::new(app_state.db_pool());
+    users.create(1000, "Test", "1000@gg.pl", PASSWORD, true).await.unwrap();
+    users.create(2000, "Test", "2000@gg.pl", PASSWORD, true).await.unwrap();
+
+    let shutdown = CancellationToken::new();
+    let (mut client, handle) = connect_until(&app_state, 1000, shutdown.clone()).await;
+
+    // Queued before shutdown, still reaches the client
+    app_state.session_registry().broadcast(SessionEvent::SystemNotice("Restart serwera".to_string()));
+    shutdown.cancel();
+    match client.next().await {
+      Some(Ok(GGPacket::RecvMessage(msg))) => assert_eq!(msg.message, "Restart serwera"),
+      other => panic!("expected notice, got {:?}", other),
+    }
+    expect_disconnect(&mut client).await;
+
+    // Sent before the client noticed the disconnect, still stored and acked
+    let message = GGSendMessage { recipient: 2000, seq: 7, class: GGMessageClass::Chat, message: "Zdazylem".to_string(), formatting: None };
+    client.send(GGPacket::SendMessage(message)).await.unwrap();
+    match client.next().await {
+      Some(Ok(GGPacket::SendMessageAck(ack))) => {
+        assert_eq!(ack.seq, 7);
+        assert_eq!(ack.status, AckStatus::Queued);
+      },
+      other => panic!("expected ack, got {:?}", other),
+    }
+
+    drop(client);
+    handle.await.unwrap();
+    assert!(!app_state.session_registry().is_online(&1000));
+    assert!(MessageRepository::new(app_state.db_pool()).find_pending(2000).await.unwrap().is_some());
+
+    let stored = PresenceRepository::new(app_state.db_pool()).find_by_uins(&[1000]).await.unwrap();
+    assert_eq!(UserPresence::from(stored[0].clone()).status, GGStatus::NotAvail);
+  }
+
+  #[tokio::test]
+  async fn test_motd_is_sent_once_per_revision() {
+    let app_state = test_app_state("[motd]\ntext = \"Witamy!\"\nsender = 1\nbold = true").await;
+    let (mut client, handle) = login(&app_state, 1000).await;
+
+    client.send(GGPacket::ListEmpty).await.unwrap();
+    match client.next().await {
+      Some(Ok(GGPacket::RecvMessage(msg))) => {
+        assert_eq!(msg.sender, 1);
+        assert_eq!(msg.message, "Witamy!");
+        assert!(msg.formatting.is_some_and(|f| f[0].bold));
+      },
+      other => panic!("expected MOTD, got {:?}", other),
+    }
+    drop(client);
+    handle.await.unwrap();
+
+    // Already seen, the next login only gets the pong
+    let (mut client, _handle) = connect(&app_state, 1000).await;
+    client.send(GGPacket::ListEmpty).await.unwrap();
+    client.send(GGPacket::Ping).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), GGPacket::Pong);
+  }
+
+  #[tokio::test]
+  async fn test_banned_user_is_kicked_and_cannot_log_in() {
+    let app_state = test_app_state("").await;
+    let (mut client, handle) = login(&app_state, 1000).await;
+    let moderation = ModerationRepository::new(app_state.db_pool());
+
+    moderation.apply(1000, ModerationKind::Ban, None, Some("spam"), "admin").await.unwrap();
+    assert_eq!(crate::messenger::moderation::enforce_pending(&app_state).await.unwrap(), 1);
+    expect_disconnect(&mut client).await;
+    handle.await.unwrap();
+    assert!(!app_state.presence_hub().is_online(&1000));
+
+    expect_login_failed(&app_state, 1000).await;
+    moderation.lift(1000, ModerationKind::Ban).await.unwrap();
+    let (_client, _handle) = connect(&app_state, 1000).await;
+  }
+
+  #[tokio::test]
+  async fn test_unapproved_user_cannot_log_in_until_approved() {
+    let app_state = test_app_state("").await;
+    let users = UserRepository::new(app_state.db_pool());
+    users.create(1000, "Test", "1000@gg.pl", PASSWORD, false).await.unwrap();
+
+    expect_login_failed(&app_state, 1000).await;
+    users.approve(1000).await.unwrap();
+    let (_client, _handle) = connect(&app_state, 1000).await;
+  }
+
+  #[tokio::test]
+  async fn test_suspended_user_cannot_log_in_until_it_expires() {
+    let app_state = test_app_state("").await;
+    UserRepository::new(app_state.db_pool()).create(1000, "Test", "1000@gg.pl", PASSWORD, true).await.unwrap();
+    let moderation = ModerationRepository::new(app_state.db_pool());
+
+    moderation.apply(1000, ModerationKind::Suspension, Some(Duration::from_secs(3600)), None, "admin").await.unwrap();
+    expect_login_failed(&app_state, 1000).await;
+
+    // Shortened to an already expired one
+    moderation.apply(1000, ModerationKind::Suspension, Some(Duration::ZERO), None, "admin").await.unwrap();
+    let (_client, _handle) = connect(&app_state, 1000).await;
+  }
+
+  #[tokio::test]
+  async fn test_muted_user_logs_in_but_messages_are_not_delivered() {
+    let app_state = test_app_state("").await;
+    let (_recipient, _recipient_handle) = login(&app_state, 2000).await;
+    let (mut client, _handle) = login(&app_state, 1000).await;
+    ModerationRepository::new(app_state.db_pool()).apply(1000, ModerationKind::Mute, None, None, "admin").await.unwrap();
+
+    client.send(GGPacket::SendMessage(GGSendMessage {
+      recipient: 2000,
+      seq: 7,
+      class: GGMessageClass::Chat,
+      message: "Hej".to_string(),
+      formatting: None,
+    })).await.unwrap();
+    assert_eq!(
+      client.next().await.unwrap().unwrap(),
+      GGPacket::SendMessageAck(GGSendMessageAck { seq: 7, status: AckStatus::NotDelivered, recipient: 2000 })
+    );
+  }
+
+  fn chat(recipient: GGNumber, seq: u32) -> GGPacket {
+    GGPacket::SendMessage(GGSendMessage {
+      recipient,
+      seq,
+      class: GGMessageClass::Chat,
+      message: "Hej".to_string(),
+      formatting: None,
+    })
+  }
+
+  async fn expect_ack(client: &mut Client, seq: u32, status: AckStatus) {
+    match client.next().await {
+      Some(Ok(GGPacket::SendMessageAck(ack))) => assert_eq!((ack.seq, ack.status), (seq, status)),
+      other => panic!("expected ack, got {:?}", other),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_flood_offender_is_disconnected() {
+    let app_state = test_app_state("[flood]\nmessages_per_second = 1\noffender_violations = 2\noffender_action = \"disconnect\"").await;
+    UserRepository::new(app_state.db_pool()).create(2000, "Ola", "2000@gg.pl", PASSWORD, true).await.unwrap();
+    let (mut client, handle) = login(&app_state, 1000).await;
+
+    client.send(chat(2000, 1)).await.unwrap();
+    expect_ack(&mut client, 1, AckStatus::Queued).await;
+    client.send(chat(2000, 2)).await.unwrap();
+    expect_ack(&mut client, 2, AckStatus::NotDelivered).await;
+    client.send(chat(2000, 3)).await.unwrap();
+    expect_ack(&mut client, 3, AckStatus::NotDelivered).await;
+    expect_disconnect(&mut client).await;
+    handle.await.unwrap();
+
+    let pending = MessageRepository::new(app_state.db_pool()).find_pending(2000).await.unwrap().unwrap();
+    assert_eq!(pending.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_flood_bytes_are_counted_in_cp1250() {
+    let app_state = test_app_state("[flood]\nbytes_per_minute = 10").await;
+    UserRepository::new(app_state.db_pool()).create(2000, "Ola", "2000@gg.pl", PASSWORD, true).await.unwrap();
+    let (mut client, _handle) = login(&app_state, 1000).await;
+
+    // 9 bytes in CP1250, 17 in UTF-8
+    client.send(GGPacket::SendMessage(GGSendMessage {
+      recipient: 2000,
+      seq: 1,
+      class: GGMessageClass::Chat,
+      message: "żółć żółć".to_string(),
+      formatting: None,
+    })).await.unwrap();
+    expect_ack(&mut client, 1, AckStatus::Queued).await;
+  }
+
+  #[tokio::test]
+  async fn test_flood_offender_is_muted() {
+    let app_state = test_app_state("[flood]\nmessages_per_second = 1\noffender_violations = 1\noffender_action = \"mute\"").await;
+    UserRepository::new(app_state.db_pool()).create(2000, "Ola", "2000@gg.pl", PASSWORD, true).await.unwrap();
+    let (mut client, _handle) = login(&app_state, 1000).await;
+
+    client.send(chat(2000, 1)).await.unwrap();
+    expect_ack(&mut client, 1, AckStatus::Queued).await;
+    client.send(chat(2000, 2)).await.unwrap();
+    expect_ack(&mut client, 2, AckStatus::NotDelivered).await;
+
+    // Within the limits again, but muted now
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    client.send(chat(2000, 3)).await.unwrap();
+    expect_ack(&mut client, 3, AckStatus::NotDelivered).await;
+    assert!(ModerationRepository::new(app_state.db_pool()).is_muted(1000).await.unwrap());
+  }
+}
@@ -0,0 +1,101 @@
+//! Identity of a single login.
+//!
+//! A UIN can log in again before its previous connection is cleaned up. Every
+//! login gets a fresh generation, and both `SessionRegistry` and `PresenceHub`
+//! only let a session update or unregister its own registration, so a stale
+//! session finishing late can't take the newer one down with it.
+//!
+//! What happens when a UIN logs in twice is set in the config:
+//!
+//! ```toml
+//! [session]
+//! multi_login = "kick"   # "kick", "reject" or "allow"
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+use gg_protocol::GGNumber;
+
+/// Policy for a UIN logging in while it already has a session.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MultiLogin {
+  /// The new login disconnects the old session.
+  #[default]
+  Kick,
+  /// The new login fails while the old session is alive.
+  Reject,
+  /// Sessions live side by side, messages reach all of them and contacts
+  /// see the most available status.
+  Allow,
+}
+
+/// What a registry does with a new session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Takeover {
+  /// Drop the existing sessions, the new one takes over.
+  Replace,
+  /// Keep the existing sessions and add the new one.
+  Join,
+  /// Keep the existing sessions, the new one may not register.
+  Refuse,
+}
+
+impl MultiLogin {
+  /// Decide what happens when `session` registers next to `existing` sessions
+  /// of the same UIN. The winner is the same however registrations
+  /// interleave: the newest login for `Kick`, the oldest for `Reject`.
+  pub fn takeover<'a>(&self, session: &SessionId, mut existing: impl Iterator<Item = &'a SessionId>) -> Takeover {
+    match self {
+      MultiLogin::Kick if existing.any(|e| e.is_newer_than(session)) => Takeover::Refuse,
+      MultiLogin::Reject if existing.any(|e| session.is_newer_than(e)) => Takeover::Refuse,
+      MultiLogin::Kick | MultiLogin::Reject => Takeover::Replace,
+      MultiLogin::Allow => Takeover::Join,
+    }
+  }
+}
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId {
+  pub uin: GGNumber,
+  generation: u64,
+}
+
+impl SessionId {
+  /// Id for a new login of `uin`, newer than every id created before it.
+  pub fn new(uin: GGNumber) -> Self {
+    Self { uin, generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed) }
+  }
+
+  /// Whether this login came after `other`.
+  pub fn is_newer_than(&self, other: &SessionId) -> bool {
+    self.generation > other.generation
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_takeover_follows_policy() {
+    let first = SessionId::new(1000);
+    let second = SessionId::new(1000);
+
+    // Second login arriving while the first is registered
+    assert_eq!(MultiLogin::Kick.takeover(&second, [first].iter()), Takeover::Replace);
+    assert_eq!(MultiLogin::Reject.takeover(&second, [first].iter()), Takeover::Refuse);
+    assert_eq!(MultiLogin::Allow.takeover(&second, [first].iter()), Takeover::Join);
+
+    // First login registering late, after the second one got in
+    assert_eq!(MultiLogin::Kick.takeover(&first, [second].iter()), Takeover::Refuse);
+    assert_eq!(MultiLogin::Reject.takeover(&first, [second].iter()), Takeover::Replace);
+    assert_eq!(MultiLogin::Allow.takeover(&first, [second].iter()), Takeover::Join);
+
+    for policy in [MultiLogin::Kick, MultiLogin::Reject] {
+      assert_eq!(policy.takeover(&first, [].iter()), Takeover::Replace);
+    }
+  }
+}
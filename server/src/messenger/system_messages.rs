@@ -0,0 +1,53 @@
+//! Sends newly published system messages to everyone online as a message from UIN 0.
+
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use crate::core::{poll_every, AppState, SharedAppState};
+use crate::messenger::SessionEvent;
+use crate::models::{RepositoryError, SystemMessageRepository};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Broadcast new system messages until `shutdown` is cancelled.
+pub async fn system_message_broadcaster(shutdown: CancellationToken, app_state: SharedAppState) {
+  poll_every(POLL_INTERVAL, shutdown, || async {
+    if let Err(e) = broadcast_pending(&app_state).await {
+      tracing::error!("Failed to broadcast system messages: {}", e);
+    }
+  }).await
+}
+
+/// Send the messages that weren't broadcast yet. Returns how many were sent.
+pub async fn broadcast_pending(app_state: &AppState) -> Result<usize, RepositoryError> {
+  let messages = SystemMessageRepository::new(app_state.db_pool());
+  let pending = messages.find_not_broadcast().await?;
+
+  for message in &pending {
+    // Marked first, a failure must not send the same message twice
+    messages.mark_broadcast(message.id).await?;
+    let sessions = app_state.session_registry().broadcast(SessionEvent::SystemNotice(message.body.clone()));
+    tracing::info!(id = message.id, sessions, "System message broadcast");
+  }
+
+  Ok(pending.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio_stream::StreamExt;
+  use crate::core::test_app_state;
+  use crate::messenger::{MultiLogin, SessionId};
+
+  #[tokio::test]
+  async fn test_published_message_reaches_online_sessions_once() {
+    let app_state = test_app_state("").await;
+    let mut events = app_state.session_registry().register(SessionId::new(1000), MultiLogin::Kick).unwrap();
+
+    SystemMessageRepository::new(app_state.db_pool()).publish("Przerwa techniczna o 22:00").await.unwrap();
+
+    assert_eq!(broadcast_pending(&app_state).await.unwrap(), 1);
+    assert_eq!(events.next().await, Some(SessionEvent::SystemNotice("Przerwa techniczna o 22:00".to_string())));
+    assert_eq!(broadcast_pending(&app_state).await.unwrap(), 0);
+  }
+}
@@ -0,0 +1,189 @@
+//! Auto-reply model and repository.
+
+use sqlx::{Pool, Sqlite, FromRow};
+use tracing::{info, instrument};
+use gg_protocol::{cp1250_len, GGNumber};
+use gg_protocol::consts::{GGStatus, GG_MAX_MESSAGE_LENGTH};
+use crate::models::RepositoryError;
+
+/// Message sent back to people writing to a user who is away.
+#[derive(Debug, Clone, FromRow)]
+pub struct AutoReply {
+  /// User the auto-reply belongs to.
+  pub uin: GGNumber,
+  /// Text of the reply.
+  pub message: String,
+  /// Reply while the user is offline (or invisible, which looks the same).
+  pub when_offline: bool,
+  /// Reply while the user is busy with a description.
+  pub when_busy: bool,
+  /// When the auto-reply was last changed.
+  pub updated_at: Option<String>,
+}
+
+impl AutoReply {
+  /// Check if `message` can be an auto-reply: not blank and not longer
+  /// than a GG message.
+  pub fn is_valid_message(message: &str) -> bool {
+    !message.trim().is_empty() && cp1250_len(message) <= GG_MAX_MESSAGE_LENGTH
+  }
+
+  /// Check if the reply goes out while the user has `status`.
+  pub fn applies_to(&self, status: GGStatus) -> bool {
+    match status {
+      GGStatus::NotAvail | GGStatus::NotAvailDescr | GGStatus::Invisible | GGStatus::InvisibleDescr => self.when_offline,
+      GGStatus::BusyDescr => self.when_busy,
+      _ => false,
+    }
+  }
+}
+
+/// Repository for auto-reply database operations.
+#[derive(Clone)]
+pub struct AutoReplyRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for AutoReplyRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("AutoReplyRepository").finish()
+  }
+}
+
+impl AutoReplyRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Set the auto-reply of a user, replacing the previous one.
+  #[instrument(skip(self, message))]
+  pub async fn set(
+    &self,
+    uin: GGNumber,
+    message: &str,
+    when_offline: bool,
+    when_busy: bool,
+  ) -> Result<AutoReply, RepositoryError> {
+    let auto_reply = sqlx::query_as::<_, AutoReply>(
+      "INSERT INTO auto_replies (uin, message, when_offline, when_busy) \
+       VALUES (?, ?, ?, ?) \
+       ON CONFLICT (uin) DO UPDATE SET \
+         message = excluded.message, when_offline = excluded.when_offline, \
+         when_busy = excluded.when_busy, updated_at = CURRENT_TIMESTAMP \
+       RETURNING *"
+    )
+      .bind(uin)
+      .bind(message)
+      .bind(when_offline)
+      .bind(when_busy)
+      .fetch_one(&self.pool)
+      .await?;
+
+    info!("Auto-reply set");
+    Ok(auto_reply)
+  }
+
+  /// Find the auto-reply of a user.
+  #[instrument(skip(self))]
+  pub async fn find(&self, uin: GGNumber) -> Result<Option<AutoReply>, RepositoryError> {
+    let auto_reply = sqlx::query_as::<_, AutoReply>("SELECT * FROM auto_replies WHERE uin = ?")
+      .bind(uin)
+      .fetch_optional(&self.pool)
+      .await?;
+
+    Ok(auto_reply)
+  }
+
+  /// List every auto-reply, by UIN.
+  #[instrument(skip(self))]
+  pub async fn list(&self) -> Result<Vec<AutoReply>, RepositoryError> {
+    let auto_replies = sqlx::query_as::<_, AutoReply>("SELECT * FROM auto_replies ORDER BY uin")
+      .fetch_all(&self.pool)
+      .await?;
+
+    Ok(auto_replies)
+  }
+
+  /// Turn off the auto-reply of a user. Returns false if there was none.
+  #[instrument(skip(self))]
+  pub async fn delete(&self, uin: GGNumber) -> Result<bool, RepositoryError> {
+    let result = sqlx::query("DELETE FROM auto_replies WHERE uin = ?")
+      .bind(uin)
+      .execute(&self.pool)
+      .await?;
+
+    let deleted = result.rows_affected() > 0;
+    if deleted {
+      info!("Auto-reply removed");
+    }
+    Ok(deleted)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_set_replaces_previous_auto_reply() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = AutoReplyRepository::new(&pool);
+
+    repo.set(1000, "Jestem na urlopie", true, true).await.unwrap();
+    let auto_reply = repo.set(1000, "Wracam w poniedziałek", true, false).await.unwrap();
+    assert_eq!(auto_reply.message, "Wracam w poniedziałek");
+
+    let found = repo.find(1000).await.unwrap().unwrap();
+    assert_eq!(found.message, "Wracam w poniedziałek");
+    assert!(found.when_offline);
+    assert!(!found.when_busy);
+    assert_eq!(repo.list().await.unwrap().len(), 1);
+
+    assert!(repo.delete(1000).await.unwrap());
+    assert!(!repo.delete(1000).await.unwrap());
+    assert!(repo.find(1000).await.unwrap().is_none());
+  }
+
+  #[test]
+  fn test_applies_to_status() {
+    let auto_reply = |when_offline, when_busy| AutoReply {
+      uin: 1000,
+      message: "Nie ma mnie".to_string(),
+      when_offline,
+      when_busy,
+      updated_at: None,
+    };
+
+    let offline = auto_reply(true, false);
+    assert!(offline.applies_to(GGStatus::NotAvail));
+    assert!(offline.applies_to(GGStatus::InvisibleDescr));
+    assert!(!offline.applies_to(GGStatus::BusyDescr));
+
+    let busy = auto_reply(false, true);
+    assert!(busy.applies_to(GGStatus::BusyDescr));
+    assert!(!busy.applies_to(GGStatus::Busy));
+    assert!(!busy.applies_to(GGStatus::NotAvailDescr));
+
+    assert!(!auto_reply(true, true).applies_to(GGStatus::Avail));
+  }
+
+  #[test]
+  fn test_is_valid_message() {
+    assert!(AutoReply::is_valid_message("Nie ma mnie"));
+    assert!(AutoReply::is_valid_message(&"ż".repeat(GG_MAX_MESSAGE_LENGTH)));
+    assert!(!AutoReply::is_valid_message(&"ż".repeat(GG_MAX_MESSAGE_LENGTH + 1)));
+    assert!(!AutoReply::is_valid_message(" \n"));
+  }
+}
@@ -0,0 +1,129 @@
+//! Email verification token model and repository.
+
+use rand::Rng;
+use sqlx::{Pool, Sqlite, FromRow};
+use tracing::{info, instrument};
+use gg_protocol::GGNumber;
+use crate::models::RepositoryError;
+
+/// Verification link expiration time in hours.
+const VERIFICATION_EXPIRY_HOURS: i32 = 48;
+
+/// Email verification record from database.
+#[derive(Debug, Clone, FromRow)]
+pub struct EmailVerification {
+  /// Unique token sent in the verification link (32 chars).
+  pub token: String,
+}
+
+/// Repository for email verification database operations.
+#[derive(Clone)]
+pub struct EmailVerificationRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for EmailVerificationRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("EmailVerificationRepository").finish()
+  }
+}
+
+impl EmailVerificationRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Create a new verification token for the user.
+  #[instrument(skip(self))]
+  pub async fn create(&self, uin: GGNumber) -> Result<EmailVerification, RepositoryError> {
+    let token: String = rand::rng()
+      .sample_iter(rand::distr::Alphanumeric)
+      .take(32)
+      .map(char::from)
+      .collect();
+
+    let result = sqlx::query_as::<_, EmailVerification>(
+      "INSERT INTO email_verifications (uin, token) VALUES (?, ?) RETURNING *"
+    )
+      .bind(uin)
+      .bind(token)
+      .fetch_one(&self.pool)
+      .await?;
+
+    info!(uin = uin, "Email verification created");
+    Ok(result)
+  }
+
+  /// Consume a verification token and mark the user's email as verified.
+  /// Returns the verified UIN, or None if the token is unknown, used or expired.
+  #[instrument(skip(self))]
+  pub async fn verify(&self, token: &str) -> Result<Option<GGNumber>, RepositoryError> {
+    let mut tx = self.pool.begin().await?;
+
+    let uin: Option<(u32,)> = sqlx::query_as(
+      "UPDATE email_verifications SET used_at = CURRENT_TIMESTAMP \
+       WHERE token = ? AND used_at IS NULL \
+       AND datetime(created_at, '+' || ? || ' hours') > datetime('now') \
+       RETURNING uin"
+    )
+      .bind(token)
+      .bind(VERIFICATION_EXPIRY_HOURS)
+      .fetch_optional(&mut *tx)
+      .await?;
+
+    if let Some((uin,)) = uin {
+      sqlx::query("UPDATE users SET email_verified_at = CURRENT_TIMESTAMP WHERE uin = ? AND email_verified_at IS NULL")
+        .bind(uin)
+        .execute(&mut *tx)
+        .await?;
+      tx.commit().await?;
+
+      info!(uin = uin, "Email verified");
+      return Ok(Some(uin))
+    }
+
+    Ok(None)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+  use crate::models::UserRepository;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_verify_marks_email_verified_once() {
+    let pool = setup_test_db().await.unwrap();
+    let users = UserRepository::new(&pool);
+    let repo = EmailVerificationRepository::new(&pool);
+
+    let user = users.create(1_000_000, "Test", "test@gg.pl", "hash123", true).await.unwrap();
+    assert!(!user.is_email_verified());
+
+    let verification = repo.create(user.uin).await.unwrap();
+    assert_eq!(repo.verify(&verification.token).await.unwrap(), Some(user.uin));
+    assert!(users.find_by_uin(user.uin).await.unwrap().unwrap().is_email_verified());
+
+    assert_eq!(repo.verify(&verification.token).await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn test_verify_unknown_token() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = EmailVerificationRepository::new(&pool);
+
+    assert_eq!(repo.verify("nonexistent").await.unwrap(), None);
+  }
+}
@@ -0,0 +1,173 @@
+//! Invite code model and repository.
+
+use rand::Rng;
+use sqlx::{Pool, Sqlite, FromRow};
+use tracing::{info, instrument};
+use gg_protocol::GGNumber;
+use crate::models::RepositoryError;
+
+/// Length of generated invite codes.
+const INVITE_CODE_LENGTH: usize = 8;
+
+/// Invite code record from database.
+#[derive(Debug, Clone, FromRow)]
+pub struct Invite {
+  /// Code the user has to enter during registration.
+  pub code: String,
+  /// Invite creation timestamp.
+  pub created_at: Option<String>,
+  /// When the invite was used (NULL if unused).
+  pub used_at: Option<String>,
+  /// Account registered with this invite.
+  pub used_by_uin: Option<u32>,
+}
+
+/// Repository for invite database operations.
+#[derive(Clone)]
+pub struct InviteRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for InviteRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("InviteRepository").finish()
+  }
+}
+
+impl InviteRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Create a new invite with a random code.
+  #[instrument(skip(self))]
+  pub async fn create(&self) -> Result<Invite, RepositoryError> {
+    let code: String = rand::rng()
+      .sample_iter(rand::distr::Alphanumeric)
+      .take(INVITE_CODE_LENGTH)
+      .map(char::from)
+      .map(|c: char| c.to_ascii_uppercase())
+      .collect();
+
+    let invite = sqlx::query_as::<_, Invite>(
+      "INSERT INTO invites (code) VALUES (?) RETURNING *"
+    )
+      .bind(&code)
+      .fetch_one(&self.pool)
+      .await?;
+
+    info!(code = code, "Invite created");
+    Ok(invite)
+  }
+
+  /// List every invite, oldest first.
+  #[instrument(skip(self))]
+  pub async fn list(&self) -> Result<Vec<Invite>, RepositoryError> {
+    let invites = sqlx::query_as::<_, Invite>("SELECT * FROM invites ORDER BY id")
+      .fetch_all(&self.pool)
+      .await?;
+    Ok(invites)
+  }
+
+  /// Mark an unused invite as used. Returns true if the invite was claimed.
+  #[instrument(skip(self))]
+  pub async fn claim(&self, code: &str) -> Result<bool, RepositoryError> {
+    let result = sqlx::query(
+      "UPDATE invites SET used_at = CURRENT_TIMESTAMP WHERE code = ? COLLATE NOCASE AND used_at IS NULL"
+    )
+      .bind(code.trim())
+      .execute(&self.pool)
+      .await?;
+
+    let claimed = result.rows_affected() > 0;
+    if claimed {
+      info!(code = code, "Invite claimed");
+    }
+    Ok(claimed)
+  }
+
+  /// Record which account was registered with a claimed invite.
+  #[instrument(skip(self))]
+  pub async fn assign(&self, code: &str, uin: GGNumber) -> Result<(), RepositoryError> {
+    sqlx::query("UPDATE invites SET used_by_uin = ? WHERE code = ? COLLATE NOCASE")
+      .bind(uin)
+      .bind(code.trim())
+      .execute(&self.pool)
+      .await?;
+    Ok(())
+  }
+
+  /// Make a claimed invite usable again, e.g. when account creation failed.
+  #[instrument(skip(self))]
+  pub async fn release(&self, code: &str) -> Result<(), RepositoryError> {
+    sqlx::query("UPDATE invites SET used_at = NULL WHERE code = ? COLLATE NOCASE AND used_by_uin IS NULL")
+      .bind(code.trim())
+      .execute(&self.pool)
+      .await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_invite_can_be_claimed_once() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = InviteRepository::new(&pool);
+
+    let invite = repo.create().await.unwrap();
+    assert_eq!(invite.code.len(), INVITE_CODE_LENGTH);
+
+    assert!(repo.claim(&invite.code.to_lowercase()).await.unwrap());
+    assert!(!repo.claim(&invite.code).await.unwrap());
+    assert!(!repo.claim("NOPE").await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_released_invite_can_be_claimed_again() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = InviteRepository::new(&pool);
+
+    let invite = repo.create().await.unwrap();
+    assert!(repo.claim(&invite.code).await.unwrap());
+    repo.release(&invite.code).await.unwrap();
+    assert!(repo.claim(&invite.code).await.unwrap());
+
+    repo.assign(&invite.code, 1_000_001).await.unwrap();
+    repo.release(&invite.code).await.unwrap();
+    assert!(!repo.claim(&invite.code).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_list_shows_who_used_invites() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = InviteRepository::new(&pool);
+
+    let used = repo.create().await.unwrap();
+    let unused = repo.create().await.unwrap();
+    assert!(repo.claim(&used.code).await.unwrap());
+    repo.assign(&used.code, 1_000_001).await.unwrap();
+
+    let invites = repo.list().await.unwrap();
+    assert_eq!(invites.len(), 2);
+    assert_eq!(invites[0].code, used.code);
+    assert!(invites[0].used_at.is_some());
+    assert_eq!(invites[0].used_by_uin, Some(1_000_001));
+    assert_eq!(invites[1].code, unused.code);
+    assert!(invites[1].used_at.is_none());
+  }
+}
@@ -0,0 +1,109 @@
+//! Banned IP address model and repository.
+
+use std::net::IpAddr;
+use sqlx::{Pool, Sqlite, FromRow};
+use tracing::{info, instrument};
+use crate::models::RepositoryError;
+
+/// Banned address record from database.
+#[derive(Debug, Clone, FromRow)]
+pub struct IpBan {
+  /// Banned address.
+  pub ip: String,
+  /// Why the address was banned.
+  pub reason: Option<String>,
+  /// When the ban was added.
+  pub created_at: Option<String>,
+}
+
+/// Repository for IP ban database operations.
+#[derive(Clone)]
+pub struct IpBanRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for IpBanRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("IpBanRepository").finish()
+  }
+}
+
+impl IpBanRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Ban an address. Returns false if it was already banned.
+  #[instrument(skip(self))]
+  pub async fn ban(&self, ip: IpAddr, reason: Option<&str>) -> Result<bool, RepositoryError> {
+    let result = sqlx::query("INSERT INTO ip_bans (ip, reason) VALUES (?, ?) ON CONFLICT (ip) DO NOTHING")
+      .bind(ip.to_canonical().to_string())
+      .bind(reason)
+      .execute(&self.pool)
+      .await?;
+
+    let banned = result.rows_affected() > 0;
+    if banned {
+      info!("IP banned");
+    }
+    Ok(banned)
+  }
+
+  /// Lift a ban. Returns false if the address wasn't banned.
+  #[instrument(skip(self))]
+  pub async fn unban(&self, ip: IpAddr) -> Result<bool, RepositoryError> {
+    let result = sqlx::query("DELETE FROM ip_bans WHERE ip = ?")
+      .bind(ip.to_canonical().to_string())
+      .execute(&self.pool)
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// List all bans, oldest first.
+  #[instrument(skip(self))]
+  pub async fn list(&self) -> Result<Vec<IpBan>, RepositoryError> {
+    let bans = sqlx::query_as::<_, IpBan>("SELECT * FROM ip_bans ORDER BY created_at, ip")
+      .fetch_all(&self.pool)
+      .await?;
+
+    Ok(bans)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_ban_and_unban() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = IpBanRepository::new(&pool);
+    let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+    assert!(repo.ban(ip, Some("flood")).await.unwrap());
+    // Same address written as IPv4-mapped IPv6
+    assert!(!repo.ban("::ffff:203.0.113.7".parse().unwrap(), None).await.unwrap());
+
+    let bans = repo.list().await.unwrap();
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].ip, "203.0.113.7");
+    assert_eq!(bans[0].reason.as_deref(), Some("flood"));
+
+    assert!(repo.unban(ip).await.unwrap());
+    assert!(!repo.unban(ip).await.unwrap());
+    assert!(repo.list().await.unwrap().is_empty());
+  }
+}
@@ -34,21 +34,21 @@ pub struct QueuedMessage {
   pub delivered_at: Option<String>,
 }
 
-impl Into<GGRecvMessage> for QueuedMessage {
-  fn into(self) -> GGRecvMessage {
+impl From<QueuedMessage> for GGRecvMessage {
+  fn from(val: QueuedMessage) -> Self {
     // Deserialize formatting from raw bytes using TryFrom
-    let formatting = self.formatting
+    let formatting = val.formatting
       .as_deref()
       .and_then(|data| RichTextFormats::try_from(data).ok())
       .map(|f| f.0)
       .filter(|f| !f.is_empty());
 
     GGRecvMessage {
-      sender: self.sender_uin,
-      seq: self.seq,
-      time: self.time,
+      sender: val.sender_uin,
+      seq: val.seq,
+      time: val.time,
       class: GGMessageClass::Queued,
-      message: self.message,
+      message: val.message,
       formatting,
     }
   }
@@ -127,7 +127,7 @@ impl MessageRepository {
 
     info!(count = messages.len(), recipient = recipient, "Found pending messages");
 
-    if messages.len() > 0 {
+    if !messages.is_empty() {
       Ok(Some(messages))
     } else {
       Ok(None)
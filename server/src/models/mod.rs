@@ -5,12 +5,28 @@ use axum::response::{IntoResponse, Response};
 use sqlx::{Pool, Sqlite};
 use thiserror::Error;
 
+pub mod auto_reply;
+pub mod email_verification;
+pub mod invite;
+pub mod ip_ban;
 pub mod message;
+pub mod moderation;
+pub mod motd;
+pub mod presence;
+pub mod system_message;
 pub mod token;
 pub mod user;
 
 pub type DatabasePool = Pool<Sqlite>;
+pub use auto_reply::{AutoReply, AutoReplyRepository};
+pub use email_verification::EmailVerificationRepository;
+pub use invite::InviteRepository;
+pub use ip_ban::IpBanRepository;
 pub use message::{QueuedMessageId, MessageRepository};
+pub use moderation::{ModerationKind, ModerationRepository};
+pub use motd::MotdRepository;
+pub use presence::{PresenceRepository, StoredPresence};
+pub use system_message::{SystemMessage, SystemMessageId, SystemMessageRepository};
 pub use token::TokenRepository;
 pub use user::UserRepository;
 
@@ -20,6 +36,16 @@ pub enum RepositoryError {
   DatabaseError(#[from] sqlx::Error)
 }
 
+impl RepositoryError {
+  /// Returns true if the query failed on a UNIQUE constraint, e.g. a duplicate email.
+  pub fn is_unique_violation(&self) -> bool {
+    match self {
+      RepositoryError::DatabaseError(sqlx::Error::Database(error)) => error.is_unique_violation(),
+      _ => false,
+    }
+  }
+}
+
 impl IntoResponse for RepositoryError {
   fn into_response(self) -> Response {
     match self {
@@ -0,0 +1,255 @@
+//! User moderation model and repository.
+
+use std::time::Duration;
+use sqlx::{Pool, Sqlite, FromRow};
+use tracing::{info, instrument};
+use gg_protocol::GGNumber;
+use crate::models::RepositoryError;
+
+/// What a moderation entry does to the account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub enum ModerationKind {
+  /// Can't log in until the ban is lifted.
+  Ban,
+  /// Can't log in until the entry expires or is lifted.
+  Suspension,
+  /// Can log in, but messages sent by the user are not delivered.
+  Mute,
+}
+
+impl std::fmt::Display for ModerationKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ModerationKind::Ban => write!(f, "ban"),
+      ModerationKind::Suspension => write!(f, "suspension"),
+      ModerationKind::Mute => write!(f, "mute"),
+    }
+  }
+}
+
+/// Moderation entry from database.
+#[derive(Debug, Clone, FromRow)]
+pub struct Moderation {
+  /// Moderated account.
+  pub uin: GGNumber,
+  /// Ban, suspension or mute.
+  pub kind: ModerationKind,
+  /// Why the account was moderated.
+  pub reason: Option<String>,
+  /// Who added the entry.
+  pub moderator: String,
+  /// When the entry expires (NULL if it lasts until lifted).
+  pub until: Option<String>,
+  /// When the entry was added.
+  pub created_at: Option<String>,
+}
+
+/// Entries still in force, expired suspensions are kept but ignored.
+const ACTIVE: &str = "(until IS NULL OR until > CURRENT_TIMESTAMP)";
+
+/// Repository for user moderation database operations.
+#[derive(Clone)]
+pub struct ModerationRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for ModerationRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("ModerationRepository").finish()
+  }
+}
+
+impl ModerationRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Add an entry, or replace the one of the same kind. Entries with a
+  /// duration expire on their own, the others last until lifted.
+  #[instrument(skip(self))]
+  pub async fn apply(
+    &self,
+    uin: GGNumber,
+    kind: ModerationKind,
+    duration: Option<Duration>,
+    reason: Option<&str>,
+    moderator: &str
+  ) -> Result<Moderation, RepositoryError> {
+    let entry = sqlx::query_as::<_, Moderation>(
+      "INSERT INTO user_moderation (uin, kind, reason, moderator, until) \
+       VALUES (?, ?, ?, ?, datetime('now', ?)) \
+       ON CONFLICT (uin, kind) DO UPDATE SET \
+         reason = excluded.reason, moderator = excluded.moderator, until = excluded.until, \
+         created_at = CURRENT_TIMESTAMP, enforced_at = NULL \
+       RETURNING *"
+    )
+      .bind(uin)
+      .bind(kind)
+      .bind(reason)
+      .bind(moderator)
+      .bind(duration.map(|d| format!("+{} seconds", d.as_secs())))
+      .fetch_one(&self.pool)
+      .await?;
+
+    info!(until = ?entry.until, "User moderated");
+    Ok(entry)
+  }
+
+  /// Remove an entry. Returns false if there was none.
+  #[instrument(skip(self))]
+  pub async fn lift(&self, uin: GGNumber, kind: ModerationKind) -> Result<bool, RepositoryError> {
+    let result = sqlx::query("DELETE FROM user_moderation WHERE uin = ? AND kind = ?")
+      .bind(uin)
+      .bind(kind)
+      .execute(&self.pool)
+      .await?;
+
+    let lifted = result.rows_affected() > 0;
+    if lifted {
+      info!("User moderation lifted");
+    }
+    Ok(lifted)
+  }
+
+  /// Find the active ban or suspension that keeps the account from logging in.
+  #[instrument(skip(self))]
+  pub async fn find_login_block(&self, uin: GGNumber) -> Result<Option<Moderation>, RepositoryError> {
+    let query = format!(
+      "SELECT * FROM user_moderation WHERE uin = ? AND kind IN ('ban', 'suspension') AND {} \
+       ORDER BY kind LIMIT 1",
+      ACTIVE
+    );
+    let entry = sqlx::query_as::<_, Moderation>(&query)
+      .bind(uin)
+      .fetch_optional(&self.pool)
+      .await?;
+
+    Ok(entry)
+  }
+
+  /// Check if messages from the account are dropped.
+  #[instrument(skip(self))]
+  pub async fn is_muted(&self, uin: GGNumber) -> Result<bool, RepositoryError> {
+    let query = format!("SELECT 1 FROM user_moderation WHERE uin = ? AND kind = 'mute' AND {}", ACTIVE);
+    let result: Option<(i32,)> = sqlx::query_as(&query)
+      .bind(uin)
+      .fetch_optional(&self.pool)
+      .await?;
+
+    Ok(result.is_some())
+  }
+
+  /// Find active bans and suspensions whose account wasn't kicked yet.
+  #[instrument(skip(self))]
+  pub async fn find_not_enforced(&self) -> Result<Vec<Moderation>, RepositoryError> {
+    let query = format!(
+      "SELECT * FROM user_moderation WHERE enforced_at IS NULL AND kind IN ('ban', 'suspension') AND {} \
+       ORDER BY created_at, uin",
+      ACTIVE
+    );
+    let entries = sqlx::query_as::<_, Moderation>(&query)
+      .fetch_all(&self.pool)
+      .await?;
+
+    Ok(entries)
+  }
+
+  /// Remember that live sessions were kicked for an entry.
+  #[instrument(skip(self))]
+  pub async fn mark_enforced(&self, uin: GGNumber, kind: ModerationKind) -> Result<(), RepositoryError> {
+    sqlx::query("UPDATE user_moderation SET enforced_at = CURRENT_TIMESTAMP WHERE uin = ? AND kind = ?")
+      .bind(uin)
+      .bind(kind)
+      .execute(&self.pool)
+      .await?;
+
+    Ok(())
+  }
+
+  /// List active entries, oldest first.
+  #[instrument(skip(self))]
+  pub async fn list(&self) -> Result<Vec<Moderation>, RepositoryError> {
+    let query = format!("SELECT * FROM user_moderation WHERE {} ORDER BY created_at, uin", ACTIVE);
+    let entries = sqlx::query_as::<_, Moderation>(&query)
+      .fetch_all(&self.pool)
+      .await?;
+
+    Ok(entries)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_ban_suspend_and_mute() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = ModerationRepository::new(&pool);
+
+    repo.apply(1000, ModerationKind::Mute, None, Some("spam"), "admin").await.unwrap();
+    assert!(repo.is_muted(1000).await.unwrap());
+    assert!(repo.find_login_block(1000).await.unwrap().is_none());
+
+    let ban = repo.apply(1000, ModerationKind::Ban, None, Some("abuse"), "admin").await.unwrap();
+    assert!(ban.until.is_none());
+    let block = repo.find_login_block(1000).await.unwrap().unwrap();
+    assert_eq!(block.kind, ModerationKind::Ban);
+    assert_eq!(block.reason.as_deref(), Some("abuse"));
+    assert_eq!(block.moderator, "admin");
+
+    let suspension = repo.apply(2000, ModerationKind::Suspension, Some(Duration::from_secs(3600)), None, "ola").await.unwrap();
+    assert!(suspension.until.is_some());
+    assert_eq!(repo.find_login_block(2000).await.unwrap().unwrap().kind, ModerationKind::Suspension);
+    assert_eq!(repo.list().await.unwrap().len(), 3);
+
+    assert!(repo.lift(1000, ModerationKind::Ban).await.unwrap());
+    assert!(!repo.lift(1000, ModerationKind::Ban).await.unwrap());
+    assert!(repo.find_login_block(1000).await.unwrap().is_none());
+    assert!(repo.is_muted(1000).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_expired_suspension_is_ignored() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = ModerationRepository::new(&pool);
+
+    repo.apply(1000, ModerationKind::Suspension, Some(Duration::ZERO), None, "admin").await.unwrap();
+    assert!(repo.find_login_block(1000).await.unwrap().is_none());
+    assert!(repo.find_not_enforced().await.unwrap().is_empty());
+    assert!(repo.list().await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_entries_are_enforced_once() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = ModerationRepository::new(&pool);
+
+    repo.apply(1000, ModerationKind::Ban, None, None, "admin").await.unwrap();
+    repo.apply(2000, ModerationKind::Mute, None, None, "admin").await.unwrap();
+
+    let pending = repo.find_not_enforced().await.unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].uin, 1000);
+
+    repo.mark_enforced(1000, ModerationKind::Ban).await.unwrap();
+    assert!(repo.find_not_enforced().await.unwrap().is_empty());
+
+    // Banning again kicks again
+    repo.apply(1000, ModerationKind::Ban, None, Some("again"), "admin").await.unwrap();
+    assert_eq!(repo.find_not_enforced().await.unwrap().len(), 1);
+  }
+}
@@ -0,0 +1,103 @@
+//! Message of the day deliveries.
+
+use sqlx::{Pool, Sqlite};
+use tracing::{info, instrument};
+use gg_protocol::GGNumber;
+use crate::models::RepositoryError;
+
+/// Repository tracking which MOTD revision each user has received.
+#[derive(Clone)]
+pub struct MotdRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for MotdRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("MotdRepository").finish()
+  }
+}
+
+impl MotdRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Record that a user gets `revision`. Returns false if they already got
+  /// it or a newer one, so the MOTD is sent at most once per revision.
+  #[instrument(skip(self))]
+  pub async fn claim(&self, uin: GGNumber, revision: u32) -> Result<bool, RepositoryError> {
+    let result = sqlx::query(
+      "INSERT INTO motd_deliveries (uin, revision) VALUES (?, ?) \
+       ON CONFLICT (uin) DO UPDATE SET \
+       revision = excluded.revision, delivered_at = CURRENT_TIMESTAMP \
+       WHERE motd_deliveries.revision < excluded.revision"
+    )
+      .bind(uin)
+      .bind(revision)
+      .execute(&self.pool)
+      .await?;
+
+    let claimed = result.rows_affected() > 0;
+    if claimed {
+      info!("MOTD claimed");
+    }
+    Ok(claimed)
+  }
+
+  /// Undo `claim` when the MOTD couldn't be sent, so the next login tries again.
+  #[instrument(skip(self))]
+  pub async fn release(&self, uin: GGNumber, revision: u32) -> Result<(), RepositoryError> {
+    sqlx::query("DELETE FROM motd_deliveries WHERE uin = ? AND revision = ?")
+      .bind(uin)
+      .bind(revision)
+      .execute(&self.pool)
+      .await?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_each_revision_is_claimed_once() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = MotdRepository::new(&pool);
+
+    assert!(repo.claim(1000, 1).await.unwrap());
+    assert!(!repo.claim(1000, 1).await.unwrap());
+    assert!(repo.claim(2000, 1).await.unwrap());
+
+    assert!(repo.claim(1000, 2).await.unwrap());
+    assert!(!repo.claim(1000, 1).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_released_revision_can_be_claimed_again() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = MotdRepository::new(&pool);
+
+    assert!(repo.claim(1000, 1).await.unwrap());
+    repo.release(1000, 1).await.unwrap();
+    assert!(repo.claim(1000, 1).await.unwrap());
+
+    // Releasing an older revision leaves the newer claim alone
+    assert!(repo.claim(1000, 2).await.unwrap());
+    repo.release(1000, 1).await.unwrap();
+    assert!(!repo.claim(1000, 2).await.unwrap());
+  }
+}
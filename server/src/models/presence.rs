@@ -0,0 +1,136 @@
+//! Persisted presence model and repository.
+
+use sqlx::{Pool, Sqlite, FromRow};
+use tracing::{info, instrument};
+use gg_protocol::GGNumber;
+use gg_protocol::consts::GGStatus;
+use crate::models::RepositoryError;
+
+/// Last known presence of a user.
+#[derive(Debug, Clone, FromRow)]
+pub struct StoredPresence {
+  /// User number.
+  pub uin: GGNumber,
+  /// Status (stored as integer).
+  pub status: u32,
+  /// Status description (NULL if none).
+  pub description: Option<String>,
+  /// Return time from the description (NULL if none).
+  pub time: Option<u32>,
+  /// When the user was last connected.
+  pub last_seen: Option<String>,
+}
+
+/// Repository for persisted presence operations.
+#[derive(Clone)]
+pub struct PresenceRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for PresenceRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("PresenceRepository").finish()
+  }
+}
+
+impl PresenceRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Store the current presence of a user and bump `last_seen`.
+  #[instrument(skip(self))]
+  pub async fn save(
+    &self,
+    uin: GGNumber,
+    status: GGStatus,
+    description: Option<&str>,
+    time: Option<u32>,
+  ) -> Result<(), RepositoryError> {
+    sqlx::query(
+      "INSERT INTO presences (uin, status, description, time, last_seen) \
+       VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP) \
+       ON CONFLICT (uin) DO UPDATE SET \
+       status = excluded.status, description = excluded.description, \
+       time = excluded.time, last_seen = excluded.last_seen"
+    )
+      .bind(uin)
+      .bind(status as u32)
+      .bind(description)
+      .bind(time)
+      .execute(&self.pool)
+      .await?;
+
+    info!("Presence saved");
+    Ok(())
+  }
+
+  /// Find the stored presences of multiple users.
+  #[instrument(skip(self))]
+  pub async fn find_by_uins(&self, uins: &[GGNumber]) -> Result<Vec<StoredPresence>, RepositoryError> {
+    if uins.is_empty() {
+      return Ok(Vec::new());
+    }
+
+    let placeholders = uins.iter()
+      .map(|_| "?")
+      .collect::<Vec<_>>()
+      .join(",");
+
+    let query = format!("SELECT * FROM presences WHERE uin IN ({})", placeholders);
+
+    let mut query_builder = sqlx::query_as::<_, StoredPresence>(&query);
+    for &uin in uins {
+      query_builder = query_builder.bind(uin);
+    }
+
+    let presences = query_builder.fetch_all(&self.pool).await?;
+    Ok(presences)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_save_overwrites_previous_presence() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = PresenceRepository::new(&pool);
+
+    repo.save(1000, GGStatus::BusyDescr, Some("Zajęty"), None).await.unwrap();
+    repo.save(1000, GGStatus::NotAvailDescr, Some("Wracam o 18"), Some(1234567890)).await.unwrap();
+
+    let stored = repo.find_by_uins(&[1000]).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].status, GGStatus::NotAvailDescr as u32);
+    assert_eq!(stored[0].description.as_deref(), Some("Wracam o 18"));
+    assert_eq!(stored[0].time, Some(1234567890));
+    assert!(stored[0].last_seen.is_some());
+  }
+
+  #[tokio::test]
+  async fn test_find_by_uins_skips_unknown_users() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = PresenceRepository::new(&pool);
+
+    repo.save(1000, GGStatus::NotAvail, None, None).await.unwrap();
+    repo.save(2000, GGStatus::NotAvail, None, None).await.unwrap();
+
+    let stored = repo.find_by_uins(&[1000, 3000]).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert!(repo.find_by_uins(&[]).await.unwrap().is_empty());
+  }
+}
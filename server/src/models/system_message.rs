@@ -0,0 +1,168 @@
+//! System message model and repository.
+
+use sqlx::{Pool, Sqlite, FromRow};
+use tracing::{info, instrument};
+use crate::models::RepositoryError;
+
+pub type SystemMessageId = i64;
+
+/// System message record from database.
+#[derive(Debug, Clone, FromRow)]
+pub struct SystemMessage {
+  /// Message number, clients remember the last one they have seen.
+  pub id: SystemMessageId,
+  /// Message content, plain text or HTML.
+  pub body: String,
+  /// When the message was published.
+  pub created_at: Option<String>,
+  /// When the message was sent to online users (NULL if not yet).
+  pub broadcast_at: Option<String>,
+}
+
+/// Repository for system message database operations.
+#[derive(Clone)]
+pub struct SystemMessageRepository {
+  pool: Pool<Sqlite>,
+}
+
+impl std::fmt::Debug for SystemMessageRepository {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("SystemMessageRepository").finish()
+  }
+}
+
+impl SystemMessageRepository {
+  /// Create a new repository with the given database pool.
+  pub fn new(pool: &Pool<Sqlite>) -> Self {
+    Self { pool: pool.clone() }
+  }
+
+  /// Publish a new system message.
+  #[instrument(skip(self))]
+  pub async fn publish(&self, body: &str) -> Result<SystemMessage, RepositoryError> {
+    let message = sqlx::query_as::<_, SystemMessage>(
+      "INSERT INTO system_messages (body) VALUES (?) RETURNING *"
+    )
+      .bind(body)
+      .fetch_one(&self.pool)
+      .await?;
+
+    info!(id = message.id, "System message published");
+    Ok(message)
+  }
+
+  /// Find the newest message a client that has seen `last_seen` doesn't know yet.
+  ///
+  /// Clients remember the last number from whatever server they used before,
+  /// usually far above ours, so a `last_seen` past the newest message counts
+  /// as having seen nothing.
+  #[instrument(skip(self))]
+  pub async fn find_newest_after(&self, last_seen: SystemMessageId) -> Result<Option<SystemMessage>, RepositoryError> {
+    let message = sqlx::query_as::<_, SystemMessage>(
+      "SELECT * FROM system_messages \
+       WHERE id > ? OR ? > (SELECT MAX(id) FROM system_messages) \
+       ORDER BY id DESC LIMIT 1"
+    )
+      .bind(last_seen)
+      .bind(last_seen)
+      .fetch_optional(&self.pool)
+      .await?;
+
+    Ok(message)
+  }
+
+  /// Find messages not yet sent to online users, oldest first.
+  #[instrument(skip(self))]
+  pub async fn find_not_broadcast(&self) -> Result<Vec<SystemMessage>, RepositoryError> {
+    let messages = sqlx::query_as::<_, SystemMessage>(
+      "SELECT * FROM system_messages WHERE broadcast_at IS NULL ORDER BY id"
+    )
+      .fetch_all(&self.pool)
+      .await?;
+
+    Ok(messages)
+  }
+
+  /// Mark a message as sent to online users.
+  #[instrument(skip(self))]
+  pub async fn mark_broadcast(&self, id: SystemMessageId) -> Result<(), RepositoryError> {
+    sqlx::query("UPDATE system_messages SET broadcast_at = CURRENT_TIMESTAMP WHERE id = ?")
+      .bind(id)
+      .execute(&self.pool)
+      .await?;
+
+    Ok(())
+  }
+
+  /// List all system messages, oldest first.
+  #[instrument(skip(self))]
+  pub async fn list(&self) -> Result<Vec<SystemMessage>, RepositoryError> {
+    let messages = sqlx::query_as::<_, SystemMessage>("SELECT * FROM system_messages ORDER BY id")
+      .fetch_all(&self.pool)
+      .await?;
+
+    Ok(messages)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  #[tokio::test]
+  async fn test_newest_message_after_last_seen() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = SystemMessageRepository::new(&pool);
+
+    assert!(repo.find_newest_after(0).await.unwrap().is_none());
+
+    let first = repo.publish("Witamy na serwerze").await.unwrap();
+    let second = repo.publish("Przerwa techniczna o 22:00").await.unwrap();
+
+    let newest = repo.find_newest_after(0).await.unwrap().unwrap();
+    assert_eq!(newest.id, second.id);
+    assert_eq!(newest.body, "Przerwa techniczna o 22:00");
+    assert_eq!(repo.find_newest_after(first.id).await.unwrap().unwrap().id, second.id);
+    assert!(repo.find_newest_after(second.id).await.unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn test_last_seen_from_another_server_counts_as_nothing_seen() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = SystemMessageRepository::new(&pool);
+
+    assert!(repo.find_newest_after(26679).await.unwrap().is_none());
+
+    repo.publish("Witamy na serwerze").await.unwrap();
+    let second = repo.publish("Przerwa techniczna o 22:00").await.unwrap();
+
+    let newest = repo.find_newest_after(26679).await.unwrap().unwrap();
+    assert_eq!(newest.id, second.id);
+  }
+
+  #[tokio::test]
+  async fn test_broadcast_is_marked() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = SystemMessageRepository::new(&pool);
+
+    let first = repo.publish("Pierwsza").await.unwrap();
+    let second = repo.publish("Druga").await.unwrap();
+    repo.mark_broadcast(first.id).await.unwrap();
+
+    let pending = repo.find_not_broadcast().await.unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].id, second.id);
+    assert_eq!(repo.list().await.unwrap().len(), 2);
+  }
+}
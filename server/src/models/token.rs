@@ -11,16 +11,10 @@ const TOKEN_EXPIRY_MINUTES: i32 = 5;
 /// Captcha token record from database.
 #[derive(Debug, Clone, FromRow)]
 pub struct Token {
-  /// Database ID.
-  pub id: i64,
   /// Unique token identifier (32 chars).
   pub token_id: String,
   /// Captcha code (4 alphanumeric chars).
   pub captcha_code: String,
-  /// Token creation timestamp.
-  pub created_at: Option<String>,
-  /// When the token was used (NULL if unused).
-  pub used_at: Option<String>,
 }
 
 /// Repository for token database operations.
@@ -41,22 +35,15 @@ impl TokenRepository {
     Self { pool: pool.clone() }
   }
 
-  /// Create a new captcha token.
+  /// Create a new token for the given captcha code.
   #[instrument(skip(self))]
-  pub async fn create(&self) -> Result<Token, RepositoryError> {
+  pub async fn create(&self, captcha_code: &str) -> Result<Token, RepositoryError> {
       let token_id: String = rand::rng()
       .sample_iter(rand::distr::Alphanumeric)
       .take(32)
       .map(char::from)
       .collect();
 
-    let captcha_code: String = rand::rng()
-      .sample_iter(rand::distr::Alphanumeric)
-      .take(4)
-      .map(char::from)
-      .map(|c: char| c.to_ascii_uppercase())
-      .collect();
-
     info!(token_id = token_id, "Token created");
     let result = sqlx::query_as::<_, Token>(
       "INSERT INTO tokens (token_id, captcha_code) VALUES (?, ?) RETURNING *"
@@ -84,29 +71,37 @@ impl TokenRepository {
   }
 
   /// Validate a token and mark it as used if valid.
+  /// The captcha answer is compared case-insensitively.
   /// Returns true if the token was valid and has been consumed.
   #[instrument(skip(self))]
   pub async fn validate(&self, token_id: &str, captcha_code: &str) -> Result<bool, RepositoryError> {
     let token = self.find_by_token_id(token_id).await?;
 
     match token {
-      Some(t) if t.captcha_code == captcha_code => {
-        let result = sqlx::query(
-          "UPDATE tokens SET used_at = CURRENT_TIMESTAMP WHERE token_id = ? AND used_at IS NULL"
-        )
-          .bind(token_id)
-          .execute(&self.pool)
-          .await?;
-
-        let validated = result.rows_affected() > 0;
-        if validated {
-          info!(token_id = token_id, "Token validated and consumed");
-        }
-        Ok(validated)
-      }
+      Some(t) if t.captcha_code.eq_ignore_ascii_case(captcha_code.trim()) => self.consume(token_id).await,
       _ => Ok(false),
     }
   }
+
+  /// Mark a token as used without checking the captcha answer.
+  /// Returns true if the token existed, was not expired and has been consumed.
+  #[instrument(skip(self))]
+  pub async fn consume(&self, token_id: &str) -> Result<bool, RepositoryError> {
+    let result = sqlx::query(
+      "UPDATE tokens SET used_at = CURRENT_TIMESTAMP \
+       WHERE token_id = ? AND used_at IS NULL AND datetime(created_at, '+' || ? || ' minutes') > datetime('now')"
+    )
+      .bind(token_id)
+      .bind(TOKEN_EXPIRY_MINUTES)
+      .execute(&self.pool)
+      .await?;
+
+    let consumed = result.rows_affected() > 0;
+    if consumed {
+      info!(token_id = token_id, "Token consumed");
+    }
+    Ok(consumed)
+  }
 }
 
 #[cfg(test)]
@@ -123,7 +118,6 @@ mod tests {
 
     Ok(pool)
   }
-  // TODO: add specs
 
   #[tokio::test]
   async fn test_token_not_found() {
@@ -133,4 +127,26 @@ mod tests {
     let found = repo.find_by_token_id("nonexistent").await.unwrap();
     assert!(found.is_none());
   }
+
+  #[tokio::test]
+  async fn test_validate_ignores_case_and_consumes_token() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = TokenRepository::new(&pool);
+
+    let token = repo.create("AC34").await.unwrap();
+    assert!(!repo.validate(&token.token_id, "AC35").await.unwrap());
+    assert!(repo.validate(&token.token_id, "ac34").await.unwrap());
+    assert!(!repo.validate(&token.token_id, "AC34").await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_consume_only_once() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = TokenRepository::new(&pool);
+
+    let token = repo.create("AC34").await.unwrap();
+    assert!(repo.consume(&token.token_id).await.unwrap());
+    assert!(!repo.consume(&token.token_id).await.unwrap());
+    assert!(!repo.consume("nonexistent").await.unwrap());
+  }
 }
@@ -1,16 +1,10 @@
 //! User model and repository.
 
-use rand::Rng;
 use sqlx::{Pool, Sqlite, FromRow};
 use tracing::{info, instrument};
 use gg_protocol::GGNumber;
 use crate::models::RepositoryError;
 
-/// Minimum valid 8-digit GG number.
-const MIN_UIN: u32 = 10_000_00;
-/// Maximum valid 8-digit GG number.
-const MAX_UIN: u32 = 66_999_99;
-
 /// User record from database.
 #[derive(Debug, Clone, FromRow)]
 pub struct User {
@@ -24,12 +18,28 @@ pub struct User {
   pub password: String,
   /// Account creation timestamp.
   pub created_at: Option<String>,
+  /// When an admin approved the account (NULL if waiting for approval).
+  pub approved_at: Option<String>,
+  /// When the user clicked the verification link (NULL if unverified).
+  pub email_verified_at: Option<String>,
+}
+
+impl User {
+  /// Returns true if the account is allowed to log in.
+  pub fn is_approved(&self) -> bool {
+    self.approved_at.is_some()
+  }
+
+  /// Returns true if the user confirmed their email address.
+  /// Unverified accounts can't recover their password.
+  pub fn is_email_verified(&self) -> bool {
+    self.email_verified_at.is_some()
+  }
 }
 
 /// Repository for user database operations.
 #[derive(Clone)]
 pub struct UserRepository {
-  #[allow(dead_code)]
   pool: Pool<Sqlite>,
 }
 
@@ -45,25 +55,42 @@ impl UserRepository {
     Self { pool: pool.clone() }
   }
 
-  /// Create a new user and return it with the assigned UIN.
+  /// Create a new user with the given UIN (see `UinAllocator`).
+  /// Unapproved users can't log in until `approve` is called.
   #[instrument(skip(self, password))]
-  pub async fn create(&self, name: &str, email: &str, password: &str) -> Result<User, RepositoryError> {
+  pub async fn create(&self, uin: GGNumber, name: &str, email: &str, password: &str, approved: bool) -> Result<User, RepositoryError> {
     // todo: add validation, min password length
-    let uin = rand::rng().random_range(MIN_UIN..=MAX_UIN);
     let result = sqlx::query_as::<_, User>(
-      "INSERT INTO users (uin, name, email, password) VALUES (?, ?, ?, ?) RETURNING *"
+      "INSERT INTO users (uin, name, email, password, approved_at) \
+       VALUES (?, ?, ?, ?, CASE WHEN ? THEN CURRENT_TIMESTAMP END) RETURNING *"
     )
       .bind(uin)
       .bind(name)
       .bind(email)
       .bind(password)
+      .bind(approved)
       .fetch_one(&self.pool)
       .await?;
 
-    info!(uin = result.uin, "User created");
+    info!(uin = result.uin, approved, "User created");
     Ok(result)
   }
 
+  /// Approve an account waiting for admin approval.
+  #[instrument(skip(self))]
+  pub async fn approve(&self, uin: GGNumber) -> Result<bool, RepositoryError> {
+    let result = sqlx::query("UPDATE users SET approved_at = CURRENT_TIMESTAMP WHERE uin = ? AND approved_at IS NULL")
+      .bind(uin)
+      .execute(&self.pool)
+      .await?;
+
+    let approved = result.rows_affected() > 0;
+    if approved {
+      info!("User approved");
+    }
+    Ok(approved)
+  }
+
   /// Find a user by UIN.
   #[instrument(skip(self))]
   pub async fn find_by_uin(&self, uin: GGNumber) -> Result<Option<User>, RepositoryError> {
@@ -84,6 +111,17 @@ impl UserRepository {
     Ok(result.is_some())
   }
 
+  /// Highest UIN taken within `min..=max`, used for sequential allocation.
+  #[instrument(skip(self))]
+  pub async fn max_uin_between(&self, min: GGNumber, max: GGNumber) -> Result<Option<GGNumber>, RepositoryError> {
+    let result: (Option<GGNumber>,) = sqlx::query_as("SELECT MAX(uin) FROM users WHERE uin BETWEEN ? AND ?")
+      .bind(min)
+      .bind(max)
+      .fetch_one(&self.pool)
+      .await?;
+    Ok(result.0)
+  }
+
   /// Find multiple users by their UIDs.
   #[instrument(skip(self))]
   pub async fn find_by_uins(&self, uins: &[GGNumber]) -> Result<Vec<User>, RepositoryError> {
@@ -107,10 +145,10 @@ impl UserRepository {
     Ok(users)
   }
 
-  /// Find a user by email.
+  /// Find a user by email (case-insensitive).
   #[instrument(skip(self))]
   pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
-    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ? COLLATE NOCASE")
       .bind(email)
       .fetch_optional(&self.pool)
       .await?;
@@ -118,6 +156,15 @@ impl UserRepository {
     Ok(user)
   }
 
+  /// List all users ordered by UIN.
+  #[instrument(skip(self))]
+  pub async fn list(&self) -> Result<Vec<User>, RepositoryError> {
+    let users = sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY uin")
+      .fetch_all(&self.pool)
+      .await?;
+    Ok(users)
+  }
+
   /// Update user's password.
   #[instrument(skip(self, password))]
   pub async fn update_password(&self, uin: u32, password: &str) -> Result<bool, RepositoryError> {
@@ -134,19 +181,36 @@ impl UserRepository {
     Ok(updated)
   }
 
-  /// Delete a user by UIN.
+  /// Delete a user by UIN, together with everything stored for the account:
+  /// queued messages, presence, MOTD deliveries, moderation entries,
+  /// auto-reply and email verification tokens. Nothing is deleted if the
+  /// user doesn't exist.
   #[instrument(skip(self))]
   pub async fn delete(&self, uin: u32) -> Result<bool, RepositoryError> {
+    let mut tx = self.pool.begin().await?;
+
     let result = sqlx::query("DELETE FROM users WHERE uin = ?")
       .bind(uin)
-      .execute(&self.pool)
+      .execute(&mut *tx)
       .await?;
+    if result.rows_affected() == 0 {
+      return Ok(false)
+    }
 
-    let deleted = result.rows_affected() > 0;
-    if deleted {
-      info!("User deleted");
+    for query in [
+      "DELETE FROM messages WHERE recipient_uin = ?",
+      "DELETE FROM presences WHERE uin = ?",
+      "DELETE FROM motd_deliveries WHERE uin = ?",
+      "DELETE FROM user_moderation WHERE uin = ?",
+      "DELETE FROM auto_replies WHERE uin = ?",
+      "DELETE FROM email_verifications WHERE uin = ?",
+    ] {
+      sqlx::query(query).bind(uin).execute(&mut *tx).await?;
     }
-    Ok(deleted)
+
+    tx.commit().await?;
+    info!("User deleted");
+    Ok(true)
   }
 }
 
@@ -154,7 +218,12 @@ impl UserRepository {
 mod tests {
   use super::*;
   use sqlx::sqlite::SqlitePoolOptions;
-  use crate::models::RepositoryError;
+  use gg_protocol::consts::{GGMessageClass, GGStatus};
+  use gg_protocol::packets::GGRecvMessage;
+  use crate::models::{
+    AutoReplyRepository, EmailVerificationRepository, MessageRepository, ModerationKind, ModerationRepository, MotdRepository,
+    PresenceRepository, RepositoryError
+  };
 
   async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
     let pool = SqlitePoolOptions::new()
@@ -172,8 +241,9 @@ mod tests {
     let repo = UserRepository::new(&pool);
 
     // Create
-    let user = repo.create("Test", "test@gg.pl", "hash123").await.unwrap();
-    assert!(user.uin > 0);
+    let user = repo.create(1_000_000, "Test", "test@gg.pl", "hash123", true).await.unwrap();
+    assert_eq!(user.uin, 1_000_000);
+    assert!(user.is_approved());
 
     // Find
     let found = repo.find_by_uin(user.uin).await.unwrap().unwrap();
@@ -185,7 +255,55 @@ mod tests {
     assert_eq!(updated.password, "newhash");
 
     // Delete
-    repo.delete(user.uin).await.unwrap();
+    assert!(repo.delete(user.uin).await.unwrap());
     assert!(repo.find_by_uin(user.uin).await.unwrap().is_none());
+    assert!(!repo.delete(user.uin).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_delete_removes_account_data() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = UserRepository::new(&pool);
+    let user = repo.create(1_000_000, "Test", "test@gg.pl", "hash123", true).await.unwrap();
+    let other = repo.create(2_000_000, "Other", "other@gg.pl", "hash123", true).await.unwrap();
+
+    let message = GGRecvMessage {
+      sender: 3_000_000,
+      seq: 1,
+      time: 1234567890,
+      class: GGMessageClass::Chat,
+      message: "Hej".to_string(),
+      formatting: None,
+    };
+    for uin in [user.uin, other.uin] {
+      EmailVerificationRepository::new(&pool).create(uin).await.unwrap();
+      PresenceRepository::new(&pool).save(uin, GGStatus::NotAvail, None, None).await.unwrap();
+      MotdRepository::new(&pool).claim(uin, 1).await.unwrap();
+      AutoReplyRepository::new(&pool).set(uin, "Jestem na urlopie", true, true).await.unwrap();
+      ModerationRepository::new(&pool).apply(uin, ModerationKind::Mute, None, None, "admin").await.unwrap();
+      MessageRepository::new(&pool).store(uin, &message).await.unwrap();
+    }
+
+    assert!(repo.delete(user.uin).await.unwrap());
+
+    for table in ["email_verifications", "presences", "motd_deliveries", "auto_replies", "user_moderation"] {
+      let uins: Vec<(u32,)> = sqlx::query_as(&format!("SELECT uin FROM {}", table)).fetch_all(&pool).await.unwrap();
+      assert_eq!(uins, vec![(other.uin,)], "{}", table);
+    }
+    let recipients: Vec<(u32,)> = sqlx::query_as("SELECT recipient_uin FROM messages").fetch_all(&pool).await.unwrap();
+    assert_eq!(recipients, vec![(other.uin,)]);
+  }
+
+  #[tokio::test]
+  async fn test_unapproved_user_until_approved() {
+    let pool = setup_test_db().await.unwrap();
+    let repo = UserRepository::new(&pool);
+
+    let user = repo.create(1_000_000, "Test", "test@gg.pl", "hash123", false).await.unwrap();
+    assert!(!user.is_approved());
+
+    assert!(repo.approve(user.uin).await.unwrap());
+    assert!(repo.find_by_uin(user.uin).await.unwrap().unwrap().is_approved());
+    assert!(!repo.approve(user.uin).await.unwrap());
   }
 }
@@ -0,0 +1,323 @@
+//! GG number (UIN) allocation for new accounts.
+//!
+//! ```toml
+//! [uin]
+//! allocation = "sequential"    # "random" or "sequential"
+//! min = 1000
+//! max = 6699999
+//! reserved = ["1000-9999", "1234567"]
+//! ```
+//!
+//! Reserved blocks are skipped by automatic allocation, but admins can still
+//! assign them by hand. Every number must fit in the 24 bits the GG 6.0
+//! protocol uses for UINs (`GG60_MAX_UIN`).
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use gg_protocol::GGNumber;
+use gg_protocol::consts::GG60_MAX_UIN;
+use crate::models::{RepositoryError, UserRepository};
+
+/// Random picks tried before falling back to a sequential scan.
+const RANDOM_ATTEMPTS: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum UinError {
+  #[error("No free UIN left in the configured range")]
+  Exhausted,
+  #[error("UIN {0} is outside of the allowed range 1-{max}", max = GG60_MAX_UIN)]
+  OutOfRange(GGNumber),
+  #[error("UIN {0} is already taken")]
+  Taken(GGNumber),
+  #[error("Invalid UIN range: {0}")]
+  InvalidRange(String),
+  #[error("Repository error: {0}")]
+  Repository(#[from] RepositoryError),
+}
+
+/// How new UINs are picked.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UinAllocation {
+  /// Random free number between `min` and `max`.
+  #[default]
+  Random,
+  /// Next free number after the highest UIN in the range.
+  Sequential,
+}
+
+/// Inclusive range of UINs, written as `"1000-9999"` or a single `"1234567"`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct UinRange {
+  pub start: GGNumber,
+  pub end: GGNumber,
+}
+
+impl UinRange {
+  pub fn contains(&self, uin: GGNumber) -> bool {
+    (self.start..=self.end).contains(&uin)
+  }
+}
+
+impl FromStr for UinRange {
+  type Err = UinError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parse = |v: &str| v.trim().parse::<GGNumber>().map_err(|_| UinError::InvalidRange(s.to_string()));
+
+    let (start, end) = match s.split_once('-') {
+      Some((start, end)) => (parse(start)?, parse(end)?),
+      None => {
+        let uin = parse(s)?;
+        (uin, uin)
+      }
+    };
+
+    if start > end {
+      return Err(UinError::InvalidRange(s.to_string()));
+    }
+    Ok(Self { start, end })
+  }
+}
+
+impl TryFrom<String> for UinRange {
+  type Error = UinError;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<UinRange> for String {
+  fn from(range: UinRange) -> Self {
+    range.to_string()
+  }
+}
+
+impl Display for UinRange {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    if self.start == self.end {
+      write!(f, "{}", self.start)
+    } else {
+      write!(f, "{}-{}", self.start, self.end)
+    }
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct UinConfig {
+  pub allocation: UinAllocation,
+  pub min: GGNumber,
+  pub max: GGNumber,
+  pub reserved: Vec<UinRange>,
+}
+
+impl Default for UinConfig {
+  fn default() -> Self {
+    Self {
+      allocation: UinAllocation::default(),
+      min: 1_000_000,
+      max: 6_699_999,
+      reserved: Vec::new(),
+    }
+  }
+}
+
+impl UinConfig {
+  /// Check that the range fits in the GG 6.0 UIN space.
+  pub fn validate(&self) -> Result<(), UinError> {
+    if self.min == 0 || self.min > self.max {
+      return Err(UinError::InvalidRange(format!("{}-{}", self.min, self.max)));
+    }
+    if self.max > GG60_MAX_UIN {
+      return Err(UinError::OutOfRange(self.max));
+    }
+    if let Some(range) = self.reserved.iter().find(|r| r.end > GG60_MAX_UIN) {
+      return Err(UinError::OutOfRange(range.end));
+    }
+    Ok(())
+  }
+}
+
+/// Picks UINs for new accounts according to `UinConfig`.
+#[derive(Debug)]
+pub struct UinAllocator {
+  config: UinConfig,
+}
+
+impl UinAllocator {
+  pub fn new(config: &UinConfig) -> Result<Self, UinError> {
+    config.validate()?;
+    Ok(Self { config: config.clone() })
+  }
+
+  fn reserved_block(&self, uin: GGNumber) -> Option<&UinRange> {
+    self.config.reserved.iter().find(|r| r.contains(uin))
+  }
+
+  /// Pick a free, non-reserved UIN for a new account.
+  #[tracing::instrument(skip(self, users))]
+  pub async fn allocate(&self, users: &UserRepository) -> Result<GGNumber, UinError> {
+    if self.config.allocation == UinAllocation::Random {
+      for _ in 0..RANDOM_ATTEMPTS {
+        let candidate = rand::rng().random_range(self.config.min..=self.config.max);
+        if self.reserved_block(candidate).is_none() && !users.exists(candidate).await? {
+          return Ok(candidate);
+        }
+      }
+      tracing::warn!("No free random UIN found, falling back to sequential allocation");
+    }
+
+    self.next_sequential(users).await
+  }
+
+  async fn next_sequential(&self, users: &UserRepository) -> Result<GGNumber, UinError> {
+    let (min, max) = (self.config.min, self.config.max);
+    let mut candidate = match users.max_uin_between(min, max).await? {
+      Some(uin) if uin < max => uin + 1,
+      Some(_) => min,
+      None => min,
+    };
+    let mut wrapped = candidate == min;
+
+    loop {
+      if candidate > max {
+        if wrapped {
+          return Err(UinError::Exhausted);
+        }
+        candidate = min;
+        wrapped = true;
+      }
+
+      match self.reserved_block(candidate) {
+        Some(block) => candidate = block.end.saturating_add(1),
+        None if users.exists(candidate).await? => candidate += 1,
+        None => return Ok(candidate),
+      }
+    }
+  }
+
+  /// Check a UIN chosen by an admin. Reserved numbers are allowed.
+  #[tracing::instrument(skip(self, users))]
+  pub async fn check_manual(&self, users: &UserRepository, uin: GGNumber) -> Result<GGNumber, UinError> {
+    if uin == 0 || uin > GG60_MAX_UIN {
+      return Err(UinError::OutOfRange(uin));
+    }
+    if users.exists(uin).await? {
+      return Err(UinError::Taken(uin));
+    }
+    Ok(uin)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sqlx::{Pool, Sqlite};
+  use sqlx::sqlite::SqlitePoolOptions;
+
+  async fn setup_test_db() -> Result<Pool<Sqlite>, RepositoryError> {
+    let pool = SqlitePoolOptions::new()
+      .connect("sqlite::memory:")
+      .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("migrations failed");
+
+    Ok(pool)
+  }
+
+  fn allocator(allocation: UinAllocation, min: GGNumber, max: GGNumber, reserved: &[&str]) -> UinAllocator {
+    UinAllocator::new(&UinConfig {
+      allocation,
+      min,
+      max,
+      reserved: reserved.iter().map(|r| r.parse().unwrap()).collect(),
+    }).unwrap()
+  }
+
+  async fn register(users: &UserRepository, uin: GGNumber) {
+    users.create(uin, "Test", &format!("{}@gg.pl", uin), "hash", true).await.unwrap();
+  }
+
+  #[test]
+  fn test_parse_ranges() {
+    assert_eq!("1000-9999".parse::<UinRange>().unwrap(), UinRange { start: 1000, end: 9999 });
+    assert_eq!(" 1234567 ".parse::<UinRange>().unwrap(), UinRange { start: 1234567, end: 1234567 });
+    assert!("9999-1000".parse::<UinRange>().is_err());
+    assert!("abc".parse::<UinRange>().is_err());
+    assert_eq!(UinRange { start: 5, end: 5 }.to_string(), "5");
+  }
+
+  #[test]
+  fn test_config_must_fit_gg60() {
+    assert!(UinConfig::default().validate().is_ok());
+    assert!(UinConfig { max: GG60_MAX_UIN, ..Default::default() }.validate().is_ok());
+    assert!(UinConfig { max: GG60_MAX_UIN + 1, ..Default::default() }.validate().is_err());
+    assert!(UinConfig { min: 0, ..Default::default() }.validate().is_err());
+    assert!(UinConfig { min: 10, max: 5, ..Default::default() }.validate().is_err());
+    assert!(UinConfig { reserved: vec!["16777000-16777300".parse().unwrap()], ..Default::default() }.validate().is_err());
+  }
+
+  #[tokio::test]
+  async fn test_sequential_skips_reserved_blocks() {
+    let pool = setup_test_db().await.unwrap();
+    let users = UserRepository::new(&pool);
+    let allocator = allocator(UinAllocation::Sequential, 1000, 1010, &["1000-1002", "1004"]);
+
+    let first = allocator.allocate(&users).await.unwrap();
+    assert_eq!(first, 1003);
+    register(&users, first).await;
+
+    assert_eq!(allocator.allocate(&users).await.unwrap(), 1005);
+  }
+
+  #[tokio::test]
+  async fn test_sequential_fills_gaps_and_reports_exhaustion() {
+    let pool = setup_test_db().await.unwrap();
+    let users = UserRepository::new(&pool);
+    let allocator = allocator(UinAllocation::Sequential, 1000, 1003, &["1001"]);
+
+    register(&users, 1003).await;
+    assert_eq!(allocator.allocate(&users).await.unwrap(), 1000);
+    register(&users, 1000).await;
+    assert_eq!(allocator.allocate(&users).await.unwrap(), 1002);
+    register(&users, 1002).await;
+
+    assert!(matches!(allocator.allocate(&users).await, Err(UinError::Exhausted)));
+  }
+
+  #[tokio::test]
+  async fn test_random_stays_in_range_and_avoids_reserved() {
+    let pool = setup_test_db().await.unwrap();
+    let users = UserRepository::new(&pool);
+    let allocator = allocator(UinAllocation::Random, 2000, 2019, &["2000-2009"]);
+
+    for _ in 0..10 {
+      let uin = allocator.allocate(&users).await.unwrap();
+      assert!((2010..=2019).contains(&uin));
+      register(&users, uin).await;
+    }
+
+    assert!(matches!(allocator.allocate(&users).await, Err(UinError::Exhausted)));
+  }
+
+  #[tokio::test]
+  async fn test_manual_assignment() {
+    let pool = setup_test_db().await.unwrap();
+    let users = UserRepository::new(&pool);
+    let allocator = allocator(UinAllocation::Sequential, 1000, 2000, &["1000-1500"]);
+
+    assert_eq!(allocator.check_manual(&users, 1234).await.unwrap(), 1234);
+    assert_eq!(allocator.check_manual(&users, 42).await.unwrap(), 42);
+    register(&users, 42).await;
+
+    assert!(matches!(allocator.check_manual(&users, 42).await, Err(UinError::Taken(42))));
+    assert!(matches!(allocator.check_manual(&users, 0).await, Err(UinError::OutOfRange(0))));
+    assert!(matches!(allocator.check_manual(&users, GG60_MAX_UIN + 1).await, Err(UinError::OutOfRange(_))));
+  }
+}